@@ -0,0 +1,427 @@
+//! Authorized nodes allowlist for direct-mode servers
+//!
+//! Works like ssh's authorized_keys: one node id per line, with optional
+//! permissions in front and a free-form label after it.
+//!
+//! ```text
+//! # anything after '#' is ignored
+//! 3e9c6243427ba9f6...a2b3e2  laptop
+//! mirror-view 9530dd2b05d2cb29...8b081fc  projector
+//! forward,mirror-view,mirror-control f884703f0ec5...3039b2  office-desktop
+//! ```
+//!
+//! Entries without permissions get all of them. The file is re-read for
+//! every connection, so edits take effect without restarting the server.
+//!
+//! The same file is the trusted-peers store written by `x11q pair`: labels
+//! double as names, so `x11q client office-desktop` resolves locally.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use iroh::NodeId;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+const AUTHORIZED_NODES_FILE: &str = "authorized_nodes";
+
+/// What an authorized node may do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    /// Open X11 streams to the local display (`server`)
+    Forward,
+    /// Watch the screen (`mirror-server`)
+    MirrorView,
+    /// Inject keyboard and mouse input via XTest (`mirror-server`)
+    MirrorControl,
+}
+
+impl FromStr for Permission {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "forward" => Ok(Self::Forward),
+            "mirror-view" => Ok(Self::MirrorView),
+            "mirror-control" => Ok(Self::MirrorControl),
+            _ => anyhow::bail!("unknown permission '{}'", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Forward => "forward",
+            Self::MirrorView => "mirror-view",
+            Self::MirrorControl => "mirror-control",
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuthorizedNode {
+    pub node_id: NodeId,
+    pub label: Option<String>,
+    permissions: Vec<Permission>,
+}
+
+impl AuthorizedNode {
+    fn all(node_id: NodeId) -> Self {
+        Self {
+            node_id,
+            label: None,
+            permissions: vec![
+                Permission::Forward,
+                Permission::MirrorView,
+                Permission::MirrorControl,
+            ],
+        }
+    }
+
+    pub fn allows(&self, permission: Permission) -> bool {
+        self.permissions.contains(&permission)
+    }
+
+    /// short id plus label, for log lines
+    pub fn display_name(&self) -> String {
+        let short = &self.node_id.to_string()[..8];
+        match &self.label {
+            Some(label) => format!("{} ({})", short, label),
+            None => short.to_string(),
+        }
+    }
+}
+
+/// Allowlist options shared by `server` and `mirror-server`
+#[derive(Args, Clone, Debug, Default)]
+pub struct AccessArgs {
+    /// Authorized nodes file (default: ~/.config/x11q/authorized_nodes)
+    #[arg(long, value_name = "PATH")]
+    pub authorized_nodes: Option<PathBuf>,
+
+    /// Also allow this node id (repeatable)
+    #[arg(long = "allow", value_name = "NODE_ID")]
+    pub allow: Vec<String>,
+}
+
+impl AccessArgs {
+    pub fn load(&self) -> Result<AuthorizedNodes> {
+        let path = match &self.authorized_nodes {
+            Some(p) => p.clone(),
+            None => default_path()?,
+        };
+        let extra = self
+            .allow
+            .iter()
+            .map(|s| NodeId::from_str(s).with_context(|| format!("invalid --allow node id {}", s)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let nodes = AuthorizedNodes { path, extra };
+        // fail early on a broken file instead of rejecting everyone later
+        let count = nodes.entries()?.len();
+        if count == 0 {
+            eprintln!(
+                "warning: no authorized nodes - add node ids to {} or pass --allow NODE_ID",
+                nodes.path.display()
+            );
+        } else {
+            eprintln!("authorized nodes: {} ({})", count, nodes.path.display());
+        }
+        Ok(nodes)
+    }
+}
+
+pub fn default_path() -> Result<PathBuf> {
+    Ok(crate::identity::config_dir()?.join(AUTHORIZED_NODES_FILE))
+}
+
+/// Accept either a node id or the label of a trusted peer
+pub fn resolve_peer(s: &str) -> Result<NodeId> {
+    if let Ok(id) = NodeId::from_str(s) {
+        return Ok(id);
+    }
+
+    let path = default_path()?;
+    let matches: Vec<_> = read_file(&path)?
+        .into_iter()
+        .filter(|e| e.label.as_deref() == Some(s))
+        .collect();
+    match matches.as_slice() {
+        [entry] => Ok(entry.node_id),
+        [] => anyhow::bail!(
+            "'{}' is neither a node id nor a paired peer in {}",
+            s,
+            path.display()
+        ),
+        _ => anyhow::bail!(
+            "name '{}' is ambiguous in {}, use the node id",
+            s,
+            path.display()
+        ),
+    }
+}
+
+/// Refuse labels that could be taken for a node id or a prefix of one
+pub fn check_label(label: &str) -> Result<()> {
+    anyhow::ensure!(
+        NodeId::from_str(label).is_err() && !label.chars().all(|c| c.is_ascii_hexdigit()),
+        "name '{}' looks like a node id",
+        label
+    );
+    Ok(())
+}
+
+/// Add `node_id` under `label` with `permissions`, replacing the label of
+/// an existing entry
+///
+/// Permissions already set on an existing entry are kept. A label another
+/// node already has gets a numeric suffix, so names stay unambiguous; the
+/// label actually written is returned.
+pub fn trust(
+    path: &Path,
+    node_id: NodeId,
+    label: &str,
+    permissions: &[Permission],
+) -> Result<String> {
+    check_label(label)?;
+    anyhow::ensure!(!permissions.is_empty(), "no permissions to grant");
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+    };
+
+    let taken: Vec<String> = contents
+        .lines()
+        .filter_map(|line| parse_line(line.split('#').next().unwrap_or("").trim()).ok())
+        .filter(|e| e.node_id != node_id)
+        .filter_map(|e| e.label)
+        .collect();
+    let label = (1..)
+        .map(|n| match n {
+            1 => label.to_string(),
+            n => format!("{}-{}", label, n),
+        })
+        .find(|l| !taken.contains(l))
+        .expect("a free suffix");
+
+    let id = node_id.to_string();
+    let mut out = String::new();
+    let mut replaced = false;
+
+    for line in contents.lines() {
+        let entry = line.split('#').next().unwrap_or("").trim();
+        match parse_line(entry) {
+            Ok(e) if e.node_id == node_id && !replaced => {
+                let first = entry.split_whitespace().next().unwrap_or("");
+                if first != id {
+                    out.push_str(first);
+                    out.push(' ');
+                }
+                out.push_str(&format!("{} {}\n", id, label));
+                replaced = true;
+            }
+            Ok(e) if e.node_id == node_id => {}
+            _ => {
+                out.push_str(line);
+                out.push('\n');
+            }
+        }
+    }
+    if !replaced {
+        let permissions: Vec<_> = permissions.iter().map(|p| p.to_string()).collect();
+        out.push_str(&format!("{} {} {}\n", permissions.join(","), id, label));
+    }
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    std::fs::write(path, out).with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(label)
+}
+
+pub struct AuthorizedNodes {
+    path: PathBuf,
+    extra: Vec<NodeId>,
+}
+
+impl AuthorizedNodes {
+    /// Look up an entry for `node_id` that grants `permission`
+    pub fn check(&self, node_id: NodeId, permission: Permission) -> Result<Option<AuthorizedNode>> {
+        Ok(self
+            .entries()?
+            .into_iter()
+            .find(|e| e.node_id == node_id && e.allows(permission)))
+    }
+
+    fn entries(&self) -> Result<Vec<AuthorizedNode>> {
+        let mut entries = read_file(&self.path)?;
+        entries.extend(self.extra.iter().map(|id| AuthorizedNode::all(*id)));
+        Ok(entries)
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<AuthorizedNode>> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => parse(&contents).with_context(|| format!("in {}", path.display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+fn parse(contents: &str) -> Result<Vec<AuthorizedNode>> {
+    let mut entries = Vec::new();
+
+    for (lineno, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let entry = parse_line(line).with_context(|| format!("line {}", lineno + 1))?;
+        entries.push(entry);
+    }
+
+    Ok(entries)
+}
+
+fn parse_line(line: &str) -> Result<AuthorizedNode> {
+    let mut tokens = line.split_whitespace();
+    let first = tokens.next().context("empty entry")?;
+
+    // optional permissions before the node id, like ssh options
+    let (node_id, permissions) = match NodeId::from_str(first) {
+        Ok(id) => (id, None),
+        Err(_) => {
+            let perms = first
+                .split(',')
+                .map(Permission::from_str)
+                .collect::<Result<Vec<_>>>()?;
+            let id = tokens.next().context("missing node id")?;
+            (
+                NodeId::from_str(id).context("invalid node id")?,
+                Some(perms),
+            )
+        }
+    };
+
+    let label = tokens.collect::<Vec<_>>().join(" ");
+    let mut entry = AuthorizedNode::all(node_id);
+    if let Some(perms) = permissions {
+        entry.permissions = perms;
+    }
+    if !label.is_empty() {
+        entry.label = Some(label);
+    }
+    Ok(entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(seed: u8) -> NodeId {
+        iroh::SecretKey::from_bytes(&[seed; 32]).public()
+    }
+
+    #[test]
+    fn test_parse_plain_entry() {
+        let id = node(1);
+        let entries = parse(&format!("{}\n", id)).unwrap();
+        assert_eq!(entries, vec![AuthorizedNode::all(id)]);
+    }
+
+    #[test]
+    fn test_parse_permissions_and_label() {
+        let id = node(1);
+        let entries = parse(&format!(
+            "# team\n\nmirror-view,mirror-control {}  office desktop # note\n",
+            id
+        ))
+        .unwrap();
+
+        assert_eq!(entries.len(), 1);
+        let e = &entries[0];
+        assert_eq!(e.label.as_deref(), Some("office desktop"));
+        assert!(e.allows(Permission::MirrorView));
+        assert!(e.allows(Permission::MirrorControl));
+        assert!(!e.allows(Permission::Forward));
+    }
+
+    #[test]
+    fn test_parse_rejects_garbage() {
+        assert!(parse("not-a-node-id").is_err());
+        assert!(parse(&format!("bogus-perm {}", node(1))).is_err());
+    }
+
+    #[test]
+    fn test_trust_replaces_label_keeps_permissions() {
+        let path = std::env::temp_dir().join(format!("x11q-trust-{}", std::process::id()));
+        let (a, b) = (node(1), node(2));
+        std::fs::write(&path, format!("# peers\nmirror-view {} old\n{}\n", a, b)).unwrap();
+
+        let forward = [Permission::Forward];
+        trust(&path, a, "office-desktop", &forward).unwrap();
+        trust(&path, node(3), "laptop", &forward).unwrap();
+        let entries = read_file(&path).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(contents.starts_with("# peers\n"));
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].label.as_deref(), Some("office-desktop"));
+        assert!(!entries[0].allows(Permission::Forward));
+        assert_eq!(entries[2].node_id, node(3));
+        // a newly paired peer gets only what was granted
+        assert!(entries[2].allows(Permission::Forward));
+        assert!(!entries[2].allows(Permission::MirrorView));
+        assert!(!entries[2].allows(Permission::MirrorControl));
+    }
+
+    #[test]
+    fn test_trust_keeps_labels_unambiguous() {
+        let path = std::env::temp_dir().join(format!("x11q-labels-{}", std::process::id()));
+        let forward = [Permission::Forward];
+        let labels = [
+            trust(&path, node(1), "laptop", &forward),
+            trust(&path, node(2), "laptop", &forward),
+            trust(&path, node(3), "laptop", &forward),
+            // renaming a node to its own label is no collision
+            trust(&path, node(1), "laptop", &forward),
+            trust(&path, node(4), &node(5).to_string(), &forward),
+            trust(&path, node(4), &node(5).to_string()[..8], &forward),
+        ];
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(labels[0].as_deref().unwrap(), "laptop");
+        assert_eq!(labels[1].as_deref().unwrap(), "laptop-2");
+        assert_eq!(labels[2].as_deref().unwrap(), "laptop-3");
+        assert_eq!(labels[3].as_deref().unwrap(), "laptop");
+        assert!(labels[4].is_err());
+        assert!(labels[5].is_err());
+    }
+
+    #[test]
+    fn test_check_requires_permission() {
+        let nodes = AuthorizedNodes {
+            path: PathBuf::from("/nonexistent/x11q/authorized_nodes"),
+            extra: vec![node(1)],
+        };
+        assert!(nodes.check(node(1), Permission::Forward).unwrap().is_some());
+        assert!(nodes.check(node(2), Permission::Forward).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_check_allow_widens_file_entry() {
+        let path = std::env::temp_dir().join(format!("x11q-allow-{}", std::process::id()));
+        std::fs::write(&path, format!("mirror-view {}\n", node(1))).unwrap();
+        let nodes = AuthorizedNodes {
+            path: path.clone(),
+            extra: vec![node(1)],
+        };
+        let forward = nodes.check(node(1), Permission::Forward);
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(forward.unwrap().is_some());
+    }
+}
@@ -0,0 +1,262 @@
+//! Per-stream zstd compression for forwarded X11 streams
+//!
+//! Image-heavy clients (PutImage toolkits, browsers) can saturate a relayed
+//! or thin link with raw X11. When both sides support it, the dialing side
+//! marks a new stream compressed in its StreamHeader and both ends run their
+//! half of it through a streaming zstd frame, one per direction.
+//!
+//! Every chunk read from the local side is flushed as its own block, so the
+//! peer can decode a request as soon as it arrives and round trips take no
+//! longer than uncompressed; the window still spans the whole stream, so
+//! repeated requests compress well.
+
+use crate::control;
+use anyhow::Result;
+use iroh::endpoint::ConnectionType;
+use std::io::Write;
+use std::net::IpAddr;
+use std::str::FromStr;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+/// Level used by `auto`, and when answering a peer's compressed stream
+const DEFAULT_LEVEL: i32 = 3;
+/// Higher levels cost more cpu than an interactive stream can spare
+const MAX_LEVEL: i32 = 19;
+const CHUNK: usize = 64 * 1024;
+const PIPE_SIZE: usize = 256 * 1024;
+
+/// `--compress`: when to compress forwarded X11 streams
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compress {
+    /// Compress unless the connection is a direct path on the local network
+    Auto,
+    Off,
+    /// Always compress at this zstd level
+    Level(i32),
+}
+
+impl Compress {
+    /// Features to offer in HELLO: compression is refused when it's off
+    pub fn features(self) -> u32 {
+        match self {
+            Self::Off => control::FEATURES & !control::COMPRESS,
+            _ => control::FEATURES,
+        }
+    }
+
+    /// Level for a new stream over `path`, None to send it raw
+    pub fn level(self, path: &ConnectionType) -> Option<i32> {
+        match self {
+            Self::Off => None,
+            Self::Level(level) => Some(level),
+            Self::Auto if is_lan(path) => None,
+            Self::Auto => Some(DEFAULT_LEVEL),
+        }
+    }
+
+    /// Level for our direction of a stream the peer chose to compress
+    pub fn reply_level(self) -> i32 {
+        match self {
+            Self::Level(level) => level,
+            _ => DEFAULT_LEVEL,
+        }
+    }
+}
+
+impl FromStr for Compress {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "off" => Ok(Self::Off),
+            _ => {
+                let level: i32 = s
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("expected auto, off or a level"))?;
+                anyhow::ensure!(
+                    (1..=MAX_LEVEL).contains(&level),
+                    "compression level must be 1-{}",
+                    MAX_LEVEL
+                );
+                Ok(Self::Level(level))
+            }
+        }
+    }
+}
+
+/// A direct path to a private, link-local or loopback address
+fn is_lan(path: &ConnectionType) -> bool {
+    let ConnectionType::Direct(addr) = path else {
+        return false;
+    };
+    match addr.ip() {
+        IpAddr::V4(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip.is_private() || ip.is_link_local() || ip.is_loopback(),
+            // fc00::/7 unique local, fe80::/10 link-local
+            None => {
+                ip.is_loopback()
+                    || ip.segments()[0] & 0xfe00 == 0xfc00
+                    || ip.segments()[0] & 0xffc0 == 0xfe80
+            }
+        },
+    }
+}
+
+/// Put compression in front of `stream`, returning the plain end
+///
+/// What is written to the returned stream goes out compressed at `level`,
+/// what comes in on `stream` is decompressed. The stream ends when either
+/// direction does, like the raw copy it replaces.
+pub fn wrap(stream: DuplexStream, level: i32) -> DuplexStream {
+    let (user, plain) = io::duplex(PIPE_SIZE);
+    tokio::spawn(async move {
+        let (plain_read, plain_write) = io::split(plain);
+        let (packed_read, packed_write) = io::split(stream);
+        let r = tokio::select! {
+            r = compress(plain_read, packed_write, level) => r,
+            r = decompress(packed_read, plain_write) => r,
+        };
+        if let Err(e) = r {
+            eprintln!("compressed stream error: {e}");
+        }
+    });
+    user
+}
+
+/// Compress `reader` into `writer`, flushing a block after every read
+async fn compress<R, W>(mut reader: R, mut writer: W, level: i32) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), level)?;
+    let mut buf = vec![0u8; CHUNK];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        encoder.write_all(&buf[..n])?;
+        // end the block here: the peer can decode everything read so far
+        encoder.flush()?;
+        writer.write_all(encoder.get_ref()).await?;
+        encoder.get_mut().clear();
+    }
+    writer.write_all(&encoder.finish()?).await?;
+    writer.shutdown().await?;
+    Ok(())
+}
+
+/// Decompress `reader` into `writer` as blocks arrive
+async fn decompress<R, W>(mut reader: R, mut writer: W) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut decoder = zstd::stream::write::Decoder::new(Vec::new())?;
+    let mut buf = vec![0u8; CHUNK];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        decoder.write_all(&buf[..n])?;
+        decoder.flush()?;
+        writer.write_all(decoder.get_ref()).await?;
+        decoder.get_mut().clear();
+    }
+    writer.shutdown().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!("auto".parse::<Compress>().unwrap(), Compress::Auto);
+        assert_eq!("off".parse::<Compress>().unwrap(), Compress::Off);
+        assert_eq!("9".parse::<Compress>().unwrap(), Compress::Level(9));
+        assert!("0".parse::<Compress>().is_err());
+        assert!("20".parse::<Compress>().is_err());
+        assert!("fast".parse::<Compress>().is_err());
+    }
+
+    #[test]
+    fn test_auto_is_off_on_the_lan() {
+        let direct = |addr: &str| ConnectionType::Direct(addr.parse().unwrap());
+        let relay = ConnectionType::Relay("https://relay.example".parse().unwrap());
+
+        assert_eq!(Compress::Auto.level(&direct("192.168.1.5:4000")), None);
+        assert_eq!(Compress::Auto.level(&direct("10.0.0.2:4000")), None);
+        assert_eq!(Compress::Auto.level(&direct("[fe80::1]:4000")), None);
+        assert_eq!(Compress::Auto.level(&direct("[fd12::1]:4000")), None);
+        assert_eq!(
+            Compress::Auto.level(&direct("[::ffff:192.168.1.5]:4000")),
+            None
+        );
+        assert_eq!(
+            Compress::Auto.level(&direct("203.0.113.7:4000")),
+            Some(DEFAULT_LEVEL)
+        );
+        assert_eq!(Compress::Auto.level(&relay), Some(DEFAULT_LEVEL));
+        assert_eq!(
+            Compress::Auto.level(&ConnectionType::None),
+            Some(DEFAULT_LEVEL)
+        );
+        assert_eq!(Compress::Level(7).level(&direct("10.0.0.2:4000")), Some(7));
+        assert_eq!(Compress::Off.level(&relay), None);
+        assert_eq!(Compress::Off.features() & control::COMPRESS, 0);
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip_compresses() {
+        // repetitive like a PutImage of a flat background
+        let data: Vec<u8> = (0..1_000_000u32).map(|i| (i / 4096) as u8).collect();
+        let mut packed = Vec::new();
+        compress(&data[..], &mut packed, DEFAULT_LEVEL)
+            .await
+            .unwrap();
+        assert!(packed.len() < data.len() / 10);
+
+        let mut plain = Vec::new();
+        decompress(&packed[..], &mut plain).await.unwrap();
+        assert_eq!(plain, data);
+    }
+
+    #[tokio::test]
+    async fn test_every_write_arrives_without_waiting() {
+        let (a, b) = io::duplex(4096);
+        let mut a = wrap(a, DEFAULT_LEVEL);
+        let mut b = wrap(b, 1);
+
+        // request and reply stay open, so only flush points can deliver them
+        for round in 0..3u8 {
+            let request = vec![round; 32];
+            a.write_all(&request).await.unwrap();
+            let mut buf = [0u8; 32];
+            tokio::time::timeout(Duration::from_secs(5), b.read_exact(&mut buf))
+                .await
+                .expect("request was held back")
+                .unwrap();
+            assert_eq!(buf.to_vec(), request);
+
+            b.write_all(b"reply").await.unwrap();
+            let mut buf = [0u8; 5];
+            tokio::time::timeout(Duration::from_secs(5), a.read_exact(&mut buf))
+                .await
+                .expect("reply was held back")
+                .unwrap();
+            assert_eq!(&buf, b"reply");
+        }
+
+        drop(a);
+        let mut rest = Vec::new();
+        b.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+}
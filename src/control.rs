@@ -0,0 +1,510 @@
+//! Control stream: the first stream of every x11q connection
+//!
+//! The dialing side opens it right after connecting. Both sides say HELLO
+//! with the newest protocol version they speak and the features they
+//! support; each then uses the lower version and the common features. The
+//! accepting side may authenticate the peer with AUTH messages (the
+//! word-code pake) before it lets it in with READY. After that the stream
+//! carries keepalive pings until one side ends the session with GOODBYE:
+//!
+//! ```text
+//! dialer   -> acceptor  HELLO    version u16, features u32
+//! acceptor -> dialer    HELLO    version u16, features u32
+//! both                  AUTH     pake message (serve/join and pair only)
+//! acceptor -> dialer    PRIME    x11 cache contents (CACHE only)
+//! acceptor -> dialer    READY
+//! both                  PING / PONG  u64
+//! both                  GOODBYE  reason
+//! ```
+//!
+//! Messages are framed as `[type u8][len u32 LE][payload]`. Unknown message
+//! types and extra payload bytes are skipped, so newer peers can add both.
+//! Every other stream starts with a StreamHeader saying what it carries.
+
+use anyhow::{Context, Result};
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// Newest protocol version we speak
+pub const VERSION: u16 = 1;
+/// Oldest protocol version we still accept
+const MIN_VERSION: u16 = 1;
+
+/// Runs programs on its display for the peer (`serve -- exec`)
+pub const EXEC: u32 = 1 << 0;
+/// Takes zstd compressed X11 streams (StreamHeader COMPRESSED)
+pub const COMPRESS: u32 = 1 << 1;
+/// Primes the peer's X11 cache with a PRIME before READY
+pub const CACHE: u32 = 1 << 2;
+/// Every feature this version supports
+pub const FEATURES: u32 = EXEC | COMPRESS | CACHE;
+
+/// Stream option: both directions are zstd compressed, needs COMPRESS
+pub const COMPRESSED: u8 = 1 << 0;
+const OPTIONS: u8 = COMPRESSED;
+
+const HELLO: u8 = 1;
+const AUTH: u8 = 2;
+const READY: u8 = 3;
+const PING: u8 = 4;
+const PONG: u8 = 5;
+const GOODBYE: u8 = 6;
+const PRIME: u8 = 7;
+
+pub const MAX_MESSAGE: usize = 64 * 1024;
+/// Often enough to keep nat mappings and relay paths warm
+const KEEPALIVE: Duration = Duration::from_secs(15);
+/// How long GOODBYE waits for the peer to close the connection
+const GOODBYE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    Hello {
+        version: u16,
+        features: u32,
+    },
+    Auth(Vec<u8>),
+    Ready,
+    Ping(u64),
+    Pong(u64),
+    Goodbye(String),
+    Prime(Vec<u8>),
+    /// from a newer peer, skipped
+    Unknown(u8),
+}
+
+impl Message {
+    fn encode(&self) -> Vec<u8> {
+        let (tag, payload) = match self {
+            Message::Hello { version, features } => (
+                HELLO,
+                [&version.to_le_bytes()[..], &features.to_le_bytes()].concat(),
+            ),
+            Message::Auth(data) => (AUTH, data.clone()),
+            Message::Ready => (READY, Vec::new()),
+            Message::Ping(n) => (PING, n.to_le_bytes().to_vec()),
+            Message::Pong(n) => (PONG, n.to_le_bytes().to_vec()),
+            Message::Goodbye(reason) => (GOODBYE, reason.as_bytes().to_vec()),
+            Message::Prime(data) => (PRIME, data.clone()),
+            Message::Unknown(tag) => (*tag, Vec::new()),
+        };
+        let mut buf = Vec::with_capacity(5 + payload.len());
+        buf.push(tag);
+        buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&payload);
+        buf
+    }
+
+    fn decode(tag: u8, payload: Vec<u8>) -> Result<Self> {
+        let short = || anyhow::anyhow!("control message {} too short", tag);
+        Ok(match tag {
+            HELLO => {
+                let version = payload.get(..2).ok_or_else(short)?;
+                let features = payload.get(2..6).ok_or_else(short)?;
+                Message::Hello {
+                    version: u16::from_le_bytes(version.try_into()?),
+                    features: u32::from_le_bytes(features.try_into()?),
+                }
+            }
+            AUTH => Message::Auth(payload),
+            READY => Message::Ready,
+            PING | PONG => {
+                let n = u64::from_le_bytes(payload.get(..8).ok_or_else(short)?.try_into()?);
+                if tag == PING {
+                    Message::Ping(n)
+                } else {
+                    Message::Pong(n)
+                }
+            }
+            GOODBYE => Message::Goodbye(String::from_utf8_lossy(&payload).into_owned()),
+            PRIME => Message::Prime(payload),
+            tag => Message::Unknown(tag),
+        })
+    }
+}
+
+async fn write_message<W: AsyncWrite + Unpin>(w: &mut W, msg: &Message) -> Result<()> {
+    w.write_all(&msg.encode()).await?;
+    Ok(())
+}
+
+/// None on a clean end of stream
+async fn read_message<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<Message>> {
+    let mut tag = [0u8; 1];
+    match r.read_exact(&mut tag).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = r.read_u32_le().await? as usize;
+    anyhow::ensure!(len <= MAX_MESSAGE, "control message too large");
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload).await?;
+    Message::decode(tag[0], payload).map(Some)
+}
+
+/// Protocol version and features both sides support
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Negotiated {
+    pub version: u16,
+    features: u32,
+}
+
+impl Negotiated {
+    pub fn has(&self, feature: u32) -> bool {
+        self.features & feature == feature
+    }
+
+    pub fn features(&self) -> u32 {
+        self.features
+    }
+}
+
+/// Agree on the peer's `version` and `features` and the ones we `offered`
+fn negotiate(version: u16, features: u32, offered: u32) -> Result<Negotiated> {
+    anyhow::ensure!(
+        version >= MIN_VERSION,
+        "peer speaks protocol version {}, we need at least {} (upgrade it)",
+        version,
+        MIN_VERSION
+    );
+    Ok(Negotiated {
+        version: version.min(VERSION),
+        features: features & offered,
+    })
+}
+
+fn hello(features: u32) -> Message {
+    Message::Hello {
+        version: VERSION,
+        features,
+    }
+}
+
+/// The control stream of one connection
+pub struct Control {
+    send: SendStream,
+    recv: RecvStream,
+    pub negotiated: Negotiated,
+    /// the acceptor's PRIME, if it sent one
+    prime: Option<Vec<u8>>,
+}
+
+/// Dialing side: open the control stream and exchange HELLO, offering
+/// `features` (some of FEATURES)
+pub async fn open(conn: &Connection, features: u32) -> Result<Control> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    write_message(&mut send, &hello(features)).await?;
+    let negotiated = read_hello(&mut recv, features).await?;
+    Ok(Control {
+        send,
+        recv,
+        negotiated,
+        prime: None,
+    })
+}
+
+/// Accepting side: take the peer's control stream and answer its HELLO
+pub async fn accept(conn: &Connection, features: u32) -> Result<Control> {
+    let (mut send, mut recv) = conn.accept_bi().await?;
+    let negotiated = read_hello(&mut recv, features).await?;
+    write_message(&mut send, &hello(features)).await?;
+    Ok(Control {
+        send,
+        recv,
+        negotiated,
+        prime: None,
+    })
+}
+
+async fn read_hello(recv: &mut RecvStream, offered: u32) -> Result<Negotiated> {
+    match next_message(recv).await? {
+        Message::Hello { version, features } => negotiate(version, features, offered),
+        msg => anyhow::bail!("expected hello, got {:?}", msg),
+    }
+}
+
+/// Next message that means something during the handshake
+async fn next_message(recv: &mut RecvStream) -> Result<Message> {
+    loop {
+        match read_message(recv)
+            .await?
+            .context("peer closed the control stream")?
+        {
+            Message::Unknown(_) | Message::Ping(_) | Message::Pong(_) => continue,
+            Message::Goodbye(reason) => anyhow::bail!("peer closed the session: {}", reason),
+            msg => return Ok(msg),
+        }
+    }
+}
+
+impl Control {
+    pub async fn send_auth(&mut self, data: &[u8]) -> Result<()> {
+        write_message(&mut self.send, &Message::Auth(data.to_vec())).await
+    }
+
+    pub async fn recv_auth(&mut self) -> Result<Vec<u8>> {
+        match next_message(&mut self.recv).await? {
+            Message::Auth(data) => Ok(data),
+            msg => anyhow::bail!("expected auth, got {:?}", msg),
+        }
+    }
+
+    /// Accepting side: send what the peer's X11 cache starts with
+    pub async fn send_prime(&mut self, data: &[u8]) -> Result<()> {
+        write_message(&mut self.send, &Message::Prime(data.to_vec())).await
+    }
+
+    /// Accepting side: the peer may open streams now
+    pub async fn admit(&mut self) -> Result<()> {
+        write_message(&mut self.send, &Message::Ready).await
+    }
+
+    /// Dialing side: wait until the acceptor lets us in
+    pub async fn ready(&mut self) -> Result<()> {
+        loop {
+            match next_message(&mut self.recv).await? {
+                Message::Ready => return Ok(()),
+                Message::Prime(data) => self.prime = Some(data),
+                msg => anyhow::bail!("expected ready, got {:?}", msg),
+            }
+        }
+    }
+
+    /// Dialing side: the PRIME that came before READY
+    pub fn take_prime(&mut self) -> Option<Vec<u8>> {
+        self.prime.take()
+    }
+
+    /// Keep the connection alive in the background until either side ends it
+    pub fn spawn(self, conn: Connection) -> Handle {
+        let (outgoing, queued) = mpsc::unbounded_channel();
+        let Control { send, mut recv, .. } = self;
+
+        // reading isn't cancel safe, so it gets a task of its own
+        let replies = outgoing.clone();
+        let closing = conn.clone();
+        tokio::spawn(async move {
+            while let Ok(Some(msg)) = read_message(&mut recv).await {
+                match msg {
+                    Message::Ping(n) => {
+                        let _ = replies.send(Message::Pong(n));
+                    }
+                    Message::Goodbye(reason) => {
+                        eprintln!("peer closed the session: {}", reason);
+                        closing.close(0u32.into(), reason.as_bytes());
+                        return;
+                    }
+                    _ => {}
+                }
+            }
+        });
+        tokio::spawn(keepalive(send, queued));
+
+        Handle { outgoing, conn }
+    }
+}
+
+async fn keepalive(mut send: SendStream, mut queued: mpsc::UnboundedReceiver<Message>) {
+    let mut ping = tokio::time::interval(KEEPALIVE);
+    let mut sent = 0u64;
+    loop {
+        let msg = tokio::select! {
+            _ = ping.tick() => {
+                sent += 1;
+                Message::Ping(sent)
+            }
+            Some(msg) = queued.recv() => msg,
+        };
+        let last = matches!(msg, Message::Goodbye(_));
+        if write_message(&mut send, &msg).await.is_err() {
+            return;
+        }
+        if last {
+            let _ = send.finish();
+            return;
+        }
+    }
+}
+
+/// Running control stream of a connection
+pub struct Handle {
+    outgoing: mpsc::UnboundedSender<Message>,
+    conn: Connection,
+}
+
+impl Handle {
+    /// End the session on purpose: tell the peer why, then close
+    pub async fn goodbye(&self, reason: &str) {
+        if self
+            .outgoing
+            .send(Message::Goodbye(reason.to_string()))
+            .is_ok()
+        {
+            // the peer closes the connection once it has read it
+            let _ = tokio::time::timeout(GOODBYE_TIMEOUT, self.conn.closed()).await;
+        }
+        self.conn.close(0u32.into(), reason.as_bytes());
+    }
+}
+
+/// What a stream other than the control stream carries
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamKind {
+    /// One X11 client connection, opened by the joining side
+    X11 = 1,
+    /// One remote command, opened by the serving side
+    Exec = 2,
+}
+
+/// First bytes of every other stream: `[kind u8][options u8][display u16 LE]`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StreamHeader {
+    pub kind: StreamKind,
+    /// Per-stream options (COMPRESSED), only ones both sides negotiated
+    pub options: u8,
+    /// Display number on the joining side
+    pub display: u16,
+}
+
+impl StreamHeader {
+    pub fn new(kind: StreamKind, display: u16) -> Self {
+        Self {
+            kind,
+            options: 0,
+            display,
+        }
+    }
+
+    pub fn encode(&self) -> [u8; 4] {
+        let display = self.display.to_le_bytes();
+        [self.kind as u8, self.options, display[0], display[1]]
+    }
+
+    /// Read a header, refusing streams that aren't `kind`
+    pub async fn read<R: AsyncRead + Unpin>(r: &mut R, kind: StreamKind) -> Result<Self> {
+        let mut buf = [0u8; 4];
+        r.read_exact(&mut buf)
+            .await
+            .context("failed to read stream header")?;
+        let header = Self {
+            kind: match buf[0] {
+                1 => StreamKind::X11,
+                2 => StreamKind::Exec,
+                n => anyhow::bail!("unknown stream kind {}", n),
+            },
+            options: buf[1],
+            display: u16::from_le_bytes([buf[2], buf[3]]),
+        };
+        anyhow::ensure!(
+            header.kind == kind,
+            "expected a {:?} stream, got {:?}",
+            kind,
+            header.kind
+        );
+        anyhow::ensure!(
+            header.options & !OPTIONS == 0,
+            "unsupported stream options {:#04x}",
+            header.options
+        );
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_messages_roundtrip() {
+        let msgs = [
+            hello(FEATURES),
+            Message::Auth(b"pake".to_vec()),
+            Message::Ready,
+            Message::Ping(7),
+            Message::Pong(7),
+            Message::Goodbye("command exited".into()),
+            Message::Prime(vec![1, 2, 3]),
+        ];
+        let bytes: Vec<u8> = msgs.iter().flat_map(Message::encode).collect();
+        let mut r = &bytes[..];
+        for msg in msgs {
+            assert_eq!(read_message(&mut r).await.unwrap(), Some(msg));
+        }
+        assert_eq!(read_message(&mut r).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_newer_peers_are_understood() {
+        // a hello with a field we don't know yet, then an unknown message
+        let mut bytes = vec![HELLO, 10, 0, 0, 0];
+        bytes.extend_from_slice(&9u16.to_le_bytes());
+        bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+        bytes.extend_from_slice(&[0xaa; 4]);
+        bytes.extend_from_slice(&[200, 1, 0, 0, 0, 0]);
+
+        let mut r = &bytes[..];
+        let Some(Message::Hello { version, features }) = read_message(&mut r).await.unwrap() else {
+            panic!("expected hello");
+        };
+        let negotiated = negotiate(version, features, FEATURES).unwrap();
+        assert_eq!(negotiated.version, VERSION);
+        assert!(negotiated.has(EXEC));
+        assert_eq!(negotiated.features, FEATURES);
+        assert_eq!(
+            read_message(&mut r).await.unwrap(),
+            Some(Message::Unknown(200))
+        );
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert!(negotiate(0, FEATURES, FEATURES).is_err());
+        let old = negotiate(1, 0, FEATURES).unwrap();
+        assert_eq!(old.version, 1);
+        assert!(!old.has(EXEC));
+        // only what we offered, whatever the peer supports
+        let plain = negotiate(1, FEATURES, EXEC).unwrap();
+        assert!(plain.has(EXEC));
+        assert!(!plain.has(COMPRESS));
+        assert!(Message::decode(HELLO, vec![1]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_header() {
+        let header = StreamHeader::new(StreamKind::X11, 99);
+        let bytes = header.encode();
+        assert_eq!(bytes, [1, 0, 99, 0]);
+        assert_eq!(
+            StreamHeader::read(&mut &bytes[..], StreamKind::X11)
+                .await
+                .unwrap(),
+            header
+        );
+        assert!(StreamHeader::read(&mut &bytes[..], StreamKind::Exec)
+            .await
+            .is_err());
+        let compressed = StreamHeader {
+            options: COMPRESSED,
+            ..header
+        };
+        assert_eq!(
+            StreamHeader::read(&mut &compressed.encode()[..], StreamKind::X11)
+                .await
+                .unwrap(),
+            compressed
+        );
+        assert!(
+            StreamHeader::read(&mut &[1u8, 4, 0, 0][..], StreamKind::X11)
+                .await
+                .is_err()
+        );
+        assert!(
+            StreamHeader::read(&mut &[9u8, 0, 0, 0][..], StreamKind::X11)
+                .await
+                .is_err()
+        );
+    }
+}
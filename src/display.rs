@@ -12,12 +12,7 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::TcpListener;
 use tokio::sync::Mutex;
 
-#[cfg(unix)]
-use tokio::net::UnixListener;
-
-#[cfg(unix)]
-const X11_UNIX_DIR: &str = "/tmp/.X11-unix";
-const X11_TCP_BASE: u16 = 6000;
+use crate::xlock::{self, DisplayLock, DisplayNumber};
 
 // Extension major opcodes
 const RANDR_MAJOR_OPCODE: u8 = 140;
@@ -1307,14 +1302,18 @@ where
 }
 
 /// Run native X11 display server
-pub async fn run_display(display_num: u32, width: u32, height: u32) -> Result<()> {
+pub async fn run_display(display: DisplayNumber, width: u32, height: u32) -> Result<()> {
     use std::sync::atomic::{AtomicU32, Ordering};
     static CLIENT_COUNTER: AtomicU32 = AtomicU32::new(0);
 
+    // held until the window closes, which removes the socket and lock
+    let lock = DisplayLock::acquire(display)?;
+    let display_num = lock.display_num;
+
     let state = Arc::new(Mutex::new(DisplayState::new(width, height)));
 
     // TCP listener (works on all platforms including Windows)
-    let tcp_port = X11_TCP_BASE + display_num as u16;
+    let tcp_port = xlock::tcp_port(display_num);
     let tcp_listener = TcpListener::bind(format!("0.0.0.0:{}", tcp_port)).await?;
     eprintln!(
         "listening on TCP port {} (DISPLAY=hostname:{})",
@@ -1324,10 +1323,8 @@ pub async fn run_display(display_num: u32, width: u32, height: u32) -> Result<()
     // Unix socket (only on Unix platforms)
     #[cfg(unix)]
     let unix_listener = {
-        let socket_path = format!("{}/X{}", X11_UNIX_DIR, display_num);
-        let _ = std::fs::remove_file(&socket_path);
-        std::fs::create_dir_all(X11_UNIX_DIR)?;
-        let listener = UnixListener::bind(&socket_path)?;
+        let socket_path = lock.socket_path();
+        let listener = lock.listen()?;
         eprintln!(
             "listening on Unix socket {} (DISPLAY=:{})",
             socket_path, display_num
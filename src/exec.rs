@@ -0,0 +1,365 @@
+//! Remote command execution over an x11q session
+//!
+//! The serving side (the one with the monitor) opens an exec stream and
+//! asks the joining side to start a program on its virtual display, like
+//! `ssh -X host firefox`. Output and the exit status stream back:
+//!
+//! ```text
+//! serve -> join   StreamHeader (Exec), then EXEC  argv, NUL separated
+//! join  -> serve  STDOUT / STDERR chunks, then EXIT (i32 LE) or ERROR (text)
+//! ```
+//!
+//! The joining side refuses every request unless it was started with
+//! `--allow-exec`, and then only runs the command lines named there. An
+//! entry ending in `*` admits any further arguments, and with them
+//! everything the program can run: `xterm *` allows `xterm -e sh`.
+
+use crate::control::{StreamHeader, StreamKind};
+use anyhow::{Context, Result};
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use std::path::PathBuf;
+use std::process::{ExitStatus, Stdio};
+use std::str::FromStr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::process::{Child, Command};
+
+const EXEC: u8 = 1;
+const STDOUT: u8 = 2;
+const STDERR: u8 = 3;
+const EXIT: u8 = 4;
+const ERROR: u8 = 5;
+
+const MAX_FRAME: usize = 1024 * 1024;
+const CHUNK: usize = 16 * 1024;
+
+/// One `--allow-exec` entry: a command line, optionally ending in `*`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExecRule {
+    argv: Vec<String>,
+    /// trailing `*`: any further arguments
+    any_args: bool,
+}
+
+impl FromStr for ExecRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut argv: Vec<String> = s.split_whitespace().map(str::to_string).collect();
+        let any_args = argv.last().is_some_and(|a| a == "*");
+        if any_args {
+            argv.pop();
+        }
+        anyhow::ensure!(
+            !argv.is_empty(),
+            "expected a program, e.g. 'firefox' or 'firefox *'"
+        );
+        Ok(Self { argv, any_args })
+    }
+}
+
+impl ExecRule {
+    /// words match exactly, so "firefox" never admits "/tmp/x/firefox"
+    /// or "firefox --some-flag"
+    fn allows(&self, argv: &[String]) -> bool {
+        let n = self.argv.len();
+        argv.len() >= n && argv[..n] == self.argv[..] && (self.any_args || argv.len() == n)
+    }
+}
+
+/// Command lines the serving side may start, as given on `--allow-exec`
+#[derive(Clone, Debug, Default)]
+pub struct Allowlist(Vec<ExecRule>);
+
+impl Allowlist {
+    pub fn new(rules: Vec<ExecRule>) -> Self {
+        Self(rules)
+    }
+
+    fn allows(&self, argv: &[String]) -> bool {
+        self.0.iter().any(|rule| rule.allows(argv))
+    }
+}
+
+/// Environment a spawned program gets to reach the virtual display
+#[derive(Clone, Debug)]
+pub struct DisplayEnv {
+    pub display: String,
+    pub xauthority: PathBuf,
+}
+
+/// Parse the trailing `exec CMD [ARGS]` of `x11q serve -- exec CMD`
+pub fn parse_command(args: &[String]) -> Result<Option<Vec<String>>> {
+    match args {
+        [] => Ok(None),
+        [verb, argv @ ..] if verb == "exec" && !argv.is_empty() => Ok(Some(argv.to_vec())),
+        _ => anyhow::bail!("expected `-- exec CMD [ARGS]`"),
+    }
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(w: &mut W, tag: u8, payload: &[u8]) -> Result<()> {
+    w.write_all(&[tag]).await?;
+    w.write_all(&(payload.len() as u32).to_le_bytes()).await?;
+    w.write_all(payload).await?;
+    Ok(())
+}
+
+/// None on a clean end of stream
+async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<(u8, Vec<u8>)>> {
+    let mut header = [0u8; 5];
+    match r.read_exact(&mut header[..1]).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    r.read_exact(&mut header[1..]).await?;
+    let len = u32::from_le_bytes([header[1], header[2], header[3], header[4]]) as usize;
+    anyhow::ensure!(len <= MAX_FRAME, "exec frame too large");
+    let mut payload = vec![0u8; len];
+    r.read_exact(&mut payload).await?;
+    Ok(Some((header[0], payload)))
+}
+
+fn encode_argv(argv: &[String]) -> Vec<u8> {
+    argv.join("\0").into_bytes()
+}
+
+fn decode_argv(data: &[u8]) -> Result<Vec<String>> {
+    let argv = std::str::from_utf8(data)
+        .context("command is not utf8")?
+        .split('\0')
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    anyhow::ensure!(!argv[0].is_empty(), "empty command");
+    Ok(argv)
+}
+
+/// Run `argv` on the joining side, relaying its output here
+///
+/// Returns the remote exit status.
+pub async fn run_remote(conn: &Connection, argv: &[String]) -> Result<i32> {
+    let (mut send, mut recv) = conn.open_bi().await?;
+    send.write_all(&StreamHeader::new(StreamKind::Exec, 0).encode())
+        .await?;
+    write_frame(&mut send, EXEC, &encode_argv(argv)).await?;
+    send.finish()?;
+
+    let mut stdout = tokio::io::stdout();
+    let mut stderr = tokio::io::stderr();
+    loop {
+        match read_frame(&mut recv).await? {
+            Some((STDOUT, data)) => {
+                stdout.write_all(&data).await?;
+                stdout.flush().await?;
+            }
+            Some((STDERR, data)) => {
+                stderr.write_all(&data).await?;
+                stderr.flush().await?;
+            }
+            Some((EXIT, data)) => {
+                let code: [u8; 4] = data.try_into().ok().context("bad exit status")?;
+                return Ok(i32::from_le_bytes(code));
+            }
+            Some((ERROR, data)) => {
+                anyhow::bail!("remote: {}", String::from_utf8_lossy(&data))
+            }
+            Some((tag, _)) => anyhow::bail!("unexpected exec frame {}", tag),
+            None => anyhow::bail!("remote closed the command stream without an exit status"),
+        }
+    }
+}
+
+/// Serve exec requests from the other side until the connection closes
+pub async fn accept_commands(conn: Connection, allowlist: Allowlist, env: DisplayEnv) {
+    while let Ok((send, recv)) = conn.accept_bi().await {
+        let allowlist = allowlist.clone();
+        let env = env.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_command(send, recv, &allowlist, &env).await {
+                eprintln!("exec error: {e}");
+            }
+        });
+    }
+}
+
+async fn handle_command(
+    mut send: SendStream,
+    mut recv: RecvStream,
+    allowlist: &Allowlist,
+    env: &DisplayEnv,
+) -> Result<()> {
+    StreamHeader::read(&mut recv, StreamKind::Exec).await?;
+    let argv = &match read_frame(&mut recv).await? {
+        Some((EXEC, data)) => decode_argv(&data)?,
+        _ => anyhow::bail!("expected an exec request"),
+    };
+
+    if !allowlist.allows(argv) {
+        eprintln!("exec refused: {}", argv.join(" "));
+        let msg = if allowlist.0.is_empty() {
+            "remote command execution is disabled (join with --allow-exec CMD)".to_string()
+        } else {
+            format!("'{}' is not in the --allow-exec list", argv.join(" "))
+        };
+        return refuse(send, &msg).await;
+    }
+
+    eprintln!("exec: {}", argv.join(" "));
+    let spawned = display_command(argv, env)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // the program goes away with the session, like under ssh
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => return refuse(send, &format!("{}: {}", argv[0], e)).await,
+    };
+
+    let mut stdout = child.stdout.take().context("no stdout")?;
+    let mut stderr = child.stderr.take().context("no stderr")?;
+    let (mut out_buf, mut err_buf) = (vec![0u8; CHUNK], vec![0u8; CHUNK]);
+    let (mut out_done, mut err_done) = (false, false);
+
+    while !(out_done && err_done) {
+        tokio::select! {
+            n = stdout.read(&mut out_buf), if !out_done => match n? {
+                0 => out_done = true,
+                n => write_frame(&mut send, STDOUT, &out_buf[..n]).await?,
+            },
+            n = stderr.read(&mut err_buf), if !err_done => match n? {
+                0 => err_done = true,
+                n => write_frame(&mut send, STDERR, &err_buf[..n]).await?,
+            },
+        }
+    }
+
+    let code = exit_code(child.wait().await?);
+    eprintln!("exec: {} exited with {}", argv[0], code);
+    write_frame(&mut send, EXIT, &code.to_le_bytes()).await?;
+    send.finish()?;
+    Ok(())
+}
+
+fn display_command(argv: &[String], env: &DisplayEnv) -> Command {
+    let mut command = Command::new(&argv[0]);
+    command
+        .args(&argv[1..])
+        .env("DISPLAY", &env.display)
+        .env("XAUTHORITY", &env.xauthority);
+    command
+}
+
+/// Start a local program on the virtual display, sharing our terminal
+pub fn spawn_with_display(argv: &[String], env: &DisplayEnv) -> Result<Child> {
+    display_command(argv, env)
+        .spawn()
+        .with_context(|| format!("failed to run {}", argv[0]))
+}
+
+/// Wait for `child` to exit, or forever without one
+pub async fn wait(child: &mut Option<Child>) -> std::io::Result<ExitStatus> {
+    match child {
+        Some(child) => child.wait().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn refuse(mut send: SendStream, msg: &str) -> Result<()> {
+    write_frame(&mut send, ERROR, msg.as_bytes()).await?;
+    send.finish()?;
+    Ok(())
+}
+
+/// shell convention: 128 + signal for a killed process
+pub fn exit_code(status: ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+    }
+    status.code().unwrap_or(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &[&str]) -> Vec<String> {
+        s.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(&[]).unwrap(), None);
+        assert_eq!(
+            parse_command(&args(&["exec", "firefox", "--new-window"])).unwrap(),
+            Some(args(&["firefox", "--new-window"]))
+        );
+        assert!(parse_command(&args(&["exec"])).is_err());
+        assert!(parse_command(&args(&["firefox"])).is_err());
+    }
+
+    #[test]
+    fn test_argv_roundtrip() {
+        let argv = args(&["xterm", "-e", "echo hi there", ""]);
+        assert_eq!(decode_argv(&encode_argv(&argv)).unwrap(), argv);
+        assert!(decode_argv(b"\0-e").is_err());
+    }
+
+    fn allowlist(entries: &[&str]) -> Allowlist {
+        Allowlist::new(entries.iter().map(|e| e.parse().unwrap()).collect())
+    }
+
+    #[test]
+    fn test_allowlist_is_exact() {
+        let allow = allowlist(&["firefox", "/usr/bin/xterm", "xclock -digital"]);
+        assert!(allow.allows(&args(&["firefox"])));
+        assert!(allow.allows(&args(&["/usr/bin/xterm"])));
+        assert!(allow.allows(&args(&["xclock", "-digital"])));
+        assert!(!allow.allows(&args(&["/tmp/evil/firefox"])));
+        assert!(!allow.allows(&args(&["xterm"])));
+        // arguments are part of the match
+        assert!(!allow.allows(&args(&["/usr/bin/xterm", "-e", "sh", "-c", "id"])));
+        assert!(!allow.allows(&args(&["firefox", "--new-window"])));
+        assert!(!allow.allows(&args(&["xclock"])));
+        assert!(!allow.allows(&args(&["xclock", "-digital", "-update", "1"])));
+        assert!(!Allowlist::default().allows(&args(&["firefox"])));
+    }
+
+    #[test]
+    fn test_allowlist_wildcard() {
+        let allow = allowlist(&["firefox *", "xclock -digital *"]);
+        assert!(allow.allows(&args(&["firefox"])));
+        assert!(allow.allows(&args(&["firefox", "--new-window", "x.org"])));
+        assert!(allow.allows(&args(&["xclock", "-digital", "-update", "1"])));
+        assert!(!allow.allows(&args(&["xclock", "-analog"])));
+        assert!(!allow.allows(&args(&["firefoxx"])));
+
+        assert!("*".parse::<ExecRule>().is_err());
+        assert!("  ".parse::<ExecRule>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_frames() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let writer = tokio::spawn(async move {
+            write_frame(&mut a, STDOUT, b"hello").await.unwrap();
+            write_frame(&mut a, EXIT, &3i32.to_le_bytes())
+                .await
+                .unwrap();
+        });
+        assert_eq!(
+            read_frame(&mut b).await.unwrap(),
+            Some((STDOUT, b"hello".to_vec()))
+        );
+        assert_eq!(
+            read_frame(&mut b).await.unwrap(),
+            Some((EXIT, 3i32.to_le_bytes().to_vec()))
+        );
+        writer.await.unwrap();
+        assert_eq!(read_frame(&mut b).await.unwrap(), None);
+    }
+}
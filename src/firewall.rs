@@ -0,0 +1,1212 @@
+//! X11 request firewall for untrusted remote clients
+//!
+//! Sits in the serve-side proxy between a forwarded client and the local
+//! X server, parsing both directions. Requests that would let the remote
+//! spy on or control the local session are never executed:
+//!
+//! - GetImage / CopyArea / CopyPlane from any drawable outside the x11q
+//!   session (screenshots of the root or of other windows), and RENDER
+//!   CreatePicture on one, which would let the client composite from it
+//! - GrabKeyboard, and GrabKey on windows the client doesn't own
+//! - QueryKeymap, answered as if no key were held down
+//! - ConvertSelection (reading the local clipboard), refused the way an
+//!   owner that can't convert would
+//! - XTEST, RECORD, Composite and XInputExtension (hidden from
+//!   QueryExtension/ListExtensions, and their opcodes answer BadRequest):
+//!   input injection and recording, other windows' pixmaps, raw key events
+//! - GetProperty / ListProperties on windows outside the x11q session
+//!
+//! Requests in BIG-REQUESTS encoding are judged on the same fields as
+//! short ones, with the extra length word taken out.
+//!
+//! Forwarded clients also get a sandboxed view of the window tree: QueryTree
+//! only lists windows created by clients of the same x11q session (every
+//! X11 stream of one QUIC connection), the window manager's lists on the
+//! root (`_NET_CLIENT_LIST`, `_NET_CLIENT_LIST_STACKING`,
+//! `_NET_ACTIVE_WINDOW`) only hold those windows, and events about other
+//! windows are dropped, so nothing on the remote can enumerate the desktop.
+//!
+//! A blocked request is replaced by a GetInputFocus so the server's
+//! sequence numbers stay in step with the client's. Its 32-byte reply is
+//! then swapped for an X error, or for a neutered reply (empty property,
+//! AlreadyGrabbed, extension absent) that keeps well-behaved apps going.
+
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// core request opcodes
+const X_QUERY_TREE: u8 = 15;
+const X_GET_PROPERTY: u8 = 20;
+const X_LIST_PROPERTIES: u8 = 21;
+const X_GRAB_KEYBOARD: u8 = 31;
+const X_CONVERT_SELECTION: u8 = 24;
+const X_GRAB_KEY: u8 = 33;
+const X_GET_INPUT_FOCUS: u8 = 43;
+const X_QUERY_KEYMAP: u8 = 44;
+const X_COPY_AREA: u8 = 62;
+const X_COPY_PLANE: u8 = 63;
+const X_GET_IMAGE: u8 = 73;
+const X_QUERY_EXTENSION: u8 = 98;
+const X_LIST_EXTENSIONS: u8 = 99;
+
+// RENDER minor opcodes
+const RENDER_CREATE_PICTURE: u8 = 4;
+
+// error codes
+const BAD_REQUEST: u8 = 1;
+const BAD_ACCESS: u8 = 10;
+
+// reply types
+const X_ERROR: u8 = 0;
+const X_REPLY: u8 = 1;
+const X_GENERIC_EVENT: u8 = 35;
+
+// core events that aren't about one particular window
+const X_KEYMAP_NOTIFY: u8 = 11;
+const X_NO_EXPOSURE: u8 = 14;
+const X_SELECTION_CLEAR: u8 = 29;
+const X_SELECTION_NOTIFY: u8 = 31;
+const X_MAPPING_NOTIFY: u8 = 34;
+
+const ALREADY_GRABBED: u8 = 1;
+
+/// Extensions untrusted clients must not use
+pub const HIDDEN_EXTENSIONS: &[&str] = &["XTEST", "RECORD", "Composite", "XInputExtension"];
+/// Root window properties that list the desktop's windows
+const WINDOW_LIST_PROPERTIES: &[&str] = &[
+    "_NET_CLIENT_LIST",
+    "_NET_CLIENT_LIST_STACKING",
+    "_NET_ACTIVE_WINDOW",
+];
+
+/// Requests up to this size are buffered whole for inspection; bigger
+/// ones (PutImage and friends) are judged on their header and streamed
+const MAX_INSPECT: usize = 64 * 1024;
+/// Enough of a request to read every field the policy looks at
+const HEADER_INSPECT: usize = 32;
+
+/// How much a forwarded client is trusted with the local display
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Trust {
+    /// Full access to the local display (like ssh -Y)
+    #[default]
+    Full,
+    /// Block screenshots, keyboard snooping, XTEST/RECORD and property snooping (like ssh -X)
+    Untrusted,
+}
+
+/// What the local X server looks like, probed once at startup
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    roots: Vec<u32>,
+    /// major opcodes of hidden extensions present on this server
+    hidden_majors: Vec<u8>,
+    /// major opcode of RENDER, if the server has it
+    render_major: Option<u8>,
+    /// atoms of the window list properties, where they exist
+    window_lists: Vec<u32>,
+}
+
+impl Policy {
+    /// Learn root windows, extension opcodes and atoms over a separate
+    /// connection
+    pub fn probe(display_num: u32) -> Result<Self> {
+        use x11rb::connection::{Connection, RequestConnection};
+        use x11rb::protocol::xproto::ConnectionExt;
+
+        let (conn, _) = x11rb::connect(Some(&format!(":{}", display_num)))
+            .context("untrusted mode needs to query the local X display")?;
+        let roots = conn.setup().roots.iter().map(|s| s.root).collect();
+        let mut hidden_majors = Vec::new();
+        for name in HIDDEN_EXTENSIONS {
+            if let Some(info) = conn.extension_information(name)? {
+                hidden_majors.push(info.major_opcode);
+            }
+        }
+        let render_major = conn
+            .extension_information("RENDER")?
+            .map(|info| info.major_opcode);
+        let mut window_lists = Vec::new();
+        for name in WINDOW_LIST_PROPERTIES {
+            let atom = conn.intern_atom(true, name.as_bytes())?.reply()?.atom;
+            if atom != 0 {
+                window_lists.push(atom);
+            }
+        }
+        Ok(Self {
+            roots,
+            hidden_majors,
+            render_major,
+            window_lists,
+        })
+    }
+}
+
+/// Resource ranges of every X11 client in one x11q session
+#[derive(Clone, Default)]
+pub struct Session {
+    clients: Arc<Mutex<Vec<(u32, u32)>>>,
+}
+
+impl Session {
+    /// Whether a client of this session created resource `id`
+    fn owns(&self, id: u32) -> bool {
+        self.clients
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|&(base, mask)| id & !mask == base)
+    }
+
+    fn register(&self, base: u32, mask: u32) {
+        self.clients.lock().unwrap().push((base, mask));
+    }
+
+    /// the server hands the range out again once the client is gone
+    fn unregister(&self, base: u32) {
+        self.clients.lock().unwrap().retain(|&(b, _)| b != base);
+    }
+}
+
+/// Byte order of one X11 connection, as the client chose in its setup
+#[derive(Clone, Copy)]
+pub struct ByteOrder {
+    big: bool,
+}
+
+impl ByteOrder {
+    /// From the setup's first byte, b'l' or b'B'
+    pub fn new(byte_order: u8) -> Self {
+        Self {
+            big: byte_order == b'B',
+        }
+    }
+
+    pub fn u16(self, b: &[u8]) -> u16 {
+        let b = [b[0], b[1]];
+        if self.big {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+
+    pub fn u32(self, b: &[u8]) -> u32 {
+        let b = [b[0], b[1], b[2], b[3]];
+        if self.big {
+            u32::from_be_bytes(b)
+        } else {
+            u32::from_le_bytes(b)
+        }
+    }
+
+    pub fn put_u16(self, out: &mut [u8], v: u16) {
+        let b = if self.big {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        };
+        out[..2].copy_from_slice(&b);
+    }
+
+    pub fn put_u32(self, out: &mut [u8], v: u32) {
+        let b = if self.big {
+            v.to_be_bytes()
+        } else {
+            v.to_le_bytes()
+        };
+        out[..4].copy_from_slice(&b);
+    }
+}
+
+/// What to send the client instead of the GetInputFocus reply
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Synthetic {
+    Error {
+        code: u8,
+        bad_value: u32,
+        major: u8,
+        minor: u8,
+    },
+    /// reply with `data` in byte 1 and an all-zero body, `length` words
+    /// longer than the minimal 32 bytes
+    EmptyReply { data: u8, length: u32 },
+    /// the SelectionNotify of a conversion that failed (property None)
+    SelectionRefused {
+        time: u32,
+        requestor: u32,
+        selection: u32,
+        target: u32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Pending {
+    Replace(Synthetic),
+    /// strip hidden extensions from a ListExtensions reply
+    FilterExtensions,
+    /// drop children outside the session from a QueryTree reply
+    FilterChildren,
+    /// drop windows outside the session from a window list property
+    FilterWindows,
+}
+
+/// Requests awaiting a rewritten reply, shared by both directions
+type PendingQueue = Arc<Mutex<VecDeque<(u16, Pending)>>>;
+
+enum Verdict {
+    Forward,
+    Track(Pending),
+    Block(Synthetic),
+}
+
+/// Something that rewrites one direction of an X11 byte stream
+pub trait Filter {
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>);
+}
+
+/// Remote client -> local X server
+pub struct RequestFilter {
+    order: ByteOrder,
+    policy: Arc<Policy>,
+    session: Session,
+    pending: PendingQueue,
+    seq: u16,
+    buf: Vec<u8>,
+    /// bytes of a large request still to pass through (or drop)
+    streaming: Option<(usize, bool)>,
+    logged: HashSet<u8>,
+}
+
+/// Local X server -> remote client
+pub struct ReplyFilter {
+    order: ByteOrder,
+    policy: Arc<Policy>,
+    session: Session,
+    pending: PendingQueue,
+    /// our resource base once the setup reply arrived
+    client: Option<u32>,
+    setup_done: bool,
+    buf: Vec<u8>,
+    /// bytes of a large reply still to pass through
+    streaming: usize,
+}
+
+/// Filters for both directions of a connection in `byte_order` (b'l'/b'B')
+pub fn filters(
+    policy: Arc<Policy>,
+    session: Session,
+    byte_order: u8,
+) -> (RequestFilter, ReplyFilter) {
+    let order = ByteOrder::new(byte_order);
+    let pending = PendingQueue::default();
+    (
+        RequestFilter {
+            order,
+            policy: Arc::clone(&policy),
+            session: session.clone(),
+            pending: Arc::clone(&pending),
+            seq: 0,
+            buf: Vec::new(),
+            streaming: None,
+            logged: HashSet::new(),
+        },
+        ReplyFilter {
+            order,
+            policy,
+            session,
+            pending,
+            client: None,
+            setup_done: false,
+            buf: Vec::new(),
+            streaming: 0,
+        },
+    )
+}
+
+impl RequestFilter {
+    fn is_root(&self, id: u32) -> bool {
+        self.policy.roots.contains(&id)
+    }
+
+    fn owns(&self, id: u32) -> bool {
+        self.session.owns(id)
+    }
+
+    fn field(&self, req: &[u8], offset: usize) -> Option<u32> {
+        req.get(offset..offset + 4).map(|b| self.order.u32(b))
+    }
+
+    fn decide(&self, req: &[u8]) -> Verdict {
+        let op = req[0];
+        let access = |bad_value| {
+            Verdict::Block(Synthetic::Error {
+                code: BAD_ACCESS,
+                bad_value,
+                major: op,
+                minor: 0,
+            })
+        };
+        let empty = |data| Verdict::Block(Synthetic::EmptyReply { data, length: 0 });
+
+        match op {
+            X_GET_IMAGE | X_COPY_AREA | X_COPY_PLANE => match self.field(req, 4) {
+                Some(src) if !self.owns(src) => access(src),
+                _ => Verdict::Forward,
+            },
+            X_CONVERT_SELECTION => {
+                let field = |offset| self.field(req, offset).unwrap_or(0);
+                Verdict::Block(Synthetic::SelectionRefused {
+                    requestor: field(4),
+                    selection: field(8),
+                    target: field(12),
+                    time: field(20),
+                })
+            }
+            X_GRAB_KEYBOARD => empty(ALREADY_GRABBED),
+            // 32 bytes of key bits after the header
+            X_QUERY_KEYMAP => Verdict::Block(Synthetic::EmptyReply { data: 0, length: 2 }),
+            X_GRAB_KEY => match self.field(req, 4) {
+                Some(w) if !self.owns(w) => access(w),
+                _ => Verdict::Forward,
+            },
+            X_GET_PROPERTY | X_LIST_PROPERTIES => match self.field(req, 4) {
+                Some(w) if !self.is_root(w) && !self.owns(w) => empty(0),
+                Some(w) if op == X_GET_PROPERTY && self.is_root(w) => match self.field(req, 8) {
+                    Some(p) if self.policy.window_lists.contains(&p) => {
+                        Verdict::Track(Pending::FilterWindows)
+                    }
+                    _ => Verdict::Forward,
+                },
+                _ => Verdict::Forward,
+            },
+            X_QUERY_EXTENSION => {
+                let len = req.get(4..6).map(|b| self.order.u16(b) as usize);
+                let name = len.and_then(|len| req.get(8..8 + len));
+                match name {
+                    Some(name) if HIDDEN_EXTENSIONS.iter().any(|h| h.as_bytes() == name) => {
+                        empty(0)
+                    }
+                    _ => Verdict::Forward,
+                }
+            }
+            X_LIST_EXTENSIONS => Verdict::Track(Pending::FilterExtensions),
+            X_QUERY_TREE => Verdict::Track(Pending::FilterChildren),
+            op if Some(op) == self.policy.render_major
+                && req.get(1) == Some(&RENDER_CREATE_PICTURE) =>
+            {
+                match self.field(req, 8) {
+                    Some(drawable) if !self.owns(drawable) => Verdict::Block(Synthetic::Error {
+                        code: BAD_ACCESS,
+                        bad_value: drawable,
+                        major: op,
+                        minor: RENDER_CREATE_PICTURE,
+                    }),
+                    _ => Verdict::Forward,
+                }
+            }
+            op if self.policy.hidden_majors.contains(&op) => Verdict::Block(Synthetic::Error {
+                code: BAD_REQUEST,
+                bad_value: 0,
+                major: op,
+                minor: req.get(1).copied().unwrap_or(0),
+            }),
+            _ => Verdict::Forward,
+        }
+    }
+
+    /// apply the verdict for the request just sequenced; true = forward it
+    fn apply(&mut self, req: &[u8], out: &mut Vec<u8>) -> bool {
+        self.seq = self.seq.wrapping_add(1);
+        match self.decide(req) {
+            Verdict::Forward => true,
+            Verdict::Track(pending) => {
+                self.pending.lock().unwrap().push_back((self.seq, pending));
+                true
+            }
+            Verdict::Block(synthetic) => {
+                if self.logged.insert(req[0]) {
+                    eprintln!("firewall: blocked request opcode {}", req[0]);
+                }
+                let mut pending = self.pending.lock().unwrap();
+                pending.push_back((self.seq, Pending::Replace(synthetic)));
+                let mut substitute = [X_GET_INPUT_FOCUS, 0, 0, 0];
+                self.order.put_u16(&mut substitute[2..], 1);
+                out.extend_from_slice(&substitute);
+                false
+            }
+        }
+    }
+}
+
+impl Filter for RequestFilter {
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.buf.extend_from_slice(data);
+        let mut pos = 0;
+
+        loop {
+            let avail = &self.buf[pos..];
+
+            if let Some((remaining, forward)) = self.streaming {
+                let n = remaining.min(avail.len());
+                if forward {
+                    out.extend_from_slice(&avail[..n]);
+                }
+                pos += n;
+                self.streaming = (remaining > n).then_some((remaining - n, forward));
+                if self.streaming.is_some() {
+                    break;
+                }
+                continue;
+            }
+
+            if avail.len() < 4 {
+                break;
+            }
+            let short = self.order.u16(&avail[2..4]) as usize;
+            let big = short == 0;
+            let len = if !big {
+                short * 4
+            } else if avail.len() < 8 {
+                break;
+            } else {
+                // BIG-REQUESTS: 32-bit length follows the header
+                (self.order.u32(&avail[4..8]) as usize).saturating_mul(4)
+            };
+            let len = len.max(4);
+
+            if len <= MAX_INSPECT {
+                if avail.len() < len {
+                    break;
+                }
+                let req = avail[..len].to_vec();
+                if self.apply(&normalize(&req, big), out) {
+                    out.extend_from_slice(&req);
+                }
+                pos += len;
+            } else {
+                if avail.len() < HEADER_INSPECT {
+                    break;
+                }
+                let header = normalize(&avail[..HEADER_INSPECT], big).into_owned();
+                let forward = self.apply(&header, out);
+                self.streaming = Some((len, forward));
+            }
+        }
+
+        self.buf.drain(..pos);
+    }
+}
+
+/// `req` with a BIG-REQUESTS length word taken out, so every field sits
+/// at the offset it has in the short encoding
+fn normalize(req: &[u8], big: bool) -> Cow<'_, [u8]> {
+    if big && req.len() >= 8 {
+        Cow::Owned([&req[..4], &req[8..]].concat())
+    } else {
+        Cow::Borrowed(req)
+    }
+}
+
+impl ReplyFilter {
+    fn synthesize(&self, seq: u16, synthetic: &Synthetic) -> Vec<u8> {
+        let mut packet = vec![0u8; 32];
+        self.order.put_u16(&mut packet[2..], seq);
+        match *synthetic {
+            Synthetic::Error {
+                code,
+                bad_value,
+                major,
+                minor,
+            } => {
+                packet[0] = X_ERROR;
+                packet[1] = code;
+                self.order.put_u32(&mut packet[4..], bad_value);
+                self.order.put_u16(&mut packet[8..], minor as u16);
+                packet[10] = major;
+            }
+            Synthetic::EmptyReply { data, length } => {
+                packet[0] = X_REPLY;
+                packet[1] = data;
+                self.order.put_u32(&mut packet[4..], length);
+                packet.resize(32 + length as usize * 4, 0);
+            }
+            Synthetic::SelectionRefused {
+                time,
+                requestor,
+                selection,
+                target,
+            } => {
+                packet[0] = X_SELECTION_NOTIFY;
+                self.order.put_u32(&mut packet[4..], time);
+                self.order.put_u32(&mut packet[8..], requestor);
+                self.order.put_u32(&mut packet[12..], selection);
+                self.order.put_u32(&mut packet[16..], target);
+            }
+        }
+        packet
+    }
+
+    /// ListExtensions reply without the hidden names
+    fn filter_extensions(&self, reply: &[u8]) -> Vec<u8> {
+        let mut names = Vec::new();
+        let mut rest = &reply[32..];
+        for _ in 0..reply[1] {
+            let Some(&len) = rest.first() else { break };
+            let Some(name) = rest.get(1..1 + len as usize) else {
+                break;
+            };
+            if !HIDDEN_EXTENSIONS.iter().any(|h| h.as_bytes() == name) {
+                names.push(name);
+            }
+            rest = &rest[1 + len as usize..];
+        }
+
+        let mut body: Vec<u8> = Vec::new();
+        for name in &names {
+            body.push(name.len() as u8);
+            body.extend_from_slice(name);
+        }
+        body.resize(body.len().div_ceil(4) * 4, 0);
+
+        let mut out = reply[..32].to_vec();
+        out[1] = names.len() as u8;
+        self.order.put_u32(&mut out[4..], (body.len() / 4) as u32);
+        out.extend_from_slice(&body);
+        out
+    }
+
+    /// QueryTree reply listing only the session's own windows
+    fn filter_children(&self, reply: &[u8]) -> Vec<u8> {
+        let count = self.order.u16(&reply[16..18]) as usize;
+        let children: Vec<u32> = reply[32..]
+            .chunks_exact(4)
+            .take(count)
+            .map(|b| self.order.u32(b))
+            .filter(|&w| self.session.owns(w))
+            .collect();
+
+        let mut out = reply[..32].to_vec();
+        self.order.put_u32(&mut out[4..], children.len() as u32);
+        self.order.put_u16(&mut out[16..], children.len() as u16);
+        for w in children {
+            let mut b = [0u8; 4];
+            self.order.put_u32(&mut b, w);
+            out.extend_from_slice(&b);
+        }
+        out
+    }
+
+    /// GetProperty reply of a window list holding only the session's own
+    /// windows; what the client didn't read yet is dropped as well
+    fn filter_windows(&self, reply: &[u8]) -> Vec<u8> {
+        if reply[1] != 32 {
+            return reply.to_vec();
+        }
+        let count = self.order.u32(&reply[16..20]) as usize;
+        let windows: Vec<u32> = reply[32..]
+            .chunks_exact(4)
+            .take(count)
+            .map(|b| self.order.u32(b))
+            .filter(|&w| self.session.owns(w))
+            .collect();
+
+        let mut out = reply[..32].to_vec();
+        self.order.put_u32(&mut out[4..], windows.len() as u32);
+        self.order.put_u32(&mut out[12..], 0);
+        self.order.put_u32(&mut out[16..], windows.len() as u32);
+        for w in windows {
+            let mut b = [0u8; 4];
+            self.order.put_u32(&mut b, w);
+            out.extend_from_slice(&b);
+        }
+        out
+    }
+
+    /// Whether a core event is about a window outside the session
+    fn foreign_event(&self, event: &[u8]) -> bool {
+        let offset = match event[0] & 0x7f {
+            // key, button, motion, crossing: the event window
+            2..=8 => 12,
+            // CreateNotify .. CirculateRequest (the subject, not the parent)
+            16..=24 | 26 | 27 => 8,
+            X_KEYMAP_NOTIFY | X_NO_EXPOSURE | X_MAPPING_NOTIFY => return false,
+            // clipboard transfers legitimately involve other clients
+            X_SELECTION_CLEAR..=X_SELECTION_NOTIFY => return false,
+            // focus, expose, visibility, resize, property, colormap, client message
+            9 | 10 | 12 | 13 | 15 | 25 | 28 | 32 | 33 => 4,
+            _ => return false,
+        };
+        let window = self.order.u32(&event[offset..offset + 4]);
+        !self.policy.roots.contains(&window) && !self.session.owns(window)
+    }
+}
+
+impl Drop for ReplyFilter {
+    fn drop(&mut self) {
+        if let Some(base) = self.client {
+            self.session.unregister(base);
+        }
+    }
+}
+
+impl Filter for ReplyFilter {
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.buf.extend_from_slice(data);
+        let mut pos = 0;
+
+        loop {
+            let avail = &self.buf[pos..];
+
+            if self.streaming > 0 {
+                let n = self.streaming.min(avail.len());
+                out.extend_from_slice(&avail[..n]);
+                pos += n;
+                self.streaming -= n;
+                if self.streaming > 0 {
+                    break;
+                }
+                continue;
+            }
+
+            if !self.setup_done {
+                if avail.len() < 8 {
+                    break;
+                }
+                let len = 8 + self.order.u16(&avail[6..8]) as usize * 4;
+                if avail.len() < len {
+                    break;
+                }
+                if avail[0] == 1 && len >= 20 {
+                    let base = self.order.u32(&avail[12..16]);
+                    let mask = self.order.u32(&avail[16..20]);
+                    self.session.register(base, mask);
+                    self.client = Some(base);
+                }
+                out.extend_from_slice(&avail[..len]);
+                pos += len;
+                self.setup_done = true;
+                continue;
+            }
+
+            if avail.len() < 32 {
+                break;
+            }
+            let kind = avail[0] & 0x7f;
+            let extra = if kind == X_REPLY || kind == X_GENERIC_EVENT {
+                (self.order.u32(&avail[4..8]) as usize).saturating_mul(4)
+            } else {
+                0
+            };
+
+            if kind != X_REPLY && kind != X_ERROR && kind != X_GENERIC_EVENT {
+                if !self.foreign_event(&avail[..32]) {
+                    out.extend_from_slice(&avail[..32]);
+                }
+                pos += 32;
+                continue;
+            }
+
+            let pending = if kind == X_REPLY || kind == X_ERROR {
+                let seq = self.order.u16(&avail[2..4]);
+                let pending = self.pending.lock().unwrap();
+                pending
+                    .front()
+                    .filter(|(p, _)| *p == seq)
+                    .map(|(_, pending)| pending.clone())
+            } else {
+                None
+            };
+
+            match pending {
+                Some(pending) => {
+                    if avail.len() < 32 + extra {
+                        break;
+                    }
+                    let seq = self.order.u16(&avail[2..4]);
+                    match &pending {
+                        Pending::Replace(synthetic) => {
+                            out.extend_from_slice(&self.synthesize(seq, synthetic))
+                        }
+                        Pending::FilterExtensions if kind == X_REPLY => {
+                            out.extend_from_slice(&self.filter_extensions(&avail[..32 + extra]))
+                        }
+                        Pending::FilterChildren if kind == X_REPLY => {
+                            out.extend_from_slice(&self.filter_children(&avail[..32 + extra]))
+                        }
+                        Pending::FilterWindows if kind == X_REPLY => {
+                            out.extend_from_slice(&self.filter_windows(&avail[..32 + extra]))
+                        }
+                        Pending::FilterExtensions
+                        | Pending::FilterChildren
+                        | Pending::FilterWindows => out.extend_from_slice(&avail[..32 + extra]),
+                    }
+                    pos += 32 + extra;
+                    self.pending.lock().unwrap().pop_front();
+                }
+                None => {
+                    out.extend_from_slice(&avail[..32]);
+                    pos += 32;
+                    self.streaming = extra;
+                }
+            }
+        }
+
+        self.buf.drain(..pos);
+    }
+}
+
+/// Copy `reader` to `writer` through `filter` until EOF
+pub async fn pump<R, W, F>(mut reader: R, mut writer: W, mut filter: F) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: Filter,
+{
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut out = Vec::with_capacity(64 * 1024);
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        out.clear();
+        filter.push(&buf[..n], &mut out);
+        writer.write_all(&out).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROOT: u32 = 0x1d9;
+    const BASE: u32 = 0x0040_0000;
+    const MASK: u32 = 0x001f_ffff;
+
+    const NET_CLIENT_LIST: u32 = 350;
+    const RENDER: u8 = 139;
+
+    fn policy() -> Arc<Policy> {
+        Arc::new(Policy {
+            roots: vec![ROOT],
+            hidden_majors: vec![132],
+            render_major: Some(RENDER),
+            window_lists: vec![NET_CLIENT_LIST],
+        })
+    }
+
+    fn request(op: u8, data: u8, body: &[u32]) -> Vec<u8> {
+        let mut req = vec![op, data];
+        req.extend_from_slice(&(1 + body.len() as u16).to_le_bytes());
+        for v in body {
+            req.extend_from_slice(&v.to_le_bytes());
+        }
+        req
+    }
+
+    /// `request` in BIG-REQUESTS encoding
+    fn big_request(op: u8, data: u8, body: &[u32]) -> Vec<u8> {
+        let mut req = vec![op, data, 0, 0];
+        req.extend_from_slice(&(2 + body.len() as u32).to_le_bytes());
+        for v in body {
+            req.extend_from_slice(&v.to_le_bytes());
+        }
+        req
+    }
+
+    fn setup_reply() -> Vec<u8> {
+        let mut reply = vec![1, 0, 11, 0, 0, 0, 3, 0];
+        reply.extend_from_slice(&0u32.to_le_bytes());
+        reply.extend_from_slice(&BASE.to_le_bytes());
+        reply.extend_from_slice(&MASK.to_le_bytes());
+        reply
+    }
+
+    fn reply(seq: u16, extra: &[u8]) -> Vec<u8> {
+        let mut r = vec![X_REPLY, 0];
+        r.extend_from_slice(&seq.to_le_bytes());
+        r.extend_from_slice(&((extra.len() / 4) as u32).to_le_bytes());
+        r.resize(32, 0);
+        r.extend_from_slice(extra);
+        r
+    }
+
+    fn connected() -> (RequestFilter, ReplyFilter) {
+        let (req, mut rep) = filters(policy(), Session::default(), b'l');
+        let mut out = Vec::new();
+        rep.push(&setup_reply(), &mut out);
+        assert_eq!(out, setup_reply());
+        (req, rep)
+    }
+
+    #[test]
+    fn test_allowed_requests_pass_unchanged() {
+        let (mut req, _rep) = connected();
+        let own_window = BASE | 5;
+        let input = [
+            request(X_GET_PROPERTY, 0, &[own_window, 39, 31, 0, 1024]),
+            request(X_GET_PROPERTY, 0, &[ROOT, 39, 31, 0, 1024]),
+            request(X_GET_IMAGE, 2, &[own_window, 0, 0, !0]),
+            request(X_COPY_AREA, 0, &[BASE | 6, own_window, 0, 0, 0, 0]),
+            request(X_GRAB_KEY, 1, &[own_window, 0]),
+            big_request(X_GET_IMAGE, 2, &[own_window, 0, 0, !0]),
+            big_request(X_GET_PROPERTY, 0, &[own_window, 39, 31, 0, 1024]),
+        ]
+        .concat();
+
+        let mut out = Vec::new();
+        // byte at a time, to exercise reassembly
+        for b in &input {
+            req.push(&[*b], &mut out);
+        }
+        assert_eq!(out, input);
+    }
+
+    #[test]
+    fn test_root_get_image_becomes_bad_access() {
+        let (mut req, mut rep) = connected();
+        let mut out = Vec::new();
+        req.push(&request(X_GET_IMAGE, 2, &[ROOT, 0, 0, !0]), &mut out);
+        assert_eq!(out, vec![X_GET_INPUT_FOCUS, 0, 1, 0]);
+
+        // server answers the substitute, client sees BadAccess for seq 1
+        let mut out = Vec::new();
+        rep.push(&reply(1, &[]), &mut out);
+        assert_eq!(out.len(), 32);
+        assert_eq!(&out[..4], &[X_ERROR, BAD_ACCESS, 1, 0]);
+        assert_eq!(&out[4..8], &ROOT.to_le_bytes());
+        assert_eq!(out[10], X_GET_IMAGE);
+    }
+
+    #[test]
+    fn test_foreign_window_sources_blocked() {
+        let (mut req, mut rep) = connected();
+        let foreign = 0x0060_0001;
+        let mut out = Vec::new();
+        req.push(&request(X_GET_IMAGE, 2, &[foreign, 0, 0, !0]), &mut out);
+        req.push(
+            &request(X_COPY_AREA, 0, &[foreign, BASE | 1, 0, 0, 0, 0]),
+            &mut out,
+        );
+        req.push(
+            &request(X_COPY_PLANE, 0, &[ROOT, BASE | 1, 0, 0, 0, 0, 1]),
+            &mut out,
+        );
+        assert_eq!(out, [X_GET_INPUT_FOCUS, 0, 1, 0].repeat(3));
+
+        let mut out = Vec::new();
+        rep.push(
+            &[reply(1, &[]), reply(2, &[]), reply(3, &[])].concat(),
+            &mut out,
+        );
+        for (packet, (bad, op)) in out.chunks(32).zip([
+            (foreign, X_GET_IMAGE),
+            (foreign, X_COPY_AREA),
+            (ROOT, X_COPY_PLANE),
+        ]) {
+            assert_eq!(&packet[..2], &[X_ERROR, BAD_ACCESS]);
+            assert_eq!(&packet[4..8], &bad.to_le_bytes());
+            assert_eq!(packet[10], op);
+        }
+    }
+
+    #[test]
+    fn test_render_pictures_of_foreign_drawables_blocked() {
+        let (mut req, mut rep) = connected();
+        let foreign = 0x0060_0001;
+        let own = request(RENDER, RENDER_CREATE_PICTURE, &[BASE | 2, BASE | 1, 36, 0]);
+        let mut out = Vec::new();
+        req.push(&own, &mut out);
+        assert_eq!(out, own);
+
+        let mut out = Vec::new();
+        for drawable in [ROOT, foreign] {
+            req.push(
+                &request(RENDER, RENDER_CREATE_PICTURE, &[BASE | 3, drawable, 36, 0]),
+                &mut out,
+            );
+        }
+        assert_eq!(out, [X_GET_INPUT_FOCUS, 0, 1, 0].repeat(2));
+
+        let mut out = Vec::new();
+        rep.push(&[reply(2, &[]), reply(3, &[])].concat(), &mut out);
+        for (packet, bad) in out.chunks(32).zip([ROOT, foreign]) {
+            assert_eq!(&packet[..2], &[X_ERROR, BAD_ACCESS]);
+            assert_eq!(&packet[4..8], &bad.to_le_bytes());
+            assert_eq!(packet[8], RENDER_CREATE_PICTURE);
+            assert_eq!(packet[10], RENDER);
+        }
+    }
+
+    #[test]
+    fn test_big_requests_judged_on_their_fields() {
+        let foreign = 0x0060_0001;
+        let mut query = vec![X_QUERY_EXTENSION, 0, 0, 0, 5, 0, 0, 0, 5, 0, 0, 0];
+        query.extend_from_slice(b"XTEST\0\0\0");
+        let blocked = [
+            big_request(X_GET_IMAGE, 2, &[ROOT, 0, 0, !0]),
+            big_request(X_COPY_AREA, 0, &[ROOT, BASE | 1, 0, 0, 0, 0]),
+            big_request(X_COPY_PLANE, 0, &[foreign, BASE | 1, 0, 0, 0, 0, 1]),
+            big_request(X_GRAB_KEY, 1, &[foreign, 0]),
+            big_request(X_GET_PROPERTY, 0, &[foreign, 39, 31, 0, 1024]),
+            big_request(X_LIST_PROPERTIES, 0, &[foreign]),
+            query,
+        ];
+        for request in blocked {
+            let (mut req, _rep) = connected();
+            let mut out = Vec::new();
+            req.push(&request, &mut out);
+            assert_eq!(out, [X_GET_INPUT_FOCUS, 0, 1, 0], "opcode {}", request[0]);
+        }
+    }
+
+    #[test]
+    fn test_convert_selection_refused() {
+        let (mut req, mut rep) = connected();
+        let (requestor, clipboard, utf8, property) = (BASE | 2, 300, 301, 302);
+        let mut out = Vec::new();
+        req.push(
+            &request(
+                X_CONVERT_SELECTION,
+                0,
+                &[requestor, clipboard, utf8, property, 1234],
+            ),
+            &mut out,
+        );
+        assert_eq!(out, [X_GET_INPUT_FOCUS, 0, 1, 0]);
+
+        // the client gets the SelectionNotify of a failed conversion
+        let mut out = Vec::new();
+        rep.push(&reply(1, &[]), &mut out);
+        assert_eq!(out.len(), 32);
+        assert_eq!(&out[..4], &[X_SELECTION_NOTIFY, 0, 1, 0]);
+        assert_eq!(&out[4..8], &1234u32.to_le_bytes());
+        assert_eq!(&out[8..12], &requestor.to_le_bytes());
+        assert_eq!(&out[12..16], &clipboard.to_le_bytes());
+        assert_eq!(&out[16..20], &utf8.to_le_bytes());
+        assert_eq!(&out[20..24], &0u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_property_snooping_neutered() {
+        let (mut req, mut rep) = connected();
+        let foreign = 0x0060_0001;
+        let mut out = Vec::new();
+        req.push(&request(X_GET_INPUT_FOCUS, 0, &[]), &mut out);
+        req.push(
+            &request(X_GET_PROPERTY, 0, &[foreign, 39, 31, 0, 1024]),
+            &mut out,
+        );
+        assert_eq!(
+            out,
+            [[X_GET_INPUT_FOCUS, 0, 1, 0], [X_GET_INPUT_FOCUS, 0, 1, 0]].concat()
+        );
+
+        let mut out = Vec::new();
+        rep.push(&[reply(1, &[]), reply(2, &[])].concat(), &mut out);
+        // first reply untouched, second turned into an empty GetProperty reply
+        assert_eq!(&out[..32], &reply(1, &[])[..]);
+        assert_eq!(&out[32..], &reply(2, &[])[..]);
+    }
+
+    #[test]
+    fn test_root_window_lists_filtered() {
+        let (mut req, mut rep) = connected();
+        let mut out = Vec::new();
+        let get = request(X_GET_PROPERTY, 0, &[ROOT, NET_CLIENT_LIST, 33, 0, 1024]);
+        req.push(&get, &mut out);
+        assert_eq!(out, get);
+
+        let windows = [0x0060_0001u32, BASE | 3, 0x0060_0007, BASE | 4];
+        let body: Vec<u8> = windows.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut list = reply(1, &body);
+        list[1] = 32;
+        list[8..12].copy_from_slice(&33u32.to_le_bytes());
+        list[12..16].copy_from_slice(&8u32.to_le_bytes());
+        list[16..20].copy_from_slice(&4u32.to_le_bytes());
+
+        let mut out = Vec::new();
+        rep.push(&list, &mut out);
+        assert_eq!(out.len(), 32 + 8);
+        assert_eq!(&out[4..8], &2u32.to_le_bytes());
+        assert_eq!(&out[8..12], &33u32.to_le_bytes());
+        assert_eq!(&out[12..16], &0u32.to_le_bytes());
+        assert_eq!(&out[16..20], &2u32.to_le_bytes());
+        assert_eq!(&out[32..36], &(BASE | 3).to_le_bytes());
+        assert_eq!(&out[36..40], &(BASE | 4).to_le_bytes());
+
+        // other root properties are left alone
+        let mut out = Vec::new();
+        let get = request(X_GET_PROPERTY, 0, &[ROOT, 39, 31, 0, 1024]);
+        req.push(&get, &mut out);
+        let name = reply(2, b"root");
+        let mut forwarded = Vec::new();
+        rep.push(&name, &mut forwarded);
+        assert_eq!(forwarded, name);
+    }
+
+    #[test]
+    fn test_grab_keyboard_reports_already_grabbed() {
+        let (mut req, mut rep) = connected();
+        let mut out = Vec::new();
+        req.push(
+            &request(X_GRAB_KEYBOARD, 0, &[BASE | 1, 0, 0x0101]),
+            &mut out,
+        );
+        let mut out = Vec::new();
+        rep.push(&reply(1, &[]), &mut out);
+        assert_eq!(out[0], X_REPLY);
+        assert_eq!(out[1], ALREADY_GRABBED);
+    }
+
+    #[test]
+    fn test_query_keymap_reports_no_keys() {
+        let (mut req, mut rep) = connected();
+        let mut out = Vec::new();
+        req.push(&request(X_QUERY_KEYMAP, 0, &[]), &mut out);
+        assert_eq!(out, vec![X_GET_INPUT_FOCUS, 0, 1, 0]);
+
+        let mut out = Vec::new();
+        rep.push(&reply(1, &[]), &mut out);
+        assert_eq!(out.len(), 40);
+        assert_eq!(&out[..8], &[X_REPLY, 0, 1, 0, 2, 0, 0, 0]);
+        assert!(out[8..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn test_hidden_extensions() {
+        let (mut req, mut rep) = connected();
+        let mut out = Vec::new();
+
+        // QueryExtension("XTEST") -> present = 0
+        let mut query = vec![X_QUERY_EXTENSION, 0, 4, 0, 5, 0, 0, 0];
+        query.extend_from_slice(b"XTEST\0\0\0");
+        req.push(&query, &mut out);
+        // direct use of the XTEST opcode -> BadRequest
+        req.push(&request(132, 2, &[]), &mut out);
+        // ListExtensions is forwarded and its reply filtered
+        req.push(&request(X_LIST_EXTENSIONS, 0, &[]), &mut out);
+        assert_eq!(out.len(), 12);
+        assert_eq!(&out[8..], &request(X_LIST_EXTENSIONS, 0, &[])[..]);
+
+        let mut names = Vec::new();
+        for n in ["RANDR", "XTEST", "RECORD", "SHAPE"] {
+            names.push(n.len() as u8);
+            names.extend_from_slice(n.as_bytes());
+        }
+        names.resize(names.len().div_ceil(4) * 4, 0);
+        let mut list = reply(3, &names);
+        list[1] = 4;
+
+        let mut out = Vec::new();
+        rep.push(&[reply(1, &[]), reply(2, &[]), list].concat(), &mut out);
+        assert_eq!(&out[..2], &[X_REPLY, 0]);
+        assert_eq!(out[8], 0); // present = false
+        assert_eq!(&out[32..34], &[X_ERROR, BAD_REQUEST]);
+
+        let filtered = &out[64..];
+        assert_eq!(filtered[1], 2);
+        assert_eq!(&filtered[32..], b"\x05RANDR\x05SHAPE");
+        assert_eq!(filtered.len(), 32 + 12);
+    }
+
+    #[test]
+    fn test_composite_and_xinput_hidden() {
+        let (mut req, mut rep) = connected();
+        let mut out = Vec::new();
+        for name in [&b"Composite"[..], b"XInputExtension"] {
+            let mut query = vec![X_QUERY_EXTENSION, 0, 0, 0, name.len() as u8, 0, 0, 0];
+            query.extend_from_slice(name);
+            query.resize(query.len().div_ceil(4) * 4, 0);
+            query[2] = (query.len() / 4) as u8;
+            req.push(&query, &mut out);
+        }
+        assert_eq!(out, [X_GET_INPUT_FOCUS, 0, 1, 0].repeat(2));
+
+        let mut out = Vec::new();
+        rep.push(&[reply(1, &[]), reply(2, &[])].concat(), &mut out);
+        for packet in out.chunks(32) {
+            assert_eq!(packet[0], X_REPLY);
+            assert_eq!(packet[8], 0); // present = false
+        }
+    }
+
+    #[test]
+    fn test_query_tree_lists_only_session_windows() {
+        let session = Session::default();
+        let (mut req, mut rep) = filters(policy(), session.clone(), b'l');
+        let mut out = Vec::new();
+        rep.push(&setup_reply(), &mut out);
+        // a second stream of the same session
+        session.register(0x0080_0000, MASK);
+
+        let mut out = Vec::new();
+        req.push(&request(X_QUERY_TREE, 0, &[ROOT]), &mut out);
+        assert_eq!(out, request(X_QUERY_TREE, 0, &[ROOT]));
+
+        let children = [0x0060_0001u32, BASE | 3, 0x0080_0002, 0x0060_0007];
+        let body: Vec<u8> = children.iter().flat_map(|w| w.to_le_bytes()).collect();
+        let mut tree = reply(1, &body);
+        tree[8..12].copy_from_slice(&ROOT.to_le_bytes());
+        tree[16..18].copy_from_slice(&4u16.to_le_bytes());
+
+        let mut out = Vec::new();
+        rep.push(&tree, &mut out);
+        assert_eq!(&out[4..8], &2u32.to_le_bytes());
+        assert_eq!(&out[16..18], &2u16.to_le_bytes());
+        assert_eq!(&out[32..36], &(BASE | 3).to_le_bytes());
+        assert_eq!(&out[36..], &0x0080_0002u32.to_le_bytes());
+    }
+
+    #[test]
+    fn test_foreign_events_dropped() {
+        let (_, mut rep) = connected();
+        let event = |kind: u8, offset: usize, window: u32| {
+            let mut e = vec![0u8; 32];
+            e[0] = kind;
+            e[offset..offset + 4].copy_from_slice(&window.to_le_bytes());
+            e
+        };
+        let foreign = 0x0060_0001;
+        let own = BASE | 9;
+
+        let mut out = Vec::new();
+        // MapNotify via SubstructureNotify on root: the subject decides
+        rep.push(&event(19, 8, foreign), &mut out);
+        rep.push(&event(28, 4, foreign), &mut out);
+        assert!(out.is_empty());
+
+        rep.push(&event(19, 8, own), &mut out);
+        rep.push(&event(28, 4, ROOT), &mut out);
+        rep.push(&event(34, 4, foreign), &mut out);
+        assert_eq!(out.len(), 3 * 32);
+    }
+
+    #[test]
+    fn test_session_forgets_closed_streams() {
+        let session = Session::default();
+        let (_, mut rep) = filters(policy(), session.clone(), b'l');
+        let mut out = Vec::new();
+        rep.push(&setup_reply(), &mut out);
+        assert!(session.owns(BASE | 1));
+        drop(rep);
+        assert!(!session.owns(BASE | 1));
+    }
+
+    #[test]
+    fn test_large_reply_streams_through() {
+        let (_, mut rep) = connected();
+        let body = vec![7u8; 200_000];
+        let input = reply(9, &body);
+        let mut out = Vec::new();
+        for chunk in input.chunks(1500) {
+            rep.push(chunk, &mut out);
+        }
+        assert_eq!(out, input);
+    }
+}
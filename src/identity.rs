@@ -0,0 +1,194 @@
+//! Persistent node identity
+//!
+//! The iroh secret key is loaded from disk (and generated on first use),
+//! so the node id printed by `x11q id` is the one `server`, `serve` and
+//! `mirror-server` actually listen on, and it survives restarts.
+
+use anyhow::{Context, Result};
+use clap::Args;
+use iroh::SecretKey;
+use std::path::{Path, PathBuf};
+
+const IDENTITY_FILE: &str = "identity.key";
+
+/// Identity selection shared by every command that binds an endpoint
+#[derive(Args, Clone, Debug, Default)]
+pub struct IdentityArgs {
+    /// Secret key file (default: ~/.config/x11q/identity.key)
+    #[arg(long, value_name = "PATH")]
+    pub identity: Option<PathBuf>,
+
+    /// Use a throwaway identity instead of the persistent one
+    #[arg(long, conflicts_with = "identity")]
+    pub ephemeral: bool,
+}
+
+impl IdentityArgs {
+    /// Resolve to the secret key the endpoint should bind with
+    pub fn secret_key(&self) -> Result<SecretKey> {
+        if self.ephemeral {
+            return Ok(SecretKey::generate(rand::rngs::OsRng));
+        }
+        let path = match &self.identity {
+            Some(p) => p.clone(),
+            None => default_path()?,
+        };
+        load_or_generate(&path)
+    }
+}
+
+/// x11q config directory ($XDG_CONFIG_HOME/x11q or ~/.config/x11q)
+pub fn config_dir() -> Result<PathBuf> {
+    if let Some(dir) = std::env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(dir).join("x11q"));
+    }
+    let home = std::env::var_os("HOME")
+        .or_else(|| std::env::var_os("USERPROFILE"))
+        .context("cannot locate home directory (set HOME or use --identity)")?;
+    Ok(PathBuf::from(home).join(".config").join("x11q"))
+}
+
+fn default_path() -> Result<PathBuf> {
+    Ok(config_dir()?.join(IDENTITY_FILE))
+}
+
+/// Load the secret key at `path`, creating a new one if it doesn't exist
+pub fn load_or_generate(path: &Path) -> Result<SecretKey> {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => {
+            check_permissions(path)?;
+            parse_key(&contents).with_context(|| format!("invalid key file {}", path.display()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let key = SecretKey::generate(rand::rngs::OsRng);
+            write_key(path, &key)?;
+            eprintln!("generated new identity: {}", path.display());
+            Ok(key)
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+fn parse_key(contents: &str) -> Result<SecretKey> {
+    let bytes = hex::decode(contents.trim()).context("expected hex")?;
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("expected 32 byte key"))?;
+    Ok(SecretKey::from_bytes(&bytes))
+}
+
+fn write_key(path: &Path, key: &SecretKey) -> Result<()> {
+    use std::io::Write;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+
+    let mut file = opts
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    writeln!(file, "{}", hex::encode(key.to_bytes()))?;
+    Ok(())
+}
+
+/// refuse keys other users can read, like ssh does
+#[cfg(unix)]
+fn check_permissions(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        anyhow::bail!(
+            "permissions {:o} for {} are too open (run: chmod 600 {})",
+            mode & 0o777,
+            path.display(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn check_permissions(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("x11q-identity-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_generate_then_reload() {
+        let dir = scratch("reload");
+        let path = dir.join("nested").join(IDENTITY_FILE);
+
+        let generated = load_or_generate(&path).unwrap();
+        let reloaded = load_or_generate(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(generated.public(), reloaded.public());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_open_key_file_refused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = scratch("mode");
+        let key = load_or_generate(&path).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        for open in [0o640, 0o604] {
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(open)).unwrap();
+            let err = load_or_generate(&path).unwrap_err();
+            assert!(err.to_string().contains("too open"));
+        }
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o400)).unwrap();
+        let reloaded = load_or_generate(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reloaded.unwrap().public(), key.public());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let key = SecretKey::generate(rand::rngs::OsRng);
+        let hex = hex::encode(key.to_bytes());
+        assert_eq!(
+            parse_key(&format!("{}\n", hex)).unwrap().public(),
+            key.public()
+        );
+
+        assert!(parse_key("").is_err());
+        assert!(parse_key("not hex at all").is_err());
+        assert!(parse_key(&hex[..62]).is_err());
+        assert!(parse_key(&format!("{}00", hex)).is_err());
+    }
+
+    #[test]
+    fn test_malformed_key_file() {
+        let path = scratch("malformed");
+        std::fs::write(&path, "garbage\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        let err = load_or_generate(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().contains("invalid key file"));
+    }
+}
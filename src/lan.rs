@@ -0,0 +1,370 @@
+//! lan - find peers on the local network without dht or relays
+//!
+//! the looking side broadcasts a query on every ipv4 subnet it is on, and
+//! instances started with `--lan` answer with their node id and udp ports;
+//! the dialing side takes the ip from where the answer came from. codes
+//! are looked up by the key their dht record is signed with, never by the
+//! code itself. answers are not trusted: the tls handshake checks the node id
+//! and the pake the code, so a forged answer only makes dialing fail.
+
+use crate::rendezvous::{CodeKeys, Publication};
+use anyhow::{Context, Result};
+use iroh::{Endpoint, NodeAddr, NodeId};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::Instant;
+
+/// udp port instances with `--lan` answer queries on
+pub const PORT: u16 = 11411;
+const MAGIC: &[u8; 8] = b"x11q-lan";
+const VERSION: u8 = 1;
+const CODE: u8 = 1;
+const NODE: u8 = 2;
+/// set on the kind of an answer
+const ANSWER: u8 = 0x80;
+const HEADER: usize = MAGIC.len() + 2 + 32;
+const MAX_DATAGRAM: usize = 512;
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+/// queries are udp, so they are repeated until someone answers
+const RETRY_INTERVAL: Duration = Duration::from_millis(500);
+/// after the first answer, how long to wait for the same node on other subnets
+const GATHER: Duration = Duration::from_millis(200);
+
+/// What a query looks for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Query {
+    /// a served word code, by its lookup key
+    Code([u8; 32]),
+    Node(NodeId),
+}
+
+impl Query {
+    pub fn code(keys: &CodeKeys) -> Self {
+        Self::Code(keys.lookup_key())
+    }
+
+    fn header(&self, answer: bool) -> Vec<u8> {
+        let (kind, key) = match self {
+            Self::Code(key) => (CODE, key),
+            Self::Node(id) => (NODE, id.as_bytes()),
+        };
+        let kind = if answer { kind | ANSWER } else { kind };
+        [MAGIC.as_slice(), &[VERSION, kind], key].concat()
+    }
+
+    /// the query in `buf` and whether it is an answer
+    fn parse(buf: &[u8]) -> Option<(Self, bool)> {
+        let rest = buf.strip_prefix(MAGIC)?;
+        let (&[version, kind], rest) = rest.split_first_chunk::<2>()?;
+        let key: [u8; 32] = *rest.first_chunk()?;
+        if version != VERSION {
+            return None;
+        }
+        let query = match kind & !ANSWER {
+            CODE => Self::Code(key),
+            NODE => Self::Node(NodeId::from_bytes(&key).ok()?),
+            _ => return None,
+        };
+        Some((query, kind & ANSWER != 0))
+    }
+}
+
+/// An answer: who serves the query, and on which udp sockets
+#[derive(Clone, Debug, PartialEq)]
+struct Answer {
+    query: Query,
+    node_id: NodeId,
+    /// unspecified ips stand for the address the answer came from
+    addrs: Vec<SocketAddr>,
+}
+
+impl Answer {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = self.query.header(true);
+        buf.extend_from_slice(self.node_id.as_bytes());
+        for addr in &self.addrs {
+            match addr.ip() {
+                IpAddr::V4(ip) => {
+                    buf.push(4);
+                    buf.extend_from_slice(&ip.octets());
+                }
+                IpAddr::V6(ip) => {
+                    buf.push(6);
+                    buf.extend_from_slice(&ip.octets());
+                }
+            }
+            buf.extend_from_slice(&addr.port().to_be_bytes());
+        }
+        buf
+    }
+
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let (query, true) = Query::parse(buf)? else {
+            return None;
+        };
+        let (node_id, mut rest) = buf[HEADER..].split_first_chunk::<32>()?;
+        let node_id = NodeId::from_bytes(node_id).ok()?;
+        let mut addrs = Vec::new();
+        while let Some((&family, tail)) = rest.split_first() {
+            let (ip, tail) = match family {
+                4 => {
+                    let (ip, tail) = tail.split_first_chunk::<4>()?;
+                    (IpAddr::V4(Ipv4Addr::from(*ip)), tail)
+                }
+                6 => {
+                    let (ip, tail) = tail.split_first_chunk::<16>()?;
+                    (IpAddr::V6(Ipv6Addr::from(*ip)), tail)
+                }
+                _ => return None,
+            };
+            let (port, tail) = tail.split_first_chunk::<2>()?;
+            addrs.push(SocketAddr::new(ip, u16::from_be_bytes(*port)));
+            rest = tail;
+        }
+        Some(Self {
+            query,
+            node_id,
+            addrs,
+        })
+    }
+
+    /// where to dial, for an answer that came from `from`
+    fn direct_addrs(&self, from: IpAddr) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.addrs.iter().filter_map(move |addr| {
+            if !addr.ip().is_unspecified() {
+                Some(*addr)
+            } else if addr.is_ipv4() == from.is_ipv4() {
+                Some(SocketAddr::new(from, addr.port()))
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Answer `queries` with the address of `endpoint` until the guard is dropped
+pub fn answer(endpoint: &Endpoint, queries: Vec<Query>) -> Result<Publication> {
+    let socket = listen().with_context(|| {
+        format!("could not listen for lan queries on udp port {PORT}, is it in use?")
+    })?;
+    let endpoint = endpoint.clone();
+    let task = tokio::spawn(async move {
+        let mut buf = [0u8; MAX_DATAGRAM];
+        loop {
+            let Ok((n, from)) = socket.recv_from(&mut buf).await else {
+                continue;
+            };
+            let Some((query, false)) = Query::parse(&buf[..n]) else {
+                continue;
+            };
+            if !queries.contains(&query) {
+                continue;
+            }
+            let (v4, v6) = endpoint.bound_sockets();
+            let answer = Answer {
+                query,
+                node_id: endpoint.node_id(),
+                addrs: std::iter::once(v4).chain(v6).collect(),
+            };
+            let _ = socket.send_to(&answer.encode(), from).await;
+        }
+    });
+    Ok(Publication::new(task))
+}
+
+/// The shared query port, which several instances on one host may bind
+fn listen() -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    let addr = SocketAddr::from((Ipv4Addr::UNSPECIFIED, PORT));
+    socket.bind(&addr.into())?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Find whoever answers `query` on the local network
+pub async fn resolve(query: Query) -> Result<NodeAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let targets = broadcast_addrs();
+    let request = query.header(false);
+
+    let deadline = Instant::now() + RESOLVE_TIMEOUT;
+    let mut found: Option<NodeAddr> = None;
+    let mut gathered = deadline;
+    let mut retry = Instant::now();
+    let mut buf = [0u8; MAX_DATAGRAM];
+    loop {
+        let received = tokio::select! {
+            received = socket.recv_from(&mut buf) => received,
+            _ = tokio::time::sleep_until(retry), if found.is_none() => {
+                for ip in &targets {
+                    // unreachable subnets are expected on some interfaces
+                    let _ = socket.send_to(&request, (*ip, PORT)).await;
+                }
+                retry += RETRY_INTERVAL;
+                continue;
+            }
+            _ = tokio::time::sleep_until(gathered.min(deadline)) => break,
+        };
+        let Ok((n, from)) = received else { continue };
+        let Some(answer) = Answer::decode(&buf[..n]).filter(|a| a.query == query) else {
+            continue;
+        };
+        let addr = found.get_or_insert_with(|| {
+            gathered = Instant::now() + GATHER;
+            NodeAddr::new(answer.node_id)
+        });
+        // a different node answering the same code is ignored, the pake
+        // with the first one decides
+        if addr.node_id == answer.node_id {
+            addr.direct_addresses.extend(answer.direct_addrs(from.ip()));
+        }
+    }
+    found.with_context(|| match query {
+        Query::Code(_) => "code not found on the local network, is serve --lan running?",
+        Query::Node(_) => "node not found on the local network, is it running with --lan?",
+    })
+}
+
+/// The broadcast address of every ipv4 subnet we are on, loopback included
+#[cfg(unix)]
+fn broadcast_addrs() -> Vec<Ipv4Addr> {
+    let mut addrs = Vec::new();
+    let mut ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return vec![Ipv4Addr::BROADCAST];
+    }
+    let mut cursor = ifaddrs;
+    while let Some(ifa) = unsafe { cursor.as_ref() } {
+        cursor = ifa.ifa_next;
+        let up = ifa.ifa_flags & libc::IFF_UP as libc::c_uint != 0;
+        let (Some(ip), Some(mask)) = (unsafe { ipv4(ifa.ifa_addr) }, unsafe {
+            ipv4(ifa.ifa_netmask)
+        }) else {
+            continue;
+        };
+        if !up || mask == Ipv4Addr::BROADCAST {
+            continue;
+        }
+        let broadcast = Ipv4Addr::from(u32::from(ip) | !u32::from(mask));
+        if !addrs.contains(&broadcast) {
+            addrs.push(broadcast);
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+    addrs
+}
+
+#[cfg(not(unix))]
+fn broadcast_addrs() -> Vec<Ipv4Addr> {
+    vec![Ipv4Addr::BROADCAST]
+}
+
+/// The ipv4 address in `addr`, if it is one
+#[cfg(unix)]
+unsafe fn ipv4(addr: *const libc::sockaddr) -> Option<Ipv4Addr> {
+    if addr.is_null() || (*addr).sa_family as libc::c_int != libc::AF_INET {
+        return None;
+    }
+    let addr = &*(addr as *const libc::sockaddr_in);
+    Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh::SecretKey;
+
+    fn node_id() -> NodeId {
+        SecretKey::generate(rand::rngs::OsRng).public()
+    }
+
+    fn code(code: &str) -> Query {
+        Query::code(&CodeKeys::derive(code))
+    }
+
+    #[test]
+    fn test_query_roundtrip() {
+        for query in [code("7-tiger-lamp"), Query::Node(node_id())] {
+            assert_eq!(Query::parse(&query.header(false)), Some((query, false)));
+            assert_eq!(Query::parse(&query.header(true)), Some((query, true)));
+        }
+        assert_eq!(code("7-tiger-lamp"), code("7-tiger-lamp"));
+        assert_ne!(code("7-tiger-lamp"), code("8-tiger-lamp"));
+
+        let mut other_version = code("7-tiger-lamp").header(false);
+        other_version[MAGIC.len()] = VERSION + 1;
+        assert_eq!(Query::parse(&other_version), None);
+        assert_eq!(Query::parse(b"x11q-lan"), None);
+        assert_eq!(
+            Query::parse(b"not x11q at all, but long enough to parse"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_answer_roundtrip() {
+        let answer = Answer {
+            query: code("7-tiger-lamp"),
+            node_id: node_id(),
+            addrs: vec![
+                "0.0.0.0:4433".parse().unwrap(),
+                "[fd00::2]:4434".parse().unwrap(),
+            ],
+        };
+        let encoded = answer.encode();
+        assert_eq!(Answer::decode(&encoded), Some(answer.clone()));
+        // a query is not an answer, and a truncated address is rejected
+        assert_eq!(Answer::decode(&answer.query.header(false)), None);
+        assert_eq!(Answer::decode(&encoded[..encoded.len() - 1]), None);
+    }
+
+    #[test]
+    fn test_direct_addrs() {
+        let answer = Answer {
+            query: Query::Node(node_id()),
+            node_id: node_id(),
+            addrs: vec![
+                "0.0.0.0:4433".parse().unwrap(),
+                "[::]:4433".parse().unwrap(),
+                "[fd00::2]:4434".parse().unwrap(),
+            ],
+        };
+        let from = "192.168.1.5".parse().unwrap();
+        let addrs: Vec<_> = answer.direct_addrs(from).collect();
+        assert_eq!(
+            addrs,
+            [
+                "192.168.1.5:4433".parse().unwrap(),
+                "[fd00::2]:4434".parse().unwrap()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_answer_and_resolve() {
+        let endpoint = Endpoint::builder()
+            .relay_mode(iroh::RelayMode::Disabled)
+            .bind_addr_v4("127.0.0.1:0".parse().unwrap())
+            .bind()
+            .await
+            .unwrap();
+        let query = Query::Node(endpoint.node_id());
+        let _answering = answer(&endpoint, vec![query]).unwrap();
+
+        let addr = resolve(query).await.unwrap();
+        assert_eq!(addr.node_id, endpoint.node_id());
+        assert!(addr.direct_addresses.contains(&endpoint.bound_sockets().0));
+        endpoint.close().await;
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_broadcast_addrs() {
+        // loopback is always there, so instances on one host find each other
+        assert!(broadcast_addrs().contains(&Ipv4Addr::new(127, 255, 255, 255)));
+    }
+}
@@ -11,6 +11,13 @@
 //! remote: x11q join 7-tiger-lamp  → DISPLAY=:99 ready
 //! ```
 //!
+//! Run a program on the remote display from the local side (like ssh -X):
+//!
+//! ```text
+//! local:  x11q serve -- exec firefox
+//! remote: x11q join --allow-exec firefox 7-tiger-lamp
+//! ```
+//!
 //! # Direct mode (node ids)
 //!
 //! ```text
@@ -18,28 +25,52 @@
 //! remote: x11q client <nodeid> → DISPLAY=:99 ready
 //! ```
 
+mod authorized;
+mod compress;
+mod control;
 mod display;
+mod exec;
+mod firewall;
+mod identity;
+mod lan;
+mod metrics;
 mod mirror;
+mod net;
+mod pair;
 mod rendezvous;
+mod rendezvous_server;
+mod resume;
+mod scrypt;
+mod status;
 #[cfg(unix)]
 mod test_server;
 #[cfg(unix)]
 mod web;
+mod xauth;
+mod xcache;
+mod xlock;
 
 use anyhow::{Context, Result};
+use authorized::{AccessArgs, AuthorizedNodes, Permission};
 use clap::{Parser, Subcommand};
-use iroh::{Endpoint, NodeId};
-use std::str::FromStr;
+use compress::Compress;
+use control::Control;
+use firewall::Trust;
+use identity::IdentityArgs;
+use iroh::{Endpoint, NodeId, SecretKey};
+use net::NetArgs;
+use rendezvous::{Backend, CodeArgs, JoinLimit};
+use status::Status;
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::io;
+use tokio::io::{self, AsyncRead, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-#[cfg(unix)]
-use tokio::net::{UnixListener, UnixStream};
+use xlock::{DisplayLock, DisplayNumber};
 
-#[cfg(unix)]
-const X11_UNIX_DIR: &str = "/tmp/.X11-unix";
-const X11_TCP_BASE: u16 = 6000;
-const ALPN: &[u8] = b"x11quic/1";
+const ALPN: &[u8] = b"x11quic/2";
+/// A joining peer gets this long to finish the pake, or it counts as a failure
+const PAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(15);
 
 #[derive(Parser)]
 #[command(name = "x11q")]
@@ -58,6 +89,40 @@ enum Commands {
         /// Local X display to forward (e.g., :0)
         #[arg(short, long, default_value = ":0")]
         display: String,
+
+        /// How far to trust forwarded clients with the local display
+        #[arg(long, value_enum, default_value_t = Trust::Full)]
+        trust: Trust,
+
+        /// Compress X11 streams: auto (not on direct LAN paths), off, or zstd level 1-19
+        #[arg(long, default_value = "auto", value_name = "auto|off|N")]
+        compress: Compress,
+
+        /// Serve OpenMetrics on this address (e.g., 127.0.0.1:9464)
+        #[arg(long, value_name = "ADDR")]
+        metrics: Option<SocketAddr>,
+
+        /// Where to publish and look up the code: dht, pkarr:URL or server:URL
+        #[arg(
+            long,
+            default_value = "dht",
+            value_name = "BACKEND",
+            conflicts_with = "lan"
+        )]
+        rendezvous: Backend,
+
+        #[command(flatten)]
+        limits: CodeArgs,
+
+        #[command(flatten)]
+        identity: IdentityArgs,
+
+        #[command(flatten)]
+        net: NetArgs,
+
+        /// Run a program on the joining side: -- exec CMD [ARGS]
+        #[arg(last = true, value_name = "exec CMD")]
+        command: Vec<String>,
     },
 
     /// Easy mode: join using a word code
@@ -66,9 +131,65 @@ enum Commands {
         /// Word code from server (e.g., "7-tiger-lamp")
         code: String,
 
-        /// Virtual display number to create
+        /// Virtual display number to create, or "auto" for the first free one
         #[arg(short, long, default_value = "99")]
-        display: u32,
+        display: DisplayNumber,
+
+        /// Let the serving side run this exact command line on the display;
+        /// end it in '*' to allow any arguments (repeatable)
+        #[arg(long, value_name = "CMD")]
+        allow_exec: Vec<exec::ExecRule>,
+
+        /// Compress X11 streams: auto (not on direct LAN paths), off, or zstd level 1-19
+        #[arg(long, default_value = "auto", value_name = "auto|off|N")]
+        compress: Compress,
+
+        /// Where to publish and look up the code: dht, pkarr:URL or server:URL
+        #[arg(
+            long,
+            default_value = "dht",
+            value_name = "BACKEND",
+            conflicts_with = "lan"
+        )]
+        rendezvous: Backend,
+
+        #[command(flatten)]
+        net: NetArgs,
+
+        /// Run this on the display, ending the session when it exits
+        #[arg(last = true, value_name = "CMD")]
+        command: Vec<String>,
+    },
+
+    /// Pair with another machine using a word code
+    /// Without CODE prints a new code; both sides then trust each other by name
+    Pair {
+        /// Word code from the other side (omit to generate one)
+        code: Option<String>,
+
+        /// Name the other side will know us by (default: hostname)
+        #[arg(long)]
+        name: Option<String>,
+
+        /// Where to save the peer (default: ~/.config/x11q/authorized_nodes)
+        #[arg(long, value_name = "PATH")]
+        authorized_nodes: Option<PathBuf>,
+
+        /// What the peer may do here: forward, mirror-view, mirror-control
+        #[arg(
+            long,
+            value_delimiter = ',',
+            default_value = "forward",
+            value_name = "PERMS"
+        )]
+        grant: Vec<Permission>,
+
+        /// Where to publish and look up the code: dht, pkarr:URL or server:URL
+        #[arg(long, default_value = "dht", value_name = "BACKEND")]
+        rendezvous: Backend,
+
+        #[command(flatten)]
+        identity: IdentityArgs,
     },
 
     /// Direct mode: server (use node id instead of word code)
@@ -77,28 +198,82 @@ enum Commands {
         #[arg(short, long, default_value = ":0")]
         display: String,
 
-        /// Optional bind address (e.g., 0.0.0.0:5000)
-        #[arg(short, long)]
-        bind: Option<String>,
+        /// How far to trust forwarded clients with the local display
+        #[arg(long, value_enum, default_value_t = Trust::Full)]
+        trust: Trust,
+
+        /// Compress X11 streams: auto (not on direct LAN paths), off, or zstd level 1-19
+        #[arg(long, default_value = "auto", value_name = "auto|off|N")]
+        compress: Compress,
+
+        /// Serve OpenMetrics on this address (e.g., 127.0.0.1:9464)
+        #[arg(long, value_name = "ADDR")]
+        metrics: Option<SocketAddr>,
+
+        #[command(flatten)]
+        identity: IdentityArgs,
+
+        #[command(flatten)]
+        net: NetArgs,
+
+        #[command(flatten)]
+        access: AccessArgs,
     },
 
     /// Direct mode: client (use node id instead of word code)
     Client {
-        /// Server NodeId (base32 public key)
+        /// Server NodeId (base32 public key) or name of a paired peer
         #[arg(value_name = "NODE_ID")]
         node_id: String,
 
-        /// Virtual display number to create
+        /// Virtual display number to create, or "auto" for the first free one
         #[arg(short, long, default_value = "99")]
-        display: u32,
+        display: DisplayNumber,
 
         /// Direct address hint (optional)
         #[arg(long)]
         addr: Option<String>,
+
+        /// Compress X11 streams: auto (not on direct LAN paths), off, or zstd level 1-19
+        #[arg(long, default_value = "auto", value_name = "auto|off|N")]
+        compress: Compress,
+
+        #[command(flatten)]
+        identity: IdentityArgs,
+
+        #[command(flatten)]
+        net: NetArgs,
+
+        /// Run this on the display, ending the session when it exits
+        #[arg(last = true, value_name = "CMD")]
+        command: Vec<String>,
     },
 
     /// Show node identity
-    Id,
+    Id {
+        #[command(flatten)]
+        identity: IdentityArgs,
+    },
+
+    /// Show running instances: peers, connection paths, RTT and traffic
+    #[cfg(unix)]
+    Status {
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Disconnect a peer from the running instances it is on
+    #[cfg(unix)]
+    Kick {
+        /// Node id, a prefix of one as shown by status, or a paired peer's name
+        #[arg(value_name = "NODE")]
+        node: String,
+
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
 
     /// Share your screen (mirror server)
     /// Captures display and streams to connected viewers
@@ -108,30 +283,54 @@ enum Commands {
         #[arg(short, long, default_value = ":0")]
         display: String,
 
-        /// Optional bind address
-        #[arg(short, long)]
-        bind: Option<String>,
+        /// Serve OpenMetrics on this address (e.g., 127.0.0.1:9464)
+        #[arg(long, value_name = "ADDR")]
+        metrics: Option<SocketAddr>,
+
+        #[command(flatten)]
+        identity: IdentityArgs,
+
+        #[command(flatten)]
+        net: NetArgs,
+
+        #[command(flatten)]
+        access: AccessArgs,
     },
 
     /// View a remote screen (mirror client)
     /// Connects to mirror-server and displays in a window
     Mirror {
-        /// Server NodeId (base32 public key)
+        /// Server NodeId (base32 public key) or name of a paired peer
         #[arg(value_name = "NODE_ID")]
         node_id: String,
 
         /// Direct address hint (optional)
         #[arg(long)]
         addr: Option<String>,
+
+        #[command(flatten)]
+        identity: IdentityArgs,
+
+        #[command(flatten)]
+        net: NetArgs,
+    },
+
+    /// Self-hosted rendezvous for word codes, where the DHT is blocked
+    /// Use with serve/join/pair --rendezvous server:URL
+    #[command(name = "rendezvous-server")]
+    RendezvousServer {
+        /// Address to listen on for HTTP
+        #[arg(long, default_value = "0.0.0.0:8787", value_name = "ADDR")]
+        listen: SocketAddr,
     },
 
     /// Run X11 server in browser via WebSocket
     /// Starts web server, X11 clients connect locally, rendered in browser
     #[cfg(unix)]
     Web {
-        /// Virtual display number to create
+        /// Virtual display number to create, or "auto" for the first free one
         #[arg(short, long, default_value = "99")]
-        display: u32,
+        display: DisplayNumber,
 
         /// HTTP port for web server
         #[arg(short, long, default_value = "8080")]
@@ -147,17 +346,17 @@ enum Commands {
     #[cfg(unix)]
     #[command(name = "test-server")]
     TestServer {
-        /// Virtual display number to create
+        /// Virtual display number to create, or "auto" for the first free one
         #[arg(short, long, default_value = "99")]
-        display: u32,
+        display: DisplayNumber,
     },
 
     /// Native X11 display server with rendering
     /// Runs on Windows/Linux/macOS, connects from WSL or remote Linux
     Display {
-        /// Virtual display number to create
+        /// Virtual display number to create, or "auto" for the first free one
         #[arg(short, long, default_value = "99")]
-        display: u32,
+        display: DisplayNumber,
 
         /// Screen width
         #[arg(long, default_value = "1280")]
@@ -180,30 +379,127 @@ async fn main() -> Result<()> {
         .init();
 
     let cli = Cli::parse();
+    xlock::cleanup_on_signal();
 
     match cli.command {
-        Commands::Serve { display } => run_serve(&display).await,
-        Commands::Join { code, display } => run_join(&code, display).await,
-        Commands::Server { display, bind } => run_server(&display, bind.as_deref()).await,
+        Commands::Serve {
+            display,
+            trust,
+            compress,
+            metrics,
+            rendezvous,
+            limits,
+            identity,
+            net,
+            command,
+        } => {
+            let command = exec::parse_command(&command)?;
+            let target = X11Target::new(parse_display(&display)?)
+                .with_trust(trust)?
+                .with_compress(compress);
+            let secret_key = identity.secret_key()?;
+            let backend = &rendezvous;
+            let served = run_serve(target, metrics, secret_key, &net, backend, limits, command);
+            exit_with(served.await?)
+        }
+        Commands::Join {
+            code,
+            display,
+            allow_exec,
+            compress,
+            rendezvous,
+            net,
+            command,
+        } => {
+            let allowlist = exec::Allowlist::new(allow_exec);
+            let command = (!command.is_empty()).then_some(command);
+            let backend = &rendezvous;
+            let joined = run_join(&code, display, allowlist, compress, &net, backend, command);
+            exit_with(joined.await?)
+        }
+        Commands::Pair {
+            code,
+            name,
+            authorized_nodes,
+            grant,
+            rendezvous,
+            identity,
+        } => {
+            pair::run_pair(
+                code.as_deref(),
+                name.as_deref(),
+                authorized_nodes,
+                &grant,
+                &rendezvous,
+                identity.secret_key()?,
+            )
+            .await
+        }
+        Commands::Server {
+            display,
+            trust,
+            compress,
+            metrics,
+            identity,
+            net,
+            access,
+        } => {
+            let target = X11Target::new(parse_display(&display)?)
+                .with_trust(trust)?
+                .with_compress(compress);
+            let secret_key = identity.secret_key()?;
+            run_server(target, metrics, secret_key, &net, access.load()?).await
+        }
         Commands::Client {
             node_id,
             display,
             addr,
-        } => run_client(&node_id, display, addr.as_deref()).await,
-        Commands::Id => {
-            let endpoint = Endpoint::builder()
-                .alpns(vec![ALPN.to_vec()])
-                .bind()
-                .await?;
-            println!("{}", endpoint.node_id());
-            endpoint.close().await;
+            compress,
+            identity,
+            net,
+            command,
+        } => {
+            let command = (!command.is_empty()).then_some(command);
+            let secret_key = identity.secret_key()?;
+            let addr = addr.as_deref();
+            let client = run_client(&node_id, display, addr, compress, secret_key, &net, command);
+            exit_with(client.await?)
+        }
+        Commands::Id { identity } => {
+            println!("{}", identity.secret_key()?.public());
             Ok(())
         }
-        Commands::MirrorServer { display, bind } => {
-            mirror::run_mirror_server(&display, bind.as_deref()).await
+        #[cfg(unix)]
+        Commands::Status { json } => status::run_status(json).await,
+        #[cfg(unix)]
+        Commands::Kick { node, json } => status::run_kick(&node, json).await,
+        Commands::MirrorServer {
+            display,
+            metrics,
+            identity,
+            net,
+            access,
+        } => {
+            mirror::run_mirror_server(
+                &display,
+                metrics,
+                identity.secret_key()?,
+                &net,
+                access.load()?,
+            )
+            .await
         }
-        Commands::Mirror { node_id, addr } => {
-            mirror::run_mirror_client(&node_id, addr.as_deref()).await
+        Commands::Mirror {
+            node_id,
+            addr,
+            identity,
+            net,
+        } => {
+            let secret_key = identity.secret_key()?;
+            mirror::run_mirror_client(&node_id, addr.as_deref(), secret_key, &net).await
+        }
+        Commands::RendezvousServer { listen } => {
+            rendezvous_server::run_rendezvous_server(listen).await
         }
         #[cfg(unix)]
         Commands::Web { display, port, www } => web::run_web(display, port, www.as_deref()).await,
@@ -217,6 +513,14 @@ async fn main() -> Result<()> {
     }
 }
 
+/// Exit with a command's status; run only after sessions are torn down
+fn exit_with(code: i32) -> Result<()> {
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
 fn parse_display(display: &str) -> Result<u32> {
     display
         .trim_start_matches(':')
@@ -224,193 +528,442 @@ fn parse_display(display: &str) -> Result<u32> {
         .context("invalid display number")
 }
 
-#[cfg(unix)]
-fn x11_paths(display_num: u32) -> (String, String, bool) {
-    let socket = format!("{}/X{}", X11_UNIX_DIR, display_num);
-    let tcp = format!("127.0.0.1:{}", X11_TCP_BASE + display_num as u16);
-    let use_unix = std::path::Path::new(&socket).exists();
-    (socket, tcp, use_unix)
+/// Local X server that forwarded streams are proxied to
+#[derive(Clone)]
+struct X11Target {
+    display_num: u32,
+    socket: String,
+    tcp: String,
+    use_unix: bool,
+    /// request firewall, None for trusted clients
+    policy: Option<Arc<firewall::Policy>>,
+    /// whether to take compressed streams, and the level to answer them at
+    compress: Compress,
 }
 
-#[cfg(not(unix))]
-fn x11_paths(display_num: u32) -> (String, String, bool) {
-    let tcp = format!("127.0.0.1:{}", X11_TCP_BASE + display_num as u16);
-    (String::new(), tcp, false)
+impl X11Target {
+    #[cfg(unix)]
+    fn new(display_num: u32) -> Self {
+        // filesystem socket, or the abstract one in containers without it
+        let socket = xlock::find_socket(display_num);
+        let tcp = format!("127.0.0.1:{}", xlock::tcp_port(display_num));
+        let use_unix = socket.is_some();
+        let socket = socket.unwrap_or_else(|| xlock::socket_path(display_num));
+        Self {
+            display_num,
+            socket,
+            tcp,
+            use_unix,
+            policy: None,
+            compress: Compress::Auto,
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn new(display_num: u32) -> Self {
+        let tcp = format!("127.0.0.1:{}", xlock::tcp_port(display_num));
+        Self {
+            display_num,
+            socket: String::new(),
+            tcp,
+            use_unix: false,
+            policy: None,
+            compress: Compress::Auto,
+        }
+    }
+
+    fn with_trust(mut self, trust: Trust) -> Result<Self> {
+        if trust == Trust::Untrusted {
+            self.policy = Some(Arc::new(firewall::Policy::probe(self.display_num)?));
+        }
+        Ok(self)
+    }
+
+    fn with_compress(mut self, compress: Compress) -> Self {
+        self.compress = compress;
+        self
+    }
+
+    fn describe(&self) -> String {
+        let trust = if self.policy.is_some() {
+            ", untrusted"
+        } else {
+            ""
+        };
+        if self.use_unix {
+            format!("{} (unix{})", self.socket, trust)
+        } else {
+            format!("{} (tcp{})", self.tcp, trust)
+        }
+    }
 }
 
-/// Parse NodeId from string
+/// Parse NodeId from string, or look up a paired peer by name
 fn parse_node_id(s: &str) -> Result<NodeId> {
-    NodeId::from_str(s).context("invalid node id (expected base32 public key)")
+    authorized::resolve_peer(s)
 }
 
 // Easy mode: serve with word code + PAKE
-async fn run_serve(display: &str) -> Result<()> {
-    let display_num = parse_display(display)?;
-    let (x11_socket, x11_tcp, use_unix) = x11_paths(display_num);
+// Returns the exit status of the remote command, 0 without one
+async fn run_serve(
+    target: X11Target,
+    metrics: Option<SocketAddr>,
+    secret_key: SecretKey,
+    net: &NetArgs,
+    backend: &Backend,
+    limits: CodeArgs,
+    command: Option<Vec<String>>,
+) -> Result<i32> {
+    anyhow::ensure!(
+        command.is_none() || limits.joins == JoinLimit::Count(1),
+        "-- exec only works with a single join"
+    );
 
-    // generate word code and publish to dht
+    // generate word code and publish to the rendezvous
     let code = rendezvous::generate_code();
+    let keys = rendezvous::CodeKeys::derive(&code);
 
-    let endpoint = Endpoint::builder()
-        .alpns(vec![ALPN.to_vec()])
-        .bind()
-        .await?;
+    let builder = Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(vec![ALPN.to_vec()]);
+    let endpoint = net.bind(builder).await?;
 
     let node_id = endpoint.node_id();
+    let status = Status::new("serve", format!(":{}", target.display_num), &endpoint);
+    let _socket = status.listen();
+    if let Some(addr) = metrics {
+        metrics::listen(addr, status.clone()).await?;
+    }
 
-    eprintln!("publishing to dht...");
-    rendezvous::publish_nodeid(&code, node_id).await?;
+    let rendezvous = (!net.lan).then(|| backend.open()).transpose()?;
+    let publication = match &rendezvous {
+        Some(rendezvous) => {
+            eprintln!("publishing to {}...", backend);
+            rendezvous::publish_renewing(rendezvous.clone(), keys.clone(), &endpoint).await?
+        }
+        None => {
+            let answering = lan::answer(&endpoint, vec![lan::Query::code(&keys)])?;
+            eprintln!("answering on the local network, udp port {}", lan::PORT);
+            answering
+        }
+    };
 
     eprintln!();
-    eprintln!("  x11q join {}", code);
+    let lan = if net.lan { "--lan " } else { "" };
+    eprintln!("  x11q join {}{}", lan, code);
     eprintln!();
+    eprintln!("X11: {}", target.describe());
     eprintln!("waiting for connection...");
 
-    // accept connection
-    let incoming = endpoint.accept().await.context("no incoming connection")?;
-    let conn = incoming.await?;
+    let mut limiter = rendezvous::AttemptLimiter::new(limits.max_failures);
+    let mut sessions = tokio::task::JoinSet::new();
+    let streams = resume::Sessions::default();
+    let mut publication = Some(publication);
+    let mut joined = 0;
+    // peers that passed the pake may reconnect and resume without it
+    let mut authenticated = std::collections::HashSet::new();
+    let mut resumable_until: Option<tokio::time::Instant> = None;
+    // ctrl-c ends the session here, so the code is tombstoned first
+    let mut interrupts = xlock::intercept_signals();
 
-    // do pake handshake
-    let pake = rendezvous::PakeServer::new(&code);
-    let (mut send, mut recv) = conn.open_bi().await?;
-
-    // send our pake message
-    let msg = pake.message();
-    send.write_all(&(msg.len() as u32).to_le_bytes()).await?;
-    send.write_all(msg).await?;
+    loop {
+        let full = limits.joins.reached(joined);
+        if full {
+            // no more joins: retire the code, keep serving who is connected
+            if publication.is_some() {
+                revoke_code(publication.take()).await;
+                if !sessions.is_empty() {
+                    eprintln!("join limit reached, serving until all sessions end");
+                }
+            }
+            let resumable = resumable_until.is_some_and(|t| t > tokio::time::Instant::now());
+            if sessions.is_empty() && !resumable {
+                break;
+            }
+        }
+        let resume_deadline = resumable_until.unwrap_or_else(tokio::time::Instant::now);
 
-    // receive client's pake message
-    let mut len_buf = [0u8; 4];
-    recv.read_exact(&mut len_buf).await?;
-    let len = u32::from_le_bytes(len_buf) as usize;
-    let mut client_msg = vec![0u8; len];
-    recv.read_exact(&mut client_msg).await?;
+        let incoming = tokio::select! {
+            signal = interrupts.recv() => {
+                revoke_code(publication.take()).await;
+                endpoint.close().await;
+                return Ok(128 + signal);
+            }
+            incoming = endpoint.accept() => incoming.context("endpoint closed")?,
+            Some(ended) = sessions.join_next() => {
+                if ended.unwrap_or(false) {
+                    resumable_until = Some(tokio::time::Instant::now() + resume::RESUME_WINDOW);
+                }
+                continue;
+            }
+            _ = tokio::time::sleep_until(resume_deadline), if full && sessions.is_empty() => continue,
+        };
+        let conn = match incoming.await {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("incoming connection failed: {e}");
+                continue;
+            }
+        };
+        let remote_id = conn.remote_node_id()?;
+        let short = remote_id.to_string()[..8].to_string();
 
-    // verify pake
-    let _shared_key = pake.finish(&client_msg)?;
-    eprintln!("authenticated!");
+        if status.kicked(remote_id) {
+            conn.close(status::KICKED.into(), b"kicked");
+            continue;
+        }
+        if authenticated.contains(&remote_id) {
+            // a reconnect: the pake already proved this node id
+            let admitted = tokio::time::timeout(PAKE_TIMEOUT, admit(&conn, &target))
+                .await
+                .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+            match admitted {
+                Ok(control) => {
+                    eprintln!("[{}] reconnected", short);
+                    let (target, streams) = (target.clone(), streams.clone());
+                    let peer =
+                        serve_peer(conn, control, target, remote_id, streams, status.clone());
+                    sessions.spawn(peer);
+                }
+                Err(e) => {
+                    conn.close(1u32.into(), b"handshake failed");
+                    eprintln!("[{}] reconnect failed: {e}", short);
+                }
+            }
+            continue;
+        }
+        if full {
+            conn.close(3u32.into(), b"code already used");
+            continue;
+        }
 
-    eprintln!(
-        "X11: {} ({})",
-        if use_unix { &x11_socket } else { &x11_tcp },
-        if use_unix { "unix" } else { "tcp" }
-    );
+        if let Some(wait) = limiter.backoff(std::time::Instant::now()) {
+            eprintln!(
+                "[{}] rejected: rate limited for {}s",
+                short,
+                wait.as_secs() + 1
+            );
+            conn.close(2u32.into(), b"rate limited");
+            continue;
+        }
 
-    // now proxy x11
-    let remote_id = conn.remote_node_id()?;
-    handle_server_connection(conn, &x11_socket, &x11_tcp, use_unix, remote_id).await
-}
+        // pake + key confirmation, nothing is proxied until both sides verified
+        let auth = tokio::time::timeout(PAKE_TIMEOUT, async {
+            let mut control = control::accept(&conn, target.compress.features()).await?;
+            rendezvous::authenticate_server(&conn, &mut control, node_id, &code).await?;
+            let_in(&mut control, &target).await?;
+            anyhow::Ok(control)
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow::anyhow!("timed out")));
+        let control = match auth {
+            Ok(control) => control,
+            Err(e) => {
+                conn.close(1u32.into(), b"authentication failed");
+                eprintln!("[{}] authentication failed: {e}", short);
+                status.pake_failed();
+                if limiter.failed(std::time::Instant::now()) {
+                    revoke_code(publication.take()).await;
+                    anyhow::bail!(
+                        "code burned after {} failed attempts, run serve again for a new one",
+                        limiter.failures()
+                    );
+                }
+                continue;
+            }
+        };
+        joined += 1;
+        authenticated.insert(remote_id);
+        eprintln!("[{}] authenticated!", short);
+
+        if let Some(argv) = command {
+            // don't hold up the command for the rendezvous
+            let revoking = tokio::spawn(revoke_code(publication.take()));
+            let code = tokio::select! {
+                code = serve_command(conn, control, &target, remote_id, &argv, &streams, &status) => code,
+                signal = interrupts.recv() => Ok(128 + signal),
+            };
+            let _ = revoking.await;
+            endpoint.close().await;
+            return code;
+        }
 
-// Easy mode: join with word code + PAKE
-async fn run_join(code: &str, display_num: u32) -> Result<()> {
-    eprintln!("looking up {} on dht...", code);
+        let (target, streams) = (target.clone(), streams.clone());
+        let peer = serve_peer(conn, control, target, remote_id, streams, status.clone());
+        sessions.spawn(peer);
+    }
 
-    let remote_node_id = rendezvous::resolve_nodeid(code).await?;
-    eprintln!("found node: {}", &remote_node_id.to_string()[..8]);
+    endpoint.close().await;
+    Ok(0)
+}
 
-    let endpoint = Endpoint::builder()
-        .alpns(vec![ALPN.to_vec()])
-        .bind()
-        .await?;
+/// Stop publishing a code and tombstone its record, so later lookups
+/// learn it was used instead of timing out
+async fn revoke_code(publication: Option<rendezvous::Publication>) {
+    if let Some(publication) = publication {
+        if let Err(e) = publication.revoke().await {
+            eprintln!("revoking code failed: {e}");
+        }
+    }
+}
 
-    let node_addr = iroh::NodeAddr::new(remote_node_id);
-    let conn = endpoint.connect(node_addr, ALPN).await?;
+/// Proxy x11 for a joined peer until it disconnects
+///
+/// Returns true when the connection was lost rather than closed, so the
+/// peer may still come back to resume its streams.
+async fn serve_peer(
+    conn: iroh::endpoint::Connection,
+    control: Control,
+    target: X11Target,
+    remote_id: NodeId,
+    streams: resume::Sessions<firewall::Session>,
+    status: Status,
+) -> bool {
+    let _control = control.spawn(conn.clone());
+    let handled = handle_server_connection(conn.clone(), &target, remote_id, &streams, &status);
+    if let Err(e) = handled.await {
+        eprintln!("[{}] error: {e}", &remote_id.to_string()[..8]);
+    }
+    conn.close_reason()
+        .is_some_and(|reason| resume::is_transport_loss(&reason))
+}
 
-    // do pake handshake
-    let pake = rendezvous::PakeClient::new(code);
-    let (mut send, mut recv) = conn.accept_bi().await?;
+/// Proxy x11 for one joined peer while running `argv` on its side
+async fn serve_command(
+    conn: iroh::endpoint::Connection,
+    control: Control,
+    target: &X11Target,
+    remote_id: NodeId,
+    argv: &[String],
+    streams: &resume::Sessions<firewall::Session>,
+    status: &Status,
+) -> Result<i32> {
+    anyhow::ensure!(
+        control.negotiated.has(control::EXEC),
+        "the joining side can't run commands, upgrade its x11q"
+    );
+    let control = control.spawn(conn.clone());
 
-    // receive server's pake message
-    let mut len_buf = [0u8; 4];
-    recv.read_exact(&mut len_buf).await?;
-    let len = u32::from_le_bytes(len_buf) as usize;
-    let mut server_msg = vec![0u8; len];
-    recv.read_exact(&mut server_msg).await?;
+    // the session lasts as long as the remote command
+    let code = tokio::select! {
+        r = handle_server_connection(conn.clone(), target, remote_id, streams, status) => {
+            r?;
+            anyhow::bail!("connection closed before the command finished");
+        }
+        r = exec::run_remote(&conn, argv) => r?,
+    };
+    control.goodbye("command finished").await;
+    Ok(code)
+}
 
-    // send our pake message
-    let msg = pake.message();
-    send.write_all(&(msg.len() as u32).to_le_bytes()).await?;
-    send.write_all(msg).await?;
+/// Accept a peer's control stream and let it in
+async fn admit(conn: &iroh::endpoint::Connection, target: &X11Target) -> Result<Control> {
+    let mut control = control::accept(conn, target.compress.features()).await?;
+    let_in(&mut control, target).await?;
+    Ok(control)
+}
 
-    // verify pake
-    let _shared_key = pake.finish(&server_msg)?;
-    eprintln!("authenticated!");
+/// Let an accepted peer in, priming its x11 cache first if it has one
+async fn let_in(control: &mut Control, target: &X11Target) -> Result<()> {
+    if control.negotiated.has(control::CACHE) {
+        let (display_num, untrusted) = (target.display_num, target.policy.is_some());
+        let prime =
+            tokio::task::spawn_blocking(move || xcache::collect(display_num, untrusted)).await?;
+        match prime {
+            Ok(prime) => control.send_prime(&prime).await?,
+            Err(e) => eprintln!("x11 cache not primed: {e}"),
+        }
+    }
+    control.admit().await
+}
 
-    let conn = Arc::new(conn);
+// Easy mode: join with word code + PAKE
+async fn run_join(
+    code: &str,
+    display: DisplayNumber,
+    allowlist: exec::Allowlist,
+    compress: Compress,
+    net: &NetArgs,
+    backend: &Backend,
+    command: Option<Vec<String>>,
+) -> Result<i32> {
+    // claim the display before anything goes over the network
+    let lock = DisplayLock::acquire(display)?;
+
+    let keys = rendezvous::CodeKeys::derive(code);
+    let node_addr = if net.lan {
+        eprintln!("looking up {} on the local network...", code);
+        lan::resolve(lan::Query::code(&keys)).await?
+    } else {
+        eprintln!("looking up {} on {}...", code, backend);
+        let rendezvous = backend.open()?;
+        rendezvous::resolve_addr(rendezvous.as_ref(), &keys).await?
+    };
+    eprintln!("found node: {}", &node_addr.node_id.to_string()[..8]);
+
+    let endpoint = net
+        .bind(Endpoint::builder().alpns(vec![ALPN.to_vec()]))
+        .await?;
 
-    #[cfg(unix)]
-    {
-        let (unix_listener, tcp_listener) = create_x11_listeners(display_num).await?;
-        eprintln!("DISPLAY=:{} ready", display_num);
+    let conn = endpoint.connect(node_addr.clone(), ALPN).await?;
 
-        loop {
-            tokio::select! {
-                Ok((stream, _)) = unix_listener.accept() => {
-                    let conn = Arc::clone(&conn);
-                    tokio::spawn(async move {
-                        if let Err(e) = forward_to_quic_unix(stream, conn).await {
-                            eprintln!("x11 error: {e}");
-                        }
-                    });
-                }
-                Ok((stream, _)) = tcp_listener.accept() => {
-                    let conn = Arc::clone(&conn);
-                    tokio::spawn(async move {
-                        if let Err(e) = forward_to_quic_tcp(stream, conn).await {
-                            eprintln!("x11 error: {e}");
-                        }
-                    });
-                }
-            }
-        }
+    // pake + key confirmation, no local listeners until both sides verified
+    let mut control = control::open(&conn, compress.features()).await?;
+    let auth = rendezvous::authenticate_client(&conn, &mut control, endpoint.node_id(), code);
+    if let Err(e) = auth.await {
+        conn.close(1u32.into(), b"authentication failed");
+        return Err(e);
     }
+    control.ready().await?;
+    eprintln!("authenticated!");
 
-    #[cfg(not(unix))]
-    {
-        let tcp_listener = create_x11_listener_tcp(display_num).await?;
-        eprintln!("DISPLAY=localhost:{} ready", display_num);
-
-        loop {
-            let (stream, _) = tcp_listener.accept().await?;
-            let conn = Arc::clone(&conn);
-            tokio::spawn(async move {
-                if let Err(e) = forward_to_quic_tcp(stream, conn).await {
-                    eprintln!("x11 error: {e}");
-                }
-            });
-        }
-    }
+    // serve knows our node id from the pake, so a reconnect resumes without it
+    let cache = xcache::Cache::new(control.take_prime().as_deref());
+    let status = Status::new("join", format!(":{}", lock.display_num), &endpoint);
+    let _socket = status.listen();
+    let header = control::StreamHeader::new(control::StreamKind::X11, lock.display_num as u16);
+    let dialer = resume::Dialer::new(endpoint.clone(), node_addr, ALPN, header, conn, control);
+    let code = serve_x11_display(dialer, lock, allowlist, compress, cache, status, command).await?;
+    endpoint.close().await;
+    Ok(code)
 }
 
 // Server: runs on local machine with display
-async fn run_server(display: &str, bind: Option<&str>) -> Result<()> {
-    let display_num = parse_display(display)?;
-    let (x11_socket, x11_tcp, use_unix) = x11_paths(display_num);
-
-    eprintln!(
-        "X11 target: {} ({})",
-        if use_unix { &x11_socket } else { &x11_tcp },
-        if use_unix { "unix" } else { "tcp" }
-    );
+async fn run_server(
+    target: X11Target,
+    metrics: Option<SocketAddr>,
+    secret_key: SecretKey,
+    net: &NetArgs,
+    authorized: AuthorizedNodes,
+) -> Result<()> {
+    let authorized = Arc::new(authorized);
 
-    // Build endpoint with optional bind address
-    let mut builder = Endpoint::builder().alpns(vec![ALPN.to_vec()]);
+    eprintln!("X11 target: {}", target.describe());
 
-    if let Some(addr) = bind {
-        builder = builder.bind_addr_v4(addr.parse().context("invalid bind address")?);
-    }
+    let builder = Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(vec![ALPN.to_vec()]);
+    let endpoint = net.bind(builder).await?;
 
-    let endpoint = builder.bind().await?;
+    let status = Status::new("server", format!(":{}", target.display_num), &endpoint);
+    let _socket = status.listen();
+    if let Some(addr) = metrics {
+        metrics::listen(addr, status.clone()).await?;
+    }
 
     eprintln!("x11q server started");
     eprintln!("node id: {}", endpoint.node_id());
 
     // Wait for relay connection and print info
-    let relay_url = endpoint.home_relay().initialized().await?;
-    eprintln!("relay: {}", relay_url);
+    net.announce(&endpoint).await?;
+    let _lan = net.answer_lan(&endpoint)?;
     eprintln!("holepunching ready - waiting for connections...");
     eprintln!();
-    eprintln!("connect with: x11q client {}", endpoint.node_id());
+    let lan = if net.lan { "--lan " } else { "" };
+    eprintln!("connect with: x11q client {}{}", lan, endpoint.node_id());
+
+    // streams outlive connections, so a client can reconnect and resume
+    let streams = resume::Sessions::default();
 
     // Accept connections
     while let Some(incoming) = endpoint.accept().await {
@@ -418,13 +971,14 @@ async fn run_server(display: &str, bind: Option<&str>) -> Result<()> {
         let remote_id = conn.remote_node_id()?;
         eprintln!("[{}] connected", &remote_id.to_string()[..8]);
 
-        let x11_socket = x11_socket.clone();
-        let x11_tcp = x11_tcp.clone();
+        let target = target.clone();
+        let authorized = Arc::clone(&authorized);
+        let streams = streams.clone();
+        let status = status.clone();
 
         tokio::spawn(async move {
-            if let Err(e) =
-                handle_server_connection(conn, &x11_socket, &x11_tcp, use_unix, remote_id).await
-            {
+            let served = serve_client(conn, &target, remote_id, &authorized, &streams, &status);
+            if let Err(e) = served.await {
                 eprintln!("[{}] error: {e}", &remote_id.to_string()[..8]);
             }
         });
@@ -433,126 +987,284 @@ async fn run_server(display: &str, bind: Option<&str>) -> Result<()> {
     Ok(())
 }
 
+/// Direct mode: let an authorized peer in and proxy its streams
+async fn serve_client(
+    conn: iroh::endpoint::Connection,
+    target: &X11Target,
+    remote_id: NodeId,
+    authorized: &AuthorizedNodes,
+    streams: &resume::Sessions<firewall::Session>,
+    status: &Status,
+) -> Result<()> {
+    if status.kicked(remote_id) {
+        conn.close(status::KICKED.into(), b"kicked");
+        return Ok(());
+    }
+    // direct mode has no pake, so the allowlist is the only gate
+    match authorized.check(remote_id, Permission::Forward)? {
+        Some(node) => eprintln!("[{}] authorized", node.display_name()),
+        None => {
+            eprintln!("[{}] rejected: not authorized to forward", remote_id);
+            conn.close(1u32.into(), b"not authorized");
+            return Ok(());
+        }
+    }
+
+    let _control = admit(&conn, target).await?.spawn(conn.clone());
+    handle_server_connection(conn, target, remote_id, streams, status).await
+}
+
 async fn handle_server_connection(
     conn: iroh::endpoint::Connection,
-    x11_socket: &str,
-    x11_tcp: &str,
-    use_unix: bool,
+    target: &X11Target,
     remote_id: NodeId,
+    streams: &resume::Sessions<firewall::Session>,
+    status: &Status,
 ) -> Result<()> {
+    let _tracked = status.connected(&conn)?;
     loop {
         let (quic_send, quic_recv) = match conn.accept_bi().await {
             Ok(s) => s,
             Err(_) => break,
         };
 
-        let x11_socket = x11_socket.to_string();
-        let x11_tcp = x11_tcp.to_string();
+        let target = target.clone();
+        let streams = streams.clone();
+        let status = status.clone();
 
         tokio::spawn(async move {
-            #[cfg(unix)]
-            let result = if use_unix {
-                proxy_to_unix(quic_send, quic_recv, &x11_socket).await
-            } else {
-                proxy_to_tcp(quic_send, quic_recv, &x11_tcp).await
-            };
-            #[cfg(not(unix))]
-            let result = {
-                let _ = (use_unix, x11_socket); // suppress warnings
-                proxy_to_tcp(quic_send, quic_recv, &x11_tcp).await
-            };
-            if let Err(e) = result {
+            let served = serve_stream(quic_send, quic_recv, &target, remote_id, &streams, &status);
+            if let Err(e) = served.await {
                 eprintln!("stream error: {e}");
             }
         });
     }
 
-    eprintln!("[{}] disconnected", &remote_id.to_string()[..8]);
+    if status.kicked(remote_id) {
+        // no coming back, so its streams end now instead of waiting to resume
+        streams.end(remote_id);
+        eprintln!("[{}] kicked", &remote_id.to_string()[..8]);
+        return Ok(());
+    }
+    match conn.close_reason() {
+        Some(reason) if resume::is_transport_loss(&reason) => eprintln!(
+            "[{}] connection lost, streams resumable for {}s",
+            &remote_id.to_string()[..8],
+            resume::RESUME_WINDOW.as_secs()
+        ),
+        _ => eprintln!("[{}] disconnected", &remote_id.to_string()[..8]),
+    }
     Ok(())
 }
 
+/// Proxy one x11 stream to the local display
+async fn serve_stream(
+    quic_send: iroh::endpoint::SendStream,
+    mut quic_recv: iroh::endpoint::RecvStream,
+    target: &X11Target,
+    remote_id: NodeId,
+    streams: &resume::Sessions<firewall::Session>,
+    status: &Status,
+) -> Result<()> {
+    let header = control::StreamHeader::read(&mut quic_recv, control::StreamKind::X11).await?;
+    let compressed = header.options & control::COMPRESSED != 0;
+    anyhow::ensure!(
+        !compressed || target.compress != Compress::Off,
+        "refused a compressed stream, compression is off"
+    );
+
+    // a resumed stream carries on where it is already being served;
+    // every x11 stream of a session shares one sandbox
+    let Some(accepted) = streams.attach(remote_id, quic_send, quic_recv).await? else {
+        return Ok(());
+    };
+    let (mut remote, session) = (accepted.stream, accepted.shared);
+    if compressed {
+        remote = compress::wrap(remote, target.compress.reply_level());
+    }
+    let remote = status::Counted::new(remote, status.stream(remote_id));
+
+    #[cfg(unix)]
+    if target.use_unix {
+        return proxy_to_unix(remote, target, session).await;
+    }
+    proxy_to_tcp(remote, target, session).await
+}
+
 // Client: runs on remote machine, creates virtual display
-async fn run_client(node_id: &str, display_num: u32, addr_hint: Option<&str>) -> Result<()> {
+async fn run_client(
+    node_id: &str,
+    display: DisplayNumber,
+    addr_hint: Option<&str>,
+    compress: Compress,
+    secret_key: SecretKey,
+    net: &NetArgs,
+    command: Option<Vec<String>>,
+) -> Result<i32> {
     let remote_node_id = parse_node_id(node_id)?;
+    let lock = DisplayLock::acquire(display)?;
 
-    let endpoint = Endpoint::builder()
-        .alpns(vec![ALPN.to_vec()])
-        .bind()
-        .await?;
+    let builder = Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(vec![ALPN.to_vec()]);
+    let endpoint = net.bind(builder).await?;
 
     eprintln!("connecting to {}...", &node_id[..8.min(node_id.len())]);
 
-    // Build node address
-    let mut node_addr = iroh::NodeAddr::new(remote_node_id);
-
-    // Add direct address hint if provided
-    if let Some(addr) = addr_hint {
-        node_addr =
-            node_addr.with_direct_addresses([addr.parse().context("invalid address hint")?]);
-    }
+    let node_addr = net.node_addr(remote_node_id, addr_hint).await?;
 
     // Connect (iroh handles holepunching automatically)
-    let conn = endpoint.connect(node_addr, ALPN).await?;
+    let conn = endpoint.connect(node_addr.clone(), ALPN).await?;
     let remote_id = conn.remote_node_id()?;
-    let conn = Arc::new(conn);
-
     eprintln!("connected to {}", &remote_id.to_string()[..8]);
 
+    let mut control = control::open(&conn, compress.features()).await?;
+    control.ready().await?;
+
+    let allowlist = exec::Allowlist::default();
+    let cache = xcache::Cache::new(control.take_prime().as_deref());
+    let status = Status::new("client", format!(":{}", lock.display_num), &endpoint);
+    let _socket = status.listen();
+    let header = control::StreamHeader::new(control::StreamKind::X11, lock.display_num as u16);
+    let dialer = resume::Dialer::new(endpoint.clone(), node_addr, ALPN, header, conn, control);
+    let code = serve_x11_display(dialer, lock, allowlist, compress, cache, status, command).await?;
+    endpoint.close().await;
+    Ok(code)
+}
+
+/// Create the virtual display and forward every local X11 client over `dialer`
+///
+/// Local clients must present the session's MIT-MAGIC-COOKIE-1, which is
+/// written to a private Xauthority file for the lifetime of the session.
+/// With a `command`, the session ends when it exits and its exit status
+/// is returned; otherwise this runs until the peer ends the session or it
+/// can't be resumed after a lost connection.
+async fn serve_x11_display(
+    dialer: Arc<resume::Dialer>,
+    lock: DisplayLock,
+    allowlist: exec::Allowlist,
+    compress: Compress,
+    cache: xcache::Cache,
+    status: Status,
+    command: Option<Vec<String>>,
+) -> Result<i32> {
+    let display_num = lock.display_num;
+    let auth = xauth::SessionAuth::create(display_num)?;
+    let cookie = auth.cookie;
+
     #[cfg(unix)]
     {
-        let (unix_listener, tcp_listener) = create_x11_listeners(display_num).await?;
-        eprintln!("X11 proxy ready - DISPLAY=:{}", display_num);
-
-        loop {
+        let (unix_listener, tcp_listener) = create_x11_listeners(&lock).await?;
+        eprintln!(
+            "DISPLAY=:{} XAUTHORITY={} ready",
+            display_num,
+            auth.path.display()
+        );
+        let env = exec::DisplayEnv {
+            display: format!(":{}", display_num),
+            xauthority: auth.path.clone(),
+        };
+        // requests are refused unless --allow-exec names the command line
+        tokio::spawn(accept_commands(Arc::clone(&dialer), allowlist, env.clone()));
+        tokio::spawn(track_connection(Arc::clone(&dialer), status.clone()));
+        let mut child = command
+            .map(|argv| exec::spawn_with_display(&argv, &env))
+            .transpose()?;
+        let keep_alive = dialer.keep_alive();
+        tokio::pin!(keep_alive);
+
+        let code = loop {
             tokio::select! {
                 Ok((stream, _)) = unix_listener.accept() => {
-                    let conn = Arc::clone(&conn);
+                    let dialer = Arc::clone(&dialer);
+                    let (cache, status) = (cache.clone(), status.clone());
                     tokio::spawn(async move {
-                        if let Err(e) = forward_to_quic_unix(stream, conn).await {
+                        let forwarded = forward_to_quic(stream, &dialer, cookie, compress, cache, &status);
+                        if let Err(e) = forwarded.await {
                             eprintln!("x11 error: {e}");
                         }
                     });
                 }
                 Ok((stream, _)) = tcp_listener.accept() => {
-                    let conn = Arc::clone(&conn);
+                    let dialer = Arc::clone(&dialer);
+                    let (cache, status) = (cache.clone(), status.clone());
                     tokio::spawn(async move {
-                        if let Err(e) = forward_to_quic_tcp(stream, conn).await {
+                        let forwarded = forward_to_quic(stream, &dialer, cookie, compress, cache, &status);
+                        if let Err(e) = forwarded.await {
                             eprintln!("x11 error: {e}");
                         }
                     });
                 }
+                status = exec::wait(&mut child) => break exec::exit_code(status?),
+                r = &mut keep_alive => {
+                    // the peer ended the session, or it couldn't be resumed
+                    r?;
+                    break 0;
+                }
             }
-        }
+        };
+
+        // removes the socket and lock
+        drop(unix_listener);
+        drop(lock);
+        dialer.close("command exited").await;
+        Ok(code)
     }
 
     #[cfg(not(unix))]
     {
         let tcp_listener = create_x11_listener_tcp(display_num).await?;
-        eprintln!("X11 proxy ready - DISPLAY=localhost:{}", display_num);
-
-        loop {
-            let (stream, _) = tcp_listener.accept().await?;
-            let conn = Arc::clone(&conn);
-            tokio::spawn(async move {
-                if let Err(e) = forward_to_quic_tcp(stream, conn).await {
-                    eprintln!("x11 error: {e}");
+        eprintln!(
+            "DISPLAY=localhost:{} XAUTHORITY={} ready",
+            display_num,
+            auth.path.display()
+        );
+        let env = exec::DisplayEnv {
+            display: format!("localhost:{}", display_num),
+            xauthority: auth.path.clone(),
+        };
+        tokio::spawn(accept_commands(Arc::clone(&dialer), allowlist, env.clone()));
+        tokio::spawn(track_connection(Arc::clone(&dialer), status.clone()));
+        let mut child = command
+            .map(|argv| exec::spawn_with_display(&argv, &env))
+            .transpose()?;
+        let keep_alive = dialer.keep_alive();
+        tokio::pin!(keep_alive);
+
+        let code = loop {
+            tokio::select! {
+                accepted = tcp_listener.accept() => {
+                    let (stream, _) = accepted?;
+                    let dialer = Arc::clone(&dialer);
+                    let (cache, status) = (cache.clone(), status.clone());
+                    tokio::spawn(async move {
+                        let forwarded = forward_to_quic(stream, &dialer, cookie, compress, cache, &status);
+                        if let Err(e) = forwarded.await {
+                            eprintln!("x11 error: {e}");
+                        }
+                    });
                 }
-            });
-        }
+                status = exec::wait(&mut child) => break exec::exit_code(status?),
+                r = &mut keep_alive => {
+                    // the peer ended the session, or it couldn't be resumed
+                    r?;
+                    break 0;
+                }
+            }
+        };
+
+        dialer.close("command exited").await;
+        Ok(code)
     }
 }
 
 // Helper functions
 
 #[cfg(unix)]
-async fn create_x11_listeners(display_num: u32) -> Result<(UnixListener, TcpListener)> {
-    let x11_socket = format!("{}/X{}", X11_UNIX_DIR, display_num);
-    let _ = std::fs::remove_file(&x11_socket);
-    std::fs::create_dir_all(X11_UNIX_DIR).ok();
+async fn create_x11_listeners(lock: &DisplayLock) -> Result<(xlock::X11Listener, TcpListener)> {
+    let unix_listener = lock.listen()?;
 
-    let unix_listener = UnixListener::bind(&x11_socket).context("failed to create X11 socket")?;
-
-    let tcp_port = X11_TCP_BASE + display_num as u16;
+    let tcp_port = xlock::tcp_port(lock.display_num);
     let tcp_listener = TcpListener::bind(format!("127.0.0.1:{}", tcp_port))
         .await
         .context("failed to bind X11 TCP port")?;
@@ -562,7 +1274,7 @@ async fn create_x11_listeners(display_num: u32) -> Result<(UnixListener, TcpList
 
 #[cfg(not(unix))]
 async fn create_x11_listener_tcp(display_num: u32) -> Result<TcpListener> {
-    let tcp_port = X11_TCP_BASE + display_num as u16;
+    let tcp_port = xlock::tcp_port(display_num);
     let tcp_listener = TcpListener::bind(format!("127.0.0.1:{}", tcp_port))
         .await
         .context("failed to bind X11 TCP port")?;
@@ -570,60 +1282,117 @@ async fn create_x11_listener_tcp(display_num: u32) -> Result<TcpListener> {
 }
 
 #[cfg(unix)]
-async fn proxy_to_unix(
-    mut quic_send: iroh::endpoint::SendStream,
-    mut quic_recv: iroh::endpoint::RecvStream,
-    socket_path: &str,
+async fn proxy_to_unix<R: AsyncRead + AsyncWrite + Unpin>(
+    mut remote: R,
+    target: &X11Target,
+    session: firewall::Session,
 ) -> Result<()> {
-    let unix = UnixStream::connect(socket_path).await?;
-    let (mut unix_read, mut unix_write) = unix.into_split();
+    // the remote's auth is never passed on, the local cookie is used instead
+    let (setup, rest) = xauth::inject_local_credentials(&mut remote, target.display_num).await?;
 
-    tokio::select! {
-        r = io::copy(&mut quic_recv, &mut unix_write) => { r?; }
-        r = io::copy(&mut unix_read, &mut quic_send) => { r?; }
-    }
-    Ok(())
+    let unix = xlock::connect(target.display_num).await?;
+    proxy_local(remote, unix, setup, rest, target, session).await
 }
 
-async fn proxy_to_tcp(
-    mut quic_send: iroh::endpoint::SendStream,
-    mut quic_recv: iroh::endpoint::RecvStream,
-    addr: &str,
+async fn proxy_to_tcp<R: AsyncRead + AsyncWrite + Unpin>(
+    mut remote: R,
+    target: &X11Target,
+    session: firewall::Session,
 ) -> Result<()> {
-    let tcp = TcpStream::connect(addr).await?;
-    let (mut tcp_read, mut tcp_write) = tcp.into_split();
+    let (setup, rest) = xauth::inject_local_credentials(&mut remote, target.display_num).await?;
 
-    tokio::select! {
-        r = io::copy(&mut quic_recv, &mut tcp_write) => { r?; }
-        r = io::copy(&mut tcp_read, &mut quic_send) => { r?; }
-    }
-    Ok(())
+    let tcp = TcpStream::connect(&target.tcp).await?;
+    proxy_local(remote, tcp, setup, rest, target, session).await
 }
 
-#[cfg(unix)]
-async fn forward_to_quic_unix(
-    unix: UnixStream,
-    conn: Arc<iroh::endpoint::Connection>,
+/// Send the rewritten setup to the local X server, then relay both ways
+async fn proxy_local<R: AsyncRead + AsyncWrite, S: AsyncRead + AsyncWrite>(
+    remote: R,
+    local: S,
+    setup: xauth::SetupRequest,
+    rest: Vec<u8>,
+    target: &X11Target,
+    session: firewall::Session,
 ) -> Result<()> {
-    let (quic_send, quic_recv) = conn.open_bi().await?;
-    let (mut unix_read, mut unix_write) = unix.into_split();
-    let (mut quic_send, mut quic_recv) = (quic_send, quic_recv);
+    let (mut remote_read, mut remote_write) = io::split(remote);
+    let (mut local_read, mut local_write) = io::split(local);
+    local_write.write_all(&setup.encode()).await?;
+
+    let Some(policy) = &target.policy else {
+        local_write.write_all(&rest).await?;
+        tokio::select! {
+            r = io::copy(&mut remote_read, &mut local_write) => { r?; }
+            r = io::copy(&mut local_read, &mut remote_write) => { r?; }
+        }
+        return Ok(());
+    };
+
+    // untrusted: every request and reply goes through the firewall
+    let (mut requests, replies) = firewall::filters(Arc::clone(policy), session, setup.byte_order);
+    let mut out = Vec::new();
+    firewall::Filter::push(&mut requests, &rest, &mut out);
+    local_write.write_all(&out).await?;
 
     tokio::select! {
-        r = io::copy(&mut unix_read, &mut quic_send) => { r?; }
-        r = io::copy(&mut quic_recv, &mut unix_write) => { r?; }
+        r = firewall::pump(remote_read, local_write, requests) => { r?; }
+        r = firewall::pump(local_read, remote_write, replies) => { r?; }
     }
     Ok(())
 }
 
-async fn forward_to_quic_tcp(tcp: TcpStream, conn: Arc<iroh::endpoint::Connection>) -> Result<()> {
-    let (quic_send, quic_recv) = conn.open_bi().await?;
-    let (mut tcp_read, mut tcp_write) = tcp.into_split();
-    let (mut quic_send, mut quic_recv) = (quic_send, quic_recv);
+/// Keep the status socket showing whichever connection the session is on
+async fn track_connection(dialer: Arc<resume::Dialer>, status: Status) {
+    let mut conns = dialer.subscribe();
+    loop {
+        let conn = conns.borrow_and_update().clone();
+        let tracked = conn.and_then(|conn| status.connected(&conn).ok());
+        if conns.changed().await.is_err() {
+            return;
+        }
+        drop(tracked);
+    }
+}
 
-    tokio::select! {
-        r = io::copy(&mut tcp_read, &mut quic_send) => { r?; }
-        r = io::copy(&mut quic_recv, &mut tcp_write) => { r?; }
+/// Answer exec requests on whichever connection the session is on
+async fn accept_commands(
+    dialer: Arc<resume::Dialer>,
+    allowlist: exec::Allowlist,
+    env: exec::DisplayEnv,
+) {
+    let mut conns = dialer.subscribe();
+    loop {
+        let conn = conns.borrow_and_update().clone();
+        if let Some(conn) = conn {
+            exec::accept_commands(conn, allowlist.clone(), env.clone()).await;
+        }
+        if conns.changed().await.is_err() {
+            return;
+        }
     }
-    Ok(())
+}
+
+async fn forward_to_quic<S: AsyncRead + AsyncWrite + Unpin>(
+    mut local: S,
+    dialer: &resume::Dialer,
+    cookie: xauth::Cookie,
+    compress: Compress,
+    cache: xcache::Cache,
+    status: &Status,
+) -> Result<()> {
+    // nothing reaches quic before the client proved it has the cookie
+    let (setup, rest) = xauth::authenticate_client(&mut local, &cookie).await?;
+
+    // decided per stream, so auto follows the path as it changes
+    let level = if dialer.negotiated().has(control::COMPRESS) {
+        compress.level(&dialer.path())
+    } else {
+        None
+    };
+    let remote = match level {
+        Some(level) => compress::wrap(dialer.open(control::COMPRESSED).await, level),
+        None => dialer.open(0).await,
+    };
+    let mut remote = status::Counted::new(remote, status.stream(dialer.peer()));
+    remote.write_all(&setup).await?;
+    xcache::proxy(local, remote, setup[0], &rest, cache).await
 }
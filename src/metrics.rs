@@ -0,0 +1,234 @@
+//! OpenMetrics endpoint for long-running instances (`--metrics ADDR`)
+//!
+//! Serves `GET /metrics` from the same live state as `x11q status`: peers,
+//! their paths and QUIC stats, X11 streams and traffic, PAKE failures and
+//! mirror frames. Per-peer series are labelled with the full node id.
+
+use crate::status::{Report, Status, Totals};
+use anyhow::{Context, Result};
+use axum::{http::header, routing::get, Router};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+
+const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+const PATHS: [&str; 4] = ["direct", "relay", "mixed", "none"];
+
+/// Start serving metrics for `status` on `addr`
+pub async fn listen(addr: SocketAddr, status: Status) -> Result<()> {
+    let app = Router::new().route(
+        "/metrics",
+        get(move || {
+            let body = render(&status.report(), status.totals());
+            async move { ([(header::CONTENT_TYPE, CONTENT_TYPE)], body) }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind metrics address {}", addr))?;
+    eprintln!("metrics: http://{}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            eprintln!("metrics server error: {e}");
+        }
+    });
+    Ok(())
+}
+
+/// Write one metric family: its metadata, then `samples` as (labels, value)
+fn family<V: std::fmt::Display>(
+    out: &mut String,
+    name: &str,
+    kind: &str,
+    help: &str,
+    samples: impl IntoIterator<Item = (String, V)>,
+) {
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for unit in ["seconds", "bytes"] {
+        if name.ends_with(unit) {
+            let _ = writeln!(out, "# UNIT {} {}", name, unit);
+        }
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let suffix = if kind == "counter" { "_total" } else { "" };
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{}{} {}", name, suffix, labels, value);
+    }
+}
+
+fn total(counter: &std::sync::atomic::AtomicU64) -> [(String, u64); 1] {
+    [(String::new(), counter.load(Ordering::Relaxed))]
+}
+
+fn peer(node_id: &str) -> String {
+    format!("{{peer=\"{}\"}}", node_id)
+}
+
+/// The OpenMetrics text for one scrape
+pub fn render(report: &Report, totals: &Totals) -> String {
+    let mut out = String::new();
+    let connected: Vec<_> = report.peers.iter().filter(|p| p.connected).collect();
+
+    family(
+        &mut out,
+        "x11q_uptime_seconds",
+        "gauge",
+        "Seconds since this instance started",
+        [(String::new(), report.uptime_secs)],
+    );
+    family(
+        &mut out,
+        "x11q_connections",
+        "gauge",
+        "Peers currently connected",
+        [(String::new(), connected.len())],
+    );
+    family(
+        &mut out,
+        "x11q_paths",
+        "gauge",
+        "Connected peers by path",
+        PATHS.map(|path| {
+            let n = connected.iter().filter(|p| p.path == path).count();
+            (format!("{{path=\"{}\"}}", path), n)
+        }),
+    );
+    family(
+        &mut out,
+        "x11q_x11_streams",
+        "gauge",
+        "X11 streams currently open, including ones waiting to resume",
+        [(
+            String::new(),
+            report.peers.iter().map(|p| p.streams.len()).sum::<usize>(),
+        )],
+    );
+    family(
+        &mut out,
+        "x11q_x11_streams_opened",
+        "counter",
+        "X11 streams opened",
+        total(&totals.streams),
+    );
+    family(
+        &mut out,
+        "x11q_x11_received_bytes",
+        "counter",
+        "X11 bytes received from peers, before compression",
+        total(&totals.bytes_in),
+    );
+    family(
+        &mut out,
+        "x11q_x11_sent_bytes",
+        "counter",
+        "X11 bytes sent to peers, before compression",
+        total(&totals.bytes_out),
+    );
+    family(
+        &mut out,
+        "x11q_pake_failures",
+        "counter",
+        "Failed word code authentications",
+        total(&totals.pake_failures),
+    );
+    family(
+        &mut out,
+        "x11q_rtt_seconds",
+        "gauge",
+        "QUIC round trip time",
+        connected
+            .iter()
+            .filter_map(|p| Some((peer(&p.node_id), p.rtt_ms? / 1000.0))),
+    );
+    family(
+        &mut out,
+        "x11q_sent_packets",
+        "counter",
+        "QUIC packets sent on the current connection",
+        connected.iter().map(|p| (peer(&p.node_id), p.sent_packets)),
+    );
+    family(
+        &mut out,
+        "x11q_lost_packets",
+        "counter",
+        "QUIC packets lost on the current connection",
+        connected.iter().map(|p| (peer(&p.node_id), p.lost_packets)),
+    );
+    family(
+        &mut out,
+        "x11q_mirror_frames",
+        "counter",
+        "Mirror frames sent to viewers",
+        total(&totals.mirror_frames),
+    );
+    family(
+        &mut out,
+        "x11q_mirror_frame_bytes",
+        "counter",
+        "Compressed size of the mirror frames sent",
+        total(&totals.mirror_bytes),
+    );
+    out.push_str("# EOF\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::PeerReport;
+
+    fn peer_report(node_id: &str, path: &str, connected: bool) -> PeerReport {
+        PeerReport {
+            node_id: node_id.to_string(),
+            connected,
+            path: path.to_string(),
+            remote_addr: None,
+            relay: None,
+            rtt_ms: connected.then_some(12.5),
+            sent_packets: 200,
+            lost_packets: 2,
+            connected_secs: 30,
+            streams: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let report = Report {
+            role: "server".to_string(),
+            pid: 1,
+            node_id: "self".to_string(),
+            display: ":0".to_string(),
+            uptime_secs: 60,
+            peers: vec![
+                peer_report("aaaa", "direct", true),
+                peer_report("bbbb", "relay", true),
+                peer_report("cccc", "none", false),
+            ],
+        };
+        let totals = Totals::default();
+        totals.bytes_in.store(4096, Ordering::Relaxed);
+        totals.pake_failures.store(3, Ordering::Relaxed);
+        let text = render(&report, &totals);
+
+        for line in [
+            "# TYPE x11q_connections gauge",
+            "x11q_connections 2",
+            "x11q_paths{path=\"direct\"} 1",
+            "x11q_paths{path=\"relay\"} 1",
+            "x11q_paths{path=\"none\"} 0",
+            "# TYPE x11q_x11_received_bytes counter",
+            "# UNIT x11q_x11_received_bytes bytes",
+            "x11q_x11_received_bytes_total 4096",
+            "x11q_pake_failures_total 3",
+            "# UNIT x11q_rtt_seconds seconds",
+            "x11q_rtt_seconds{peer=\"aaaa\"} 0.0125",
+            "x11q_lost_packets_total{peer=\"bbbb\"} 2",
+        ] {
+            assert!(text.lines().any(|l| l == line), "missing {line}");
+        }
+        // a peer that is only resumable has no connection to measure
+        assert!(!text.contains("cccc"));
+        assert!(text.ends_with("# EOF\n"));
+    }
+}
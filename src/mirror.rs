@@ -3,10 +3,13 @@
 //! Captures the screen, compresses, and streams over QUIC.
 //! Receives input events and injects them via XTest.
 
+use crate::authorized::{AuthorizedNodes, Permission};
+use crate::net::NetArgs;
+use crate::status::Status;
 use anyhow::{Context, Result};
-use iroh::{Endpoint, NodeAddr, NodeId};
+use iroh::{Endpoint, SecretKey};
 use minifb::{Key, MouseButton, MouseMode, Window, WindowOptions};
-use std::str::FromStr;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use x11rb::connection::{Connection, RequestConnection};
 use x11rb::protocol::shm;
@@ -24,7 +27,14 @@ const MSG_MOUSE: u8 = 4; // Mouse button event
 const MSG_MOTION: u8 = 5; // Mouse motion event
 
 /// Server: captures screen and streams to client
-pub async fn run_mirror_server(display: &str, bind: Option<&str>) -> Result<()> {
+pub async fn run_mirror_server(
+    display: &str,
+    metrics: Option<SocketAddr>,
+    secret_key: SecretKey,
+    net: &NetArgs,
+    authorized: AuthorizedNodes,
+) -> Result<()> {
+    let authorized = Arc::new(authorized);
     let display_num: u32 = display
         .trim_start_matches(':')
         .parse()
@@ -52,23 +62,27 @@ pub async fn run_mirror_server(display: &str, bind: Option<&str>) -> Result<()>
     );
 
     // Set up iroh endpoint
-    let mut builder = Endpoint::builder().alpns(vec![ALPN.to_vec()]);
-
-    if let Some(addr) = bind {
-        builder = builder.bind_addr_v4(addr.parse().context("invalid bind address")?);
+    let builder = Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(vec![ALPN.to_vec()]);
+    let endpoint = net.bind(builder).await?;
+
+    let status = Status::new("mirror-server", format!(":{}", display_num), &endpoint);
+    let _socket = status.listen();
+    if let Some(addr) = metrics {
+        crate::metrics::listen(addr, status.clone()).await?;
     }
 
-    let endpoint = builder.bind().await?;
-
     eprintln!("x11q mirror-server started");
     eprintln!("node id: {}", endpoint.node_id());
 
     // Wait for relay connection
-    let relay_url = endpoint.home_relay().initialized().await?;
-    eprintln!("relay: {}", relay_url);
+    net.announce(&endpoint).await?;
+    let _lan = net.answer_lan(&endpoint)?;
     eprintln!("holepunching ready - waiting for viewer...");
     eprintln!();
-    eprintln!("connect with: x11q mirror {}", endpoint.node_id());
+    let lan = if net.lan { "--lan " } else { "" };
+    eprintln!("connect with: x11q mirror {}{}", lan, endpoint.node_id());
 
     loop {
         let incoming = match endpoint.accept().await {
@@ -88,8 +102,20 @@ pub async fn run_mirror_server(display: &str, bind: Option<&str>) -> Result<()>
         eprintln!("[{}] viewer connected", &remote_id.to_string()[..8]);
 
         let conn_clone = Arc::clone(&conn);
+        let authorized = Arc::clone(&authorized);
+        let status = status.clone();
         tokio::spawn(async move {
-            if let Err(e) = handle_viewer(quic_conn, conn_clone, root, width, height).await {
+            if let Err(e) = handle_viewer(
+                quic_conn,
+                conn_clone,
+                &authorized,
+                &status,
+                root,
+                width,
+                height,
+            )
+            .await
+            {
                 eprintln!("viewer error: {e}");
             }
         });
@@ -99,10 +125,37 @@ pub async fn run_mirror_server(display: &str, bind: Option<&str>) -> Result<()>
 async fn handle_viewer(
     quic_conn: iroh::endpoint::Connection,
     x_conn: Arc<x11rb::rust_connection::RustConnection>,
+    authorized: &AuthorizedNodes,
+    status: &Status,
     root: u32,
     width: u32,
     height: u32,
 ) -> Result<()> {
+    let remote_id = quic_conn.remote_node_id()?;
+    if status.kicked(remote_id) {
+        quic_conn.close(crate::status::KICKED.into(), b"kicked");
+        return Ok(());
+    }
+    let viewer = match authorized.check(remote_id, Permission::MirrorView)? {
+        Some(node) => node,
+        None => {
+            eprintln!("[{}] rejected: not authorized to view", remote_id);
+            quic_conn.close(1u32.into(), b"not authorized");
+            return Ok(());
+        }
+    };
+    let _tracked = status.connected(&quic_conn)?;
+    let control = viewer.allows(Permission::MirrorControl);
+    eprintln!(
+        "[{}] authorized ({})",
+        viewer.display_name(),
+        if control {
+            "view + control"
+        } else {
+            "view only"
+        }
+    );
+
     // Open streams for video and input
     let (mut send, recv) = quic_conn.open_bi().await?;
 
@@ -110,9 +163,17 @@ async fn handle_viewer(
     send.write_all(&width.to_le_bytes()).await?;
     send.write_all(&height.to_le_bytes()).await?;
 
-    // Spawn input handler
+    // Spawn input handler, view-only viewers get their input dropped
     let x_conn_input = Arc::clone(&x_conn);
-    let input_handle = tokio::spawn(async move { handle_input(recv, x_conn_input).await });
+    let input_handle = tokio::spawn(async move {
+        if control {
+            handle_input(recv, x_conn_input).await
+        } else {
+            let mut recv = recv;
+            while let Ok(Some(_)) = recv.read_chunk(4096, true).await {}
+            Ok(())
+        }
+    });
 
     // Capture and send frames
     let mut last_frame: Vec<u8> = vec![0; (width * height * 4) as usize];
@@ -170,6 +231,7 @@ async fn handle_viewer(
             send.write_all(&(compressed.len() as u32).to_le_bytes())
                 .await?;
             send.write_all(&compressed).await?;
+            status.mirror_frame(compressed.len());
 
             last_frame = frame;
 
@@ -267,21 +329,22 @@ async fn handle_input(
 }
 
 /// Client: displays remote screen and sends input
-pub async fn run_mirror_client(node_id: &str, addr_hint: Option<&str>) -> Result<()> {
-    let remote_node_id = NodeId::from_str(node_id).context("invalid node id")?;
+pub async fn run_mirror_client(
+    node_id: &str,
+    addr_hint: Option<&str>,
+    secret_key: SecretKey,
+    net: &NetArgs,
+) -> Result<()> {
+    let remote_node_id = crate::authorized::resolve_peer(node_id)?;
 
-    let endpoint = Endpoint::builder()
-        .alpns(vec![ALPN.to_vec()])
-        .bind()
-        .await?;
+    let builder = Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(vec![ALPN.to_vec()]);
+    let endpoint = net.bind(builder).await?;
 
     eprintln!("connecting to {}...", &node_id[..8.min(node_id.len())]);
 
-    let mut node_addr = NodeAddr::new(remote_node_id);
-
-    if let Some(addr) = addr_hint {
-        node_addr = node_addr.with_direct_addresses([addr.parse().context("invalid address")?]);
-    }
+    let node_addr = net.node_addr(remote_node_id, addr_hint).await?;
 
     let conn = endpoint.connect(node_addr, ALPN).await?;
 
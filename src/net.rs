@@ -0,0 +1,277 @@
+//! Endpoint options shared by every command that talks to a peer
+//!
+//! By default iroh binds `0.0.0.0` and `[::]` on random UDP ports and uses
+//! the public relays. `--relay` swaps in self-hosted relays, `--no-relay`
+//! drops them for LAN-only or air-gapped setups, and `--bind`/`--port` pin
+//! the addresses and the UDP port, e.g. for firewall rules. `--lan` finds
+//! peers by broadcast on the local network and uses no relay unless one is
+//! named, so nothing outside the subnet is contacted.
+
+use crate::lan;
+use crate::rendezvous::Publication;
+use anyhow::{Context, Result};
+use clap::Args;
+use iroh::endpoint::Builder;
+use iroh::{Endpoint, NodeAddr, NodeId, RelayMap, RelayMode, RelayUrl};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::str::FromStr;
+
+/// Relay and bind options for the endpoint
+#[derive(Args, Clone, Debug, Default)]
+pub struct NetArgs {
+    /// Relay server to use instead of the public ones (repeatable)
+    #[arg(long, value_name = "URL", conflicts_with = "no_relay")]
+    pub relay: Vec<RelayUrl>,
+
+    /// Use no relay at all: direct connections only (LAN, air-gapped)
+    #[arg(long)]
+    pub no_relay: bool,
+
+    /// Local address to bind, IPv4 or IPv6 with an optional port; repeat
+    /// with one of each family for a dual-stack bind
+    #[arg(short, long, value_name = "ADDR")]
+    pub bind: Vec<BindAddr>,
+
+    /// UDP port to bind on every address that doesn't name one
+    #[arg(long, value_name = "PORT")]
+    pub port: Option<u16>,
+
+    /// Find peers on the local network instead of the DHT; no relay unless
+    /// --relay is given
+    #[arg(long)]
+    pub lan: bool,
+}
+
+/// `--bind`: an address with or without a port (`::1`, `10.0.0.2:4433`, `[::]:4433`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BindAddr {
+    ip: IpAddr,
+    port: Option<u16>,
+}
+
+impl FromStr for BindAddr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Self {
+                ip: addr.ip(),
+                port: Some(addr.port()),
+            });
+        }
+        let ip = s
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .map_err(|_| anyhow::anyhow!("expected an IP address, optionally with a port"))?;
+        Ok(Self { ip, port: None })
+    }
+}
+
+impl NetArgs {
+    pub fn relay_mode(&self) -> RelayMode {
+        if self.no_relay || (self.lan && self.relay.is_empty()) {
+            RelayMode::Disabled
+        } else if self.relay.is_empty() {
+            RelayMode::Default
+        } else {
+            RelayMode::Custom(self.relay.iter().cloned().collect::<RelayMap>())
+        }
+    }
+
+    /// The IPv4 and IPv6 addresses to bind, None for iroh's default
+    fn addrs(&self) -> Result<(Option<SocketAddrV4>, Option<SocketAddrV6>)> {
+        let (mut v4, mut v6) = (None, None);
+        for bind in &self.bind {
+            let port = bind.port.or(self.port).unwrap_or(0);
+            match bind.ip {
+                IpAddr::V4(ip) => {
+                    anyhow::ensure!(v4.is_none(), "--bind takes one IPv4 address");
+                    v4 = Some(SocketAddrV4::new(ip, port));
+                }
+                IpAddr::V6(ip) => {
+                    anyhow::ensure!(v6.is_none(), "--bind takes one IPv6 address");
+                    v6 = Some(SocketAddrV6::new(ip, port, 0, 0));
+                }
+            }
+        }
+        if let Some(port) = self.port {
+            v4 = v4.or(Some(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)));
+            v6 = v6.or(Some(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0)));
+        }
+        Ok((v4, v6))
+    }
+
+    /// Bind `builder` with these options
+    ///
+    /// iroh quietly takes a random port when the one asked for is in use,
+    /// so this fails instead: a rule for a fixed port wouldn't match.
+    pub async fn bind(&self, builder: Builder) -> Result<Endpoint> {
+        let (v4, v6) = self.addrs()?;
+        let mut builder = builder.relay_mode(self.relay_mode());
+        if let Some(addr) = v4 {
+            builder = builder.bind_addr_v4(addr);
+        }
+        if let Some(addr) = v6 {
+            builder = builder.bind_addr_v6(addr);
+        }
+        let endpoint = builder.bind().await?;
+
+        let (bound_v4, bound_v6) = endpoint.bound_sockets();
+        let wanted = [v4.map(SocketAddr::V4), v6.map(SocketAddr::V6)];
+        let bound = [Some(bound_v4), bound_v6];
+        for (wanted, bound) in wanted.into_iter().zip(bound) {
+            let Some(wanted) = wanted else { continue };
+            let ok = bound.is_some_and(|b| wanted.port() == 0 || b.port() == wanted.port());
+            if !ok {
+                endpoint.close().await;
+                anyhow::bail!("could not bind {}, is the port in use?", wanted);
+            }
+        }
+        Ok(endpoint)
+    }
+
+    /// Wait until the endpoint can be reached and say how
+    pub async fn announce(&self, endpoint: &Endpoint) -> Result<()> {
+        let (v4, v6) = endpoint.bound_sockets();
+        let mut udp = v4.to_string();
+        if let Some(v6) = v6 {
+            udp.push_str(&format!(", {}", v6));
+        }
+        if self.relay_mode() == RelayMode::Disabled {
+            eprintln!("relay: disabled, direct connections only");
+            eprintln!("udp: {}", udp);
+            return Ok(());
+        }
+        if !self.bind.is_empty() || self.port.is_some() {
+            eprintln!("udp: {}", udp);
+        }
+        let relay_url = endpoint
+            .home_relay()
+            .initialized()
+            .await
+            .context("no relay reachable")?;
+        eprintln!("relay: {}", relay_url);
+        Ok(())
+    }
+
+    /// With `--lan`, answer queries for the endpoint's node id until dropped
+    pub fn answer_lan(&self, endpoint: &Endpoint) -> Result<Option<Publication>> {
+        if !self.lan {
+            return Ok(None);
+        }
+        let answering = lan::answer(endpoint, vec![lan::Query::Node(endpoint.node_id())])?;
+        eprintln!("lan: answering on udp port {}", lan::PORT);
+        Ok(Some(answering))
+    }
+
+    /// Where to dial `node_id`: at `addr` if given, else with `--lan` where it
+    /// answers on the local network, else wherever discovery finds it
+    pub async fn node_addr(&self, node_id: NodeId, addr: Option<&str>) -> Result<NodeAddr> {
+        if let Some(addr) = addr {
+            let addr = addr.parse().context("invalid address hint")?;
+            return Ok(NodeAddr::new(node_id).with_direct_addresses([addr]));
+        }
+        if self.lan {
+            return lan::resolve(lan::Query::Node(node_id)).await;
+        }
+        Ok(NodeAddr::new(node_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct Cli {
+        #[command(flatten)]
+        net: NetArgs,
+    }
+
+    fn parse(args: &[&str]) -> Result<NetArgs, clap::Error> {
+        let args = std::iter::once("x11q").chain(args.iter().copied());
+        Cli::try_parse_from(args).map(|cli| cli.net)
+    }
+
+    #[test]
+    fn test_bind_addr() {
+        let addr = |s: &str| s.parse::<BindAddr>().unwrap();
+        assert_eq!(addr("10.0.0.2:4433").port, Some(4433));
+        assert_eq!(addr("10.0.0.2").port, None);
+        assert_eq!(addr("[::]:4433").ip, IpAddr::V6(Ipv6Addr::UNSPECIFIED));
+        assert_eq!(addr("::1").ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert_eq!(addr("[::1]").ip, IpAddr::V6(Ipv6Addr::LOCALHOST));
+        assert!("localhost".parse::<BindAddr>().is_err());
+    }
+
+    #[test]
+    fn test_addrs() {
+        assert_eq!(parse(&[]).unwrap().addrs().unwrap(), (None, None));
+
+        let net = parse(&["--port", "4433"]).unwrap();
+        assert_eq!(
+            net.addrs().unwrap(),
+            (
+                Some("0.0.0.0:4433".parse().unwrap()),
+                Some("[::]:4433".parse().unwrap())
+            )
+        );
+
+        // a port on the address wins over --port
+        let net = parse(&["--bind", "10.0.0.2:5000", "--bind", "::", "--port", "4433"]).unwrap();
+        assert_eq!(
+            net.addrs().unwrap(),
+            (
+                Some("10.0.0.2:5000".parse().unwrap()),
+                Some("[::]:4433".parse().unwrap())
+            )
+        );
+
+        let net = parse(&["--bind", "[fd00::2]:4433"]).unwrap();
+        assert_eq!(
+            net.addrs().unwrap(),
+            (None, Some("[fd00::2]:4433".parse().unwrap()))
+        );
+
+        let net = parse(&["--bind", "10.0.0.2", "--bind", "10.0.0.3"]).unwrap();
+        assert!(net.addrs().is_err());
+    }
+
+    #[test]
+    fn test_relay_mode() {
+        assert_eq!(parse(&[]).unwrap().relay_mode(), RelayMode::Default);
+        assert_eq!(
+            parse(&["--no-relay"]).unwrap().relay_mode(),
+            RelayMode::Disabled
+        );
+        let net = parse(&[
+            "--relay",
+            "https://relay1.example.org",
+            "--relay",
+            "https://relay2.example.org",
+        ])
+        .unwrap();
+        let RelayMode::Custom(map) = net.relay_mode() else {
+            panic!("expected custom relays");
+        };
+        assert_eq!(map.len(), 2);
+        assert_eq!(parse(&["--lan"]).unwrap().relay_mode(), RelayMode::Disabled);
+        let net = parse(&["--lan", "--relay", "https://relay1.example.org"]).unwrap();
+        assert!(matches!(net.relay_mode(), RelayMode::Custom(_)));
+        assert!(parse(&["--no-relay", "--relay", "https://relay1.example.org"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fixed_port_in_use_fails() {
+        let net = parse(&["--no-relay", "--bind", "127.0.0.1"]).unwrap();
+        let first = net.bind(Endpoint::builder()).await.unwrap();
+        let port = first.bound_sockets().0.port();
+
+        let taken = format!("127.0.0.1:{}", port);
+        let net = parse(&["--no-relay", "--bind", &taken]).unwrap();
+        assert!(net.bind(Endpoint::builder()).await.is_err());
+        first.close().await;
+    }
+}
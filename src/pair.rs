@@ -0,0 +1,161 @@
+//! One-time pairing: turn a word code into a persistent trusted peer
+//!
+//! Both sides meet through the usual rendezvous and spake2 exchange,
+//! using their persistent identities. After key confirmation each side
+//! sends a friendly name, and writes the other into its authorized_nodes
+//! file under that name. The node id recorded is the one proven by the
+//! pake on this connection, never a value the peer merely claims, and it
+//! gets only the permissions granted with `--grant` (forward by default).
+//! A name that collides with another peer's gets a numeric suffix.
+//!
+//! ```text
+//! local:  x11q pair              → prints "x11q pair 7-tiger-lamp"
+//! remote: x11q pair 7-tiger-lamp → both sides now trust each other
+//! remote: x11q client office-desktop
+//! ```
+
+use crate::authorized::{self, Permission};
+use crate::control;
+use crate::rendezvous::{self, Backend};
+use crate::xauth;
+use anyhow::{Context, Result};
+use iroh::endpoint::Connection;
+use iroh::{Endpoint, SecretKey};
+use std::path::PathBuf;
+
+const ALPN: &[u8] = b"x11quic-pair/2";
+const MAX_NAME_LEN: usize = 64;
+
+/// Pair with another machine, generating a code if none is given
+pub async fn run_pair(
+    code: Option<&str>,
+    name: Option<&str>,
+    authorized_nodes: Option<PathBuf>,
+    grant: &[Permission],
+    backend: &Backend,
+    secret_key: SecretKey,
+) -> Result<()> {
+    let name = match name {
+        Some(n) => sanitize_name(n)?,
+        None => sanitize_name(&default_name())?,
+    };
+    let path = match authorized_nodes {
+        Some(p) => p,
+        None => authorized::default_path()?,
+    };
+
+    let endpoint = Endpoint::builder()
+        .secret_key(secret_key)
+        .alpns(vec![ALPN.to_vec()])
+        .bind()
+        .await?;
+    let node_id = endpoint.node_id();
+    let rendezvous = backend.open()?;
+
+    let (conn, peer_name) = match code {
+        None => {
+            let code = rendezvous::generate_code();
+            eprintln!("publishing to {}...", backend);
+            let keys = rendezvous::CodeKeys::derive(&code);
+            let addr = rendezvous::local_addr(&endpoint).await;
+            rendezvous::publish_addr(rendezvous.as_ref(), &keys, &addr).await?;
+
+            eprintln!();
+            eprintln!("  x11q pair {}", code);
+            eprintln!();
+            eprintln!("waiting for peer...");
+
+            let incoming = endpoint.accept().await.context("no incoming connection")?;
+            let conn = incoming.await?;
+            let mut control = control::accept(&conn, control::FEATURES).await?;
+            authenticate(
+                &conn,
+                rendezvous::authenticate_server(&conn, &mut control, node_id, &code).await,
+            )?;
+            if let Err(e) = rendezvous.revoke(&keys).await {
+                eprintln!("revoking code failed: {e}");
+            }
+            control.admit().await?;
+
+            let (mut send, mut recv) = conn.open_bi().await?;
+            rendezvous::write_msg(&mut send, name.as_bytes()).await?;
+            let peer_name = read_name(&mut recv).await?;
+            send.finish()?;
+            (conn, peer_name)
+        }
+        Some(code) => {
+            eprintln!("looking up {} on {}...", code, backend);
+            let keys = rendezvous::CodeKeys::derive(code);
+            let remote_addr = rendezvous::resolve_addr(rendezvous.as_ref(), &keys).await?;
+
+            let conn = endpoint.connect(remote_addr, ALPN).await?;
+            let mut control = control::open(&conn, control::FEATURES).await?;
+            authenticate(
+                &conn,
+                rendezvous::authenticate_client(&conn, &mut control, node_id, code).await,
+            )?;
+            control.ready().await?;
+
+            let (mut send, mut recv) = conn.accept_bi().await?;
+            let peer_name = read_name(&mut recv).await?;
+            rendezvous::write_msg(&mut send, name.as_bytes()).await?;
+            send.finish()?;
+            (conn, peer_name)
+        }
+    };
+
+    let peer_id = conn.remote_node_id()?;
+    let peer_name = authorized::trust(&path, peer_id, &peer_name, grant)?;
+    let granted: Vec<_> = grant.iter().map(|p| p.to_string()).collect();
+    eprintln!(
+        "trusted peer saved to {} with {}",
+        path.display(),
+        granted.join(",")
+    );
+
+    conn.close(0u32.into(), b"paired");
+    endpoint.close().await;
+
+    eprintln!("paired with {} ({})", peer_name, &peer_id.to_string()[..8]);
+    eprintln!("  connect with: x11q client {}", peer_name);
+    eprintln!("  or mirror:    x11q mirror {}", peer_name);
+    Ok(())
+}
+
+fn authenticate(conn: &Connection, result: Result<rendezvous::PakeKey>) -> Result<()> {
+    match result {
+        Ok(_) => {
+            eprintln!("authenticated!");
+            Ok(())
+        }
+        Err(e) => {
+            conn.close(1u32.into(), b"authentication failed");
+            Err(e)
+        }
+    }
+}
+
+async fn read_name(recv: &mut iroh::endpoint::RecvStream) -> Result<String> {
+    let bytes = rendezvous::read_msg(recv).await?;
+    let name = String::from_utf8(bytes).context("peer name is not utf8")?;
+    sanitize_name(&name).context("peer sent an invalid name")
+}
+
+/// names end up as authorized_nodes labels and cli arguments
+fn sanitize_name(name: &str) -> Result<String> {
+    let name: String = name
+        .trim()
+        .chars()
+        .map(|c| if c.is_whitespace() { '-' } else { c })
+        .filter(|c| *c != '#' && !c.is_control())
+        .take(MAX_NAME_LEN)
+        .collect();
+    anyhow::ensure!(!name.is_empty(), "empty peer name");
+    authorized::check_label(&name)?;
+    Ok(name)
+}
+
+/// hostname, so peers show up as something recognisable
+fn default_name() -> String {
+    xauth::hostname().unwrap_or_else(|| "x11q".to_string())
+}
@@ -1,22 +1,81 @@
-//! rendezvous - word-code based peer discovery over mainline dht
+//! rendezvous - word-code based peer discovery
 //!
 //! uses pkarr to publish nodeid under a derived keypair, so both sides
 //! can find each other using just a short word code like "7-tiger-lamp".
-//! spake2 pake ensures only someone with the code can connect.
+//! the signed record goes to mainline dht by default, or to a pkarr relay
+//! or a self-hosted rendezvous server where the dht is blocked.
+//! spake2 pake ensures only someone with the code can connect, and
+//! key confirmation bound to both node ids and the tls session makes
+//! sure it is the peer on *this* connection that knew the code.
+//!
+//! a served code stays valid while the record is republished; every
+//! failed pake attempt doubles the wait before the next one is tried,
+//! and the code is burned after too many failures.
 
+use crate::control::Control;
+use crate::scrypt;
 use anyhow::{Context, Result};
-use ed25519_dalek::SigningKey;
-use iroh::NodeId;
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::Args;
+use crypto_secretbox::aead::{Aead, AeadCore, OsRng};
+use crypto_secretbox::XSalsa20Poly1305;
+use hmac::{Hmac, Mac};
+use iroh::endpoint::{Connection, RecvStream, SendStream};
+use iroh::{Endpoint, NodeAddr, NodeId};
 use pkarr::dns::{rdata::TXT, Name};
-use pkarr::{Client as PkarrClient, Keypair, SignedPacket};
+use pkarr::{Client as PkarrClient, Keypair, PublicKey, SignedPacket};
 use rand::Rng;
-use sha2::{Digest, Sha256};
+use reqwest::Url;
+use sha2::Sha256;
 use spake2::{Ed25519Group, Identity, Password, Spake2};
-use std::time::Duration;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
 use tokio::time::timeout;
 
-const DHT_TIMEOUT: Duration = Duration::from_secs(30);
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
 const CODE_TTL: u32 = 120;
+/// a used code keeps saying so long after its record would have expired
+const TOMBSTONE_TTL: u32 = 3600;
+/// republish well before resolvers drop the record
+const RENEW_INTERVAL: Duration = Duration::from_secs(CODE_TTL as u64 / 2);
+/// scrypt cost of stretching a code: 32 MiB, ~100ms in a release build.
+/// tests use a cheap one, the derivation is the same otherwise
+#[cfg(not(test))]
+const STRETCH: scrypt::Params = scrypt::Params {
+    log_n: 15,
+    r: 8,
+    p: 1,
+};
+#[cfg(test)]
+const STRETCH: scrypt::Params = scrypt::Params {
+    log_n: 4,
+    r: 8,
+    p: 1,
+};
+const STRETCH_SALT: &[u8] = b"x11q-rendezvous-v2";
+/// leads the TXT record; a new format gets a new tag
+const RECORD_VERSION: &str = "x11q2:";
+const NONCE_LEN: usize = 24;
+/// the record of a used code, after the version tag; far shorter than any
+/// sealed record, so the two can't be confused
+const TOMBSTONE: &str = "used";
+/// how long a fresh endpoint may take to find its direct addresses
+const ADDR_WAIT: Duration = Duration::from_secs(2);
+/// wait after an address change before republishing
+const SETTLE: Duration = Duration::from_secs(1);
+/// direct addresses published, so the record stays well under 1000 bytes
+const MAX_ADDRS: usize = 8;
+/// longest forced wait between attempts after repeated failures
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+const EXPORTER_LABEL: &[u8] = b"x11q-pake-v1";
+/// spake2 messages and confirmation tags are well under this
+const MAX_PAKE_MSG: usize = 1024;
+
+type HmacSha256 = Hmac<Sha256>;
 
 /// pgp-style wordlist (256 words, 8 bits each)
 /// even words: 2 syllables, odd words: 3 syllables (helps error detection)
@@ -290,89 +349,580 @@ pub fn generate_code() -> String {
     format!("{}-{}-{}", n, w1, w2)
 }
 
-/// derive deterministic ed25519 keypair from code
-/// used as pkarr identity for dht publish/lookup
-fn derive_keypair(code: &str) -> Keypair {
-    let mut hasher = Sha256::new();
-    hasher.update(b"x11q-rendezvous-v1:");
-    hasher.update(code.as_bytes());
-    let seed: [u8; 32] = hasher.finalize().into();
+/// what a code stretches to: the pkarr identity its record is published
+/// under, and the key the record is encrypted with
+///
+/// both come from one scrypt run, so matching a record on the dht to a
+/// code costs a full run per guess, and only code holders can read it.
+#[derive(Clone)]
+pub struct CodeKeys {
+    keypair: Keypair,
+    secret: [u8; 32],
+}
 
-    // pkarr keypair from seed
-    let signing_key = SigningKey::from_bytes(&seed);
-    Keypair::from_secret_key(&signing_key.to_bytes())
+impl CodeKeys {
+    /// stretch `code`; this takes a while and 32 MiB on purpose
+    pub fn derive(code: &str) -> Self {
+        let mut out = [0u8; 64];
+        scrypt::scrypt(code.as_bytes(), STRETCH_SALT, STRETCH, &mut out);
+        let (seed, secret) = out.split_at(32);
+        Self {
+            keypair: Keypair::from_secret_key(seed.try_into().expect("32 bytes")),
+            secret: secret.try_into().expect("32 bytes"),
+        }
+    }
+
+    /// what the code is looked up by, without giving the code away
+    pub fn lookup_key(&self) -> [u8; 32] {
+        self.keypair.public_key().to_bytes()
+    }
+
+    fn cipher(&self) -> XSalsa20Poly1305 {
+        <XSalsa20Poly1305 as crypto_secretbox::KeyInit>::new(&self.secret.into())
+    }
 }
 
-/// publish our nodeid to dht under the code's derived key
-pub async fn publish_nodeid(code: &str, node_id: NodeId) -> Result<()> {
-    let keypair = derive_keypair(code);
-    let client = PkarrClient::builder().build()?;
+/// where word codes are published and looked up
+///
+/// records are pkarr packets signed with the code's derived key, so a
+/// backend only stores and hands them out; it can't forge or alter one.
+#[async_trait]
+pub trait Rendezvous: Send + Sync {
+    /// store `packet`, replacing an older one under the same key
+    async fn publish(&self, packet: &SignedPacket) -> Result<()>;
 
-    // encode nodeid as TXT record
-    let node_id_hex = hex::encode(node_id.as_bytes());
-    let name = Name::new("_x11q").context("invalid dns name")?;
-    let txt = TXT::new()
-        .with_string(&node_id_hex)
-        .context("invalid txt")?;
+    /// the packet stored under `key`, None if there is none
+    async fn resolve(&self, key: &PublicKey) -> Result<Option<SignedPacket>>;
 
-    let packet = SignedPacket::builder()
-        .txt(name, txt, CODE_TTL)
-        .sign(&keypair)?;
+    /// replace the record of a code with a tombstone, so lookups fail with
+    /// "code already used" at once instead of finding a stale address or
+    /// timing out
+    async fn revoke(&self, keys: &CodeKeys) -> Result<()> {
+        self.publish(&tombstone(keys)?).await
+    }
+}
 
-    client.publish(&packet, None).await?;
+/// which rendezvous to use: `dht`, `pkarr:URL` or `server:URL`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Backend {
+    /// mainline dht, and pkarr's public relays where it is slow
+    Dht,
+    /// a pkarr relay, which also publishes to the dht
+    Pkarr(Url),
+    /// a self-hosted `x11q rendezvous-server`
+    Server(Url),
+}
 
-    Ok(())
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "dht" {
+            return Ok(Self::Dht);
+        }
+        let (kind, url) = s
+            .split_once(':')
+            .context("expected dht, pkarr:URL or server:URL")?;
+        let url: Url = url.parse().context("invalid url")?;
+        anyhow::ensure!(
+            matches!(url.scheme(), "http" | "https"),
+            "expected an http or https url"
+        );
+        match kind {
+            "pkarr" => Ok(Self::Pkarr(url)),
+            "server" => Ok(Self::Server(url)),
+            _ => anyhow::bail!("expected dht, pkarr:URL or server:URL"),
+        }
+    }
 }
 
-/// resolve nodeid from dht using code
-pub async fn resolve_nodeid(code: &str) -> Result<NodeId> {
-    let keypair = derive_keypair(code);
-    let public_key = keypair.public_key();
-    let client = PkarrClient::builder().build()?;
+impl std::fmt::Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dht => write!(f, "dht"),
+            Self::Pkarr(url) => write!(f, "pkarr relay {}", url),
+            Self::Server(url) => write!(f, "rendezvous server {}", url),
+        }
+    }
+}
 
-    let packet = timeout(DHT_TIMEOUT, client.resolve(&public_key))
-        .await
-        .context("dht lookup timed out")?
-        .ok_or_else(|| anyhow::anyhow!("code not found on dht"))?;
-
-    // find TXT record
-    for record in packet.resource_records("_x11q") {
-        if let pkarr::dns::rdata::RData::TXT(ref txt) = record.rdata {
-            // convert TXT to String
-            let txt_str: String = txt
-                .clone()
+impl Backend {
+    pub fn open(&self) -> Result<Arc<dyn Rendezvous>> {
+        Ok(match self {
+            Self::Dht => Arc::new(Pkarr(PkarrClient::builder().build()?)),
+            Self::Pkarr(url) => Arc::new(Pkarr(
+                PkarrClient::builder()
+                    .no_default_network()
+                    .relays(std::slice::from_ref(url))?
+                    .build()?,
+            )),
+            Self::Server(url) => Arc::new(Server {
+                url: url.clone(),
+                http: reqwest::Client::new(),
+            }),
+        })
+    }
+}
+
+/// mainline dht or pkarr relays, through the pkarr client
+struct Pkarr(PkarrClient);
+
+#[async_trait]
+impl Rendezvous for Pkarr {
+    async fn publish(&self, packet: &SignedPacket) -> Result<()> {
+        Ok(self.0.publish(packet, None).await?)
+    }
+
+    async fn resolve(&self, key: &PublicKey) -> Result<Option<SignedPacket>> {
+        Ok(self.0.resolve(key).await)
+    }
+}
+
+/// an `x11q rendezvous-server`
+///
+/// it speaks the pkarr relay protocol, but unlike the pkarr client this
+/// tells an unreachable server apart from a code that isn't there.
+struct Server {
+    url: Url,
+    http: reqwest::Client,
+}
+
+impl Server {
+    fn record(&self, key: &PublicKey) -> Result<Url> {
+        let mut url = self.url.clone();
+        url.path_segments_mut()
+            .map_err(|_| anyhow::anyhow!("invalid rendezvous server url"))?
+            .pop_if_empty()
+            .push(&key.to_z32());
+        Ok(url)
+    }
+}
+
+#[async_trait]
+impl Rendezvous for Server {
+    async fn publish(&self, packet: &SignedPacket) -> Result<()> {
+        self.http
+            .put(self.record(&packet.public_key())?)
+            .body(packet.to_relay_payload())
+            .send()
+            .await
+            .with_context(|| format!("rendezvous server {} unreachable", self.url))?
+            .error_for_status()
+            .context("rendezvous server refused the record")?;
+        Ok(())
+    }
+
+    async fn resolve(&self, key: &PublicKey) -> Result<Option<SignedPacket>> {
+        let response = self
+            .http
+            .get(self.record(key)?)
+            .send()
+            .await
+            .with_context(|| format!("rendezvous server {} unreachable", self.url))?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let payload = response
+            .error_for_status()
+            .context("rendezvous server lookup failed")?
+            .bytes()
+            .await?;
+        // the server is not trusted with the record: check its signature
+        let packet = SignedPacket::from_relay_payload(key, &payload)
+            .context("rendezvous server sent an invalid record")?;
+        Ok(Some(packet))
+    }
+}
+
+/// the signed record for `addr` under the code's key
+///
+/// a single TXT record: the format version, then the address sealed with
+/// the code's key (xsalsa20-poly1305, random nonce) in base64. sealed are
+/// the node id, the home relay (`relay=URL`) and the direct addresses
+/// (`addr=IP:PORT`), one per line.
+fn encode_record(keys: &CodeKeys, addr: &NodeAddr) -> Result<SignedPacket> {
+    let mut lines = vec![hex::encode(addr.node_id.as_bytes())];
+    if let Some(relay) = &addr.relay_url {
+        lines.push(format!("relay={}", relay));
+    }
+    let direct = addr.direct_addresses.iter().take(MAX_ADDRS);
+    lines.extend(direct.map(|a| format!("addr={}", a)));
+
+    let nonce = XSalsa20Poly1305::generate_nonce(&mut OsRng);
+    let sealed = keys
+        .cipher()
+        .encrypt(&nonce, lines.join("\n").as_bytes())
+        .map_err(|_| anyhow::anyhow!("sealing record failed"))?;
+    let payload = format!(
+        "{}{}",
+        RECORD_VERSION,
+        BASE64.encode([nonce.as_slice(), &sealed].concat())
+    );
+
+    // a txt string holds at most 255 bytes
+    let mut txt = TXT::new();
+    for chunk in payload.as_bytes().chunks(255) {
+        let chunk = std::str::from_utf8(chunk).expect("base64 is ascii");
+        txt.add_string(chunk).context("invalid txt")?;
+    }
+    let name = Name::new("_x11q").context("invalid dns name")?;
+    Ok(SignedPacket::builder()
+        .txt(name, txt, CODE_TTL)
+        .sign(&keys.keypair)?)
+}
+
+/// the record that marks a code as used
+fn tombstone(keys: &CodeKeys) -> Result<SignedPacket> {
+    let payload = format!("{}{}", RECORD_VERSION, TOMBSTONE);
+    let mut txt = TXT::new();
+    txt.add_string(&payload).context("invalid txt")?;
+    let name = Name::new("_x11q").context("invalid dns name")?;
+    Ok(SignedPacket::builder()
+        .txt(name, txt, TOMBSTONE_TTL)
+        .sign(&keys.keypair)?)
+}
+
+/// the node address in a record; entries it doesn't know are skipped
+fn decode_record(keys: &CodeKeys, packet: &SignedPacket) -> Result<NodeAddr> {
+    let txt = packet
+        .resource_records("_x11q")
+        .find_map(|record| match &record.rdata {
+            pkarr::dns::rdata::RData::TXT(txt) => Some(txt.clone()),
+            _ => None,
+        })
+        .context("no nodeid found in rendezvous record")?;
+    let txt: String = txt
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid utf8 in txt record"))?;
+    let payload = txt.strip_prefix(RECORD_VERSION).context(
+        "rendezvous record in an unknown format, does the other side run another x11q version?",
+    )?;
+    anyhow::ensure!(payload != TOMBSTONE, "code already used");
+    let sealed = BASE64
+        .decode(payload)
+        .context("invalid rendezvous record encoding")?;
+    anyhow::ensure!(sealed.len() > NONCE_LEN, "rendezvous record too short");
+    let (nonce, sealed) = sealed.split_at(NONCE_LEN);
+    let opened = keys
+        .cipher()
+        .decrypt(nonce.into(), sealed)
+        .map_err(|_| anyhow::anyhow!("rendezvous record can't be opened with this code"))?;
+    let opened = String::from_utf8(opened).context("invalid utf8 in rendezvous record")?;
+
+    let mut node_id = None;
+    let mut relay_url = None;
+    let mut direct = Vec::new();
+    for line in opened.lines() {
+        if let Some(url) = line.strip_prefix("relay=") {
+            relay_url = Some(url.parse().context("invalid relay url")?);
+        } else if let Some(addr) = line.strip_prefix("addr=") {
+            direct.push(addr.parse().context("invalid direct address")?);
+        } else if !line.contains('=') {
+            let bytes = hex::decode(line).context("invalid nodeid encoding")?;
+            let bytes = bytes
                 .try_into()
-                .map_err(|_| anyhow::anyhow!("invalid utf8 in txt record"))?;
-            let node_id_bytes = hex::decode(&txt_str).context("invalid nodeid encoding")?;
-            let node_id = NodeId::from_bytes(
-                &node_id_bytes
-                    .try_into()
-                    .map_err(|_| anyhow::anyhow!("wrong nodeid length"))?,
-            )
-            .context("invalid nodeid")?;
-            return Ok(node_id);
+                .map_err(|_| anyhow::anyhow!("wrong nodeid length"))?;
+            node_id = Some(NodeId::from_bytes(&bytes).context("invalid nodeid")?);
+        }
+    }
+    let node_id = node_id.context("no nodeid found in rendezvous record")?;
+    Ok(NodeAddr::from_parts(node_id, relay_url, direct))
+}
+
+/// publish where to reach us under the code's key
+pub async fn publish_addr(
+    rendezvous: &dyn Rendezvous,
+    keys: &CodeKeys,
+    addr: &NodeAddr,
+) -> Result<()> {
+    rendezvous.publish(&encode_record(keys, addr)?).await
+}
+
+/// where the endpoint can be reached now, waiting briefly for its
+/// direct addresses if it has only just been bound
+pub async fn local_addr(endpoint: &Endpoint) -> NodeAddr {
+    match timeout(ADDR_WAIT, endpoint.node_addr()).await {
+        Ok(Ok(addr)) => addr,
+        _ => NodeAddr::new(endpoint.node_id()),
+    }
+}
+
+/// publish now, then keep the record alive and up to date with the
+/// endpoint's relay and addresses until the guard is dropped
+pub async fn publish_renewing(
+    rendezvous: Arc<dyn Rendezvous>,
+    keys: CodeKeys,
+    endpoint: &Endpoint,
+) -> Result<Publication> {
+    let mut published = local_addr(endpoint).await;
+    publish_addr(rendezvous.as_ref(), &keys, &published).await?;
+
+    let endpoint = endpoint.clone();
+    let record = Some((rendezvous.clone(), keys.clone()));
+    let task = tokio::spawn(async move {
+        let mut direct = endpoint.direct_addresses();
+        let mut relay = endpoint.home_relay();
+        let mut renew =
+            tokio::time::interval_at(tokio::time::Instant::now() + RENEW_INTERVAL, RENEW_INTERVAL);
+        loop {
+            let changed = tokio::select! {
+                _ = renew.tick() => false,
+                Ok(_) = direct.updated() => true,
+                Ok(_) = relay.updated() => true,
+            };
+            if changed {
+                // addresses tend to change in bursts, publish once they settle
+                tokio::time::sleep(SETTLE).await;
+            }
+            let addr = local_addr(&endpoint).await;
+            if changed && addr == published {
+                continue;
+            }
+            match publish_addr(rendezvous.as_ref(), &keys, &addr).await {
+                Ok(()) => published = addr,
+                Err(e) => eprintln!("republishing code failed: {e}"),
+            }
+        }
+    });
+    Ok(Publication { task, record })
+}
+
+/// a code being kept findable by `task`; dropping it stops the task, and
+/// a dht record then expires within CODE_TTL
+pub struct Publication {
+    task: JoinHandle<()>,
+    /// where the record lives, to tombstone it on revoke
+    record: Option<(Arc<dyn Rendezvous>, CodeKeys)>,
+}
+
+impl Publication {
+    pub fn new(task: JoinHandle<()>) -> Self {
+        Self { task, record: None }
+    }
+
+    /// stop keeping the code findable and tombstone its record, if any
+    pub async fn revoke(mut self) -> Result<()> {
+        self.task.abort();
+        // a republish in flight must not land after the tombstone
+        let _ = (&mut self.task).await;
+        match &self.record {
+            Some((rendezvous, keys)) => rendezvous.revoke(keys).await,
+            None => Ok(()),
         }
     }
+}
 
-    anyhow::bail!("no nodeid found in dht record")
+impl Drop for Publication {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// how many peers may join with one code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JoinLimit {
+    Count(u32),
+    Unlimited,
+}
+
+impl JoinLimit {
+    pub fn reached(self, joined: u32) -> bool {
+        match self {
+            Self::Count(n) => joined >= n,
+            Self::Unlimited => false,
+        }
+    }
+}
+
+impl FromStr for JoinLimit {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "unlimited" {
+            return Ok(Self::Unlimited);
+        }
+        let n: u32 = s.parse().context("expected a number or 'unlimited'")?;
+        anyhow::ensure!(n > 0, "at least one join");
+        Ok(Self::Count(n))
+    }
+}
+
+/// Lifetime of a served word code
+#[derive(Args, Clone, Debug)]
+pub struct CodeArgs {
+    /// Peers that may join with the code, or "unlimited" (until Ctrl-C)
+    #[arg(long, default_value = "1", value_name = "N|unlimited")]
+    pub joins: JoinLimit,
+
+    /// Burn the code after this many failed authentication attempts
+    #[arg(long, default_value = "5", value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_failures: u32,
+}
+
+/// rate limit for failed pake attempts
+///
+/// each attempt costs an attacker one guess at the code, so after every
+/// failure attempts are refused for a doubling backoff, and after
+/// `max_failures` the code is burned for good.
+pub struct AttemptLimiter {
+    failures: u32,
+    max_failures: u32,
+    not_before: Option<Instant>,
+}
+
+impl AttemptLimiter {
+    pub fn new(max_failures: u32) -> Self {
+        Self {
+            failures: 0,
+            max_failures,
+            not_before: None,
+        }
+    }
+
+    /// how long to wait before the next attempt may run, if at all
+    pub fn backoff(&self, now: Instant) -> Option<Duration> {
+        self.not_before
+            .filter(|t| *t > now)
+            .map(|t| t.duration_since(now))
+    }
+
+    /// record a failure; true once the code is burned
+    pub fn failed(&mut self, now: Instant) -> bool {
+        self.failures += 1;
+        let backoff = Duration::from_secs(1 << (self.failures - 1).min(6)).min(MAX_BACKOFF);
+        self.not_before = Some(now + backoff);
+        self.failures >= self.max_failures
+    }
+
+    pub fn failures(&self) -> u32 {
+        self.failures
+    }
+}
+
+/// resolve where to reach the node serving code
+pub async fn resolve_addr(rendezvous: &dyn Rendezvous, keys: &CodeKeys) -> Result<NodeAddr> {
+    let public_key = keys.keypair.public_key();
+
+    let packet = timeout(LOOKUP_TIMEOUT, rendezvous.resolve(&public_key))
+        .await
+        .context("rendezvous lookup timed out")??
+        .ok_or_else(|| anyhow::anyhow!("code not found"))?;
+    decode_record(keys, &packet)
+}
+
+/// what the pake is bound to: both node ids and the tls session
+///
+/// each side fills this in from its own view of the quic connection.
+/// a relaying man-in-the-middle has two separate connections, so the
+/// node ids and exporter secrets differ and key confirmation fails.
+#[derive(Clone, Copy)]
+pub struct PakeBinding {
+    pub server: NodeId,
+    pub client: NodeId,
+    pub exporter: [u8; 32],
+}
+
+impl PakeBinding {
+    /// binding for the server side of `conn`
+    pub fn server(conn: &Connection, local: NodeId) -> Result<Self> {
+        Ok(Self {
+            server: local,
+            client: conn.remote_node_id()?,
+            exporter: tls_exporter(conn)?,
+        })
+    }
+
+    /// binding for the client side of `conn`
+    pub fn client(conn: &Connection, local: NodeId) -> Result<Self> {
+        Ok(Self {
+            server: conn.remote_node_id()?,
+            client: local,
+            exporter: tls_exporter(conn)?,
+        })
+    }
+
+    fn server_identity(&self) -> Vec<u8> {
+        [b"x11q-server:".as_slice(), self.server.as_bytes()].concat()
+    }
+
+    fn client_identity(&self) -> Vec<u8> {
+        [b"x11q-client:".as_slice(), self.client.as_bytes()].concat()
+    }
+}
+
+/// tls keying material exported from the quic session (rfc 5705)
+fn tls_exporter(conn: &Connection) -> Result<[u8; 32]> {
+    let mut out = [0u8; 32];
+    conn.export_keying_material(&mut out, EXPORTER_LABEL, &[])
+        .map_err(|_| anyhow::anyhow!("tls exporter unavailable"))?;
+    Ok(out)
+}
+
+/// spake2 session key plus the binding it was derived under
+pub struct PakeKey {
+    key: [u8; 32],
+    binding: PakeBinding,
+}
+
+impl PakeKey {
+    fn mac(&self, role: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("hmac accepts any key length");
+        mac.update(b"x11q-confirm-v1:");
+        mac.update(role);
+        mac.update(self.binding.server.as_bytes());
+        mac.update(self.binding.client.as_bytes());
+        mac.update(&self.binding.exporter);
+        mac
+    }
+
+    /// confirmation tag sent by the server
+    pub fn server_confirmation(&self) -> [u8; 32] {
+        self.mac(b"server").finalize().into_bytes().into()
+    }
+
+    /// confirmation tag sent by the client
+    pub fn client_confirmation(&self) -> [u8; 32] {
+        self.mac(b"client").finalize().into_bytes().into()
+    }
+
+    /// check the server's tag (constant time)
+    pub fn verify_server(&self, tag: &[u8]) -> Result<()> {
+        self.mac(b"server")
+            .verify_slice(tag)
+            .map_err(|_| anyhow::anyhow!("key confirmation failed - wrong code or mitm"))
+    }
+
+    /// check the client's tag (constant time)
+    pub fn verify_client(&self, tag: &[u8]) -> Result<()> {
+        self.mac(b"client")
+            .verify_slice(tag)
+            .map_err(|_| anyhow::anyhow!("key confirmation failed - wrong code or mitm"))
+    }
+
+    #[cfg(test)]
+    fn key(&self) -> &[u8; 32] {
+        &self.key
+    }
 }
 
 /// spake2 side A (server)
 pub struct PakeServer {
     spake: Spake2<Ed25519Group>,
     outbound_msg: Vec<u8>,
+    binding: PakeBinding,
 }
 
 impl PakeServer {
-    pub fn new(code: &str) -> Self {
+    pub fn new(code: &str, binding: PakeBinding) -> Self {
         let (spake, outbound_msg) = Spake2::<Ed25519Group>::start_a(
             &Password::new(code.as_bytes()),
-            &Identity::new(b"x11q-server"),
-            &Identity::new(b"x11q-client"),
+            &Identity::new(&binding.server_identity()),
+            &Identity::new(&binding.client_identity()),
         );
         Self {
             spake,
             outbound_msg,
+            binding,
         }
     }
 
@@ -380,12 +930,15 @@ impl PakeServer {
         &self.outbound_msg
     }
 
-    pub fn finish(self, client_msg: &[u8]) -> Result<[u8; 32]> {
+    pub fn finish(self, client_msg: &[u8]) -> Result<PakeKey> {
         let key = self
             .spake
             .finish(client_msg)
             .map_err(|_| anyhow::anyhow!("pake failed - wrong code?"))?;
-        Ok(key.try_into().expect("spake2 produces 32 byte key"))
+        Ok(PakeKey {
+            key: key.try_into().expect("spake2 produces 32 byte key"),
+            binding: self.binding,
+        })
     }
 }
 
@@ -393,18 +946,20 @@ impl PakeServer {
 pub struct PakeClient {
     spake: Spake2<Ed25519Group>,
     outbound_msg: Vec<u8>,
+    binding: PakeBinding,
 }
 
 impl PakeClient {
-    pub fn new(code: &str) -> Self {
+    pub fn new(code: &str, binding: PakeBinding) -> Self {
         let (spake, outbound_msg) = Spake2::<Ed25519Group>::start_b(
             &Password::new(code.as_bytes()),
-            &Identity::new(b"x11q-server"),
-            &Identity::new(b"x11q-client"),
+            &Identity::new(&binding.server_identity()),
+            &Identity::new(&binding.client_identity()),
         );
         Self {
             spake,
             outbound_msg,
+            binding,
         }
     }
 
@@ -412,18 +967,94 @@ impl PakeClient {
         &self.outbound_msg
     }
 
-    pub fn finish(self, server_msg: &[u8]) -> Result<[u8; 32]> {
+    pub fn finish(self, server_msg: &[u8]) -> Result<PakeKey> {
         let key = self
             .spake
             .finish(server_msg)
             .map_err(|_| anyhow::anyhow!("pake failed - wrong code?"))?;
-        Ok(key.try_into().expect("spake2 produces 32 byte key"))
+        Ok(PakeKey {
+            key: key.try_into().expect("spake2 produces 32 byte key"),
+            binding: self.binding,
+        })
     }
 }
 
+/// length-prefixed message, as used by the pake exchange
+pub async fn write_msg(send: &mut SendStream, msg: &[u8]) -> Result<()> {
+    send.write_all(&(msg.len() as u32).to_le_bytes()).await?;
+    send.write_all(msg).await?;
+    Ok(())
+}
+
+pub async fn read_msg(recv: &mut RecvStream) -> Result<Vec<u8>> {
+    let mut len_buf = [0u8; 4];
+    recv.read_exact(&mut len_buf).await?;
+    let len = u32::from_le_bytes(len_buf) as usize;
+    anyhow::ensure!(len <= MAX_PAKE_MSG, "pake message too large");
+    let mut msg = vec![0u8; len];
+    recv.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+/// run pake + key confirmation as the serving side
+///
+/// returns only after the client proved knowledge of the code on this
+/// exact connection; nothing else should be accepted before that.
+pub async fn authenticate_server(
+    conn: &Connection,
+    control: &mut Control,
+    local: NodeId,
+    code: &str,
+) -> Result<PakeKey> {
+    let pake = PakeServer::new(code, PakeBinding::server(conn, local)?);
+
+    control.send_auth(pake.message()).await?;
+    let client_msg = control.recv_auth().await?;
+    let key = pake.finish(&client_msg)?;
+
+    control.send_auth(&key.server_confirmation()).await?;
+    let tag = control.recv_auth().await?;
+    key.verify_client(&tag)?;
+
+    Ok(key)
+}
+
+/// run pake + key confirmation as the joining side
+pub async fn authenticate_client(
+    conn: &Connection,
+    control: &mut Control,
+    local: NodeId,
+    code: &str,
+) -> Result<PakeKey> {
+    let pake = PakeClient::new(code, PakeBinding::client(conn, local)?);
+
+    let server_msg = control.recv_auth().await?;
+    control.send_auth(pake.message()).await?;
+    let key = pake.finish(&server_msg)?;
+
+    let tag = control.recv_auth().await?;
+    key.verify_server(&tag)?;
+    control.send_auth(&key.client_confirmation()).await?;
+
+    Ok(key)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::net::SocketAddr;
+
+    fn node(seed: u8) -> NodeId {
+        iroh::SecretKey::from_bytes(&[seed; 32]).public()
+    }
+
+    fn binding(server: u8, client: u8, exporter: u8) -> PakeBinding {
+        PakeBinding {
+            server: node(server),
+            client: node(client),
+            exporter: [exporter; 32],
+        }
+    }
 
     #[test]
     fn test_code_generation() {
@@ -434,38 +1065,212 @@ mod tests {
     }
 
     #[test]
-    fn test_keypair_derivation_deterministic() {
-        let k1 = derive_keypair("7-tiger-lamp");
-        let k2 = derive_keypair("7-tiger-lamp");
-        assert_eq!(k1.public_key().to_z32(), k2.public_key().to_z32());
+    fn test_code_keys_deterministic() {
+        let keys = CodeKeys::derive("7-tiger-lamp");
+        let again = CodeKeys::derive("7-tiger-lamp");
+        assert_eq!(keys.lookup_key(), again.lookup_key());
+        assert_eq!(keys.secret, again.secret);
+
+        let other = CodeKeys::derive("8-tiger-lamp");
+        assert_ne!(keys.lookup_key(), other.lookup_key());
+        assert_ne!(keys.secret, other.secret);
+    }
+
+    #[test]
+    fn test_backend_parse() {
+        assert_eq!("dht".parse::<Backend>().unwrap(), Backend::Dht);
+        assert_eq!(
+            "pkarr:https://relay.pkarr.org".parse::<Backend>().unwrap(),
+            Backend::Pkarr("https://relay.pkarr.org".parse().unwrap())
+        );
+        assert_eq!(
+            "server:http://10.0.0.2:8787".parse::<Backend>().unwrap(),
+            Backend::Server("http://10.0.0.2:8787".parse().unwrap())
+        );
+        assert!("https://relay.pkarr.org".parse::<Backend>().is_err());
+        assert!("server:ftp://10.0.0.2".parse::<Backend>().is_err());
+        assert!("mdns".parse::<Backend>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_publish_resolve_revoke() {
+        let memory = crate::rendezvous_server::Store::default();
+        let (keys, node_id) = (CodeKeys::derive("7-tiger-lamp"), node(1));
+
+        let addr = NodeAddr::new(node_id);
+
+        assert!(resolve_addr(&memory, &keys).await.is_err());
+        publish_addr(&memory, &keys, &addr).await.unwrap();
+        assert_eq!(resolve_addr(&memory, &keys).await.unwrap(), addr);
+        let other = CodeKeys::derive("8-tiger-lamp");
+        assert!(resolve_addr(&memory, &other).await.is_err());
+
+        memory.revoke(&keys).await.unwrap();
+        let err = resolve_addr(&memory, &keys).await.unwrap_err();
+        assert_eq!(err.to_string(), "code already used");
+    }
+
+    #[test]
+    fn test_record_roundtrip() {
+        let keys = CodeKeys::derive("7-tiger-lamp");
+        let addr = NodeAddr::from_parts(
+            node(1),
+            Some("https://relay.example.org".parse().unwrap()),
+            [
+                "192.168.1.5:4433".parse().unwrap(),
+                "[fd00::2]:4433".parse().unwrap(),
+            ],
+        );
+        let packet = encode_record(&keys, &addr).unwrap();
+        assert_eq!(decode_record(&keys, &packet).unwrap(), addr);
+
+        let bare = NodeAddr::new(node(2));
+        let packet = encode_record(&keys, &bare).unwrap();
+        assert_eq!(decode_record(&keys, &packet).unwrap(), bare);
+
+        // a full set of addresses still fits
+        let many = (0..20).map(|i| SocketAddr::from(([10, 0, 0, i], 4433)));
+        let crowded = NodeAddr::from_parts(node(3), addr.relay_url.clone(), many);
+        let packet = encode_record(&keys, &crowded).unwrap();
+        assert_eq!(
+            decode_record(&keys, &packet)
+                .unwrap()
+                .direct_addresses
+                .len(),
+            MAX_ADDRS
+        );
+    }
+
+    #[test]
+    fn test_record_is_sealed() {
+        let keys = CodeKeys::derive("7-tiger-lamp");
+        let addr = NodeAddr::from_parts(
+            node(1),
+            Some("https://relay.example.org".parse().unwrap()),
+            ["192.168.1.5:4433".parse().unwrap()],
+        );
+        let packet = encode_record(&keys, &addr).unwrap();
+        let bytes = packet.as_bytes();
+        for leak in [
+            hex::encode(node(1).as_bytes()),
+            "relay.example.org".into(),
+            "192.168.1.5".into(),
+        ] {
+            assert!(!bytes.windows(leak.len()).any(|w| w == leak.as_bytes()));
+        }
+
+        // same code, same key; another code can't open it
+        let again = CodeKeys::derive("7-tiger-lamp");
+        assert_eq!(again.lookup_key(), keys.lookup_key());
+        assert_eq!(decode_record(&again, &packet).unwrap(), addr);
+        let err = decode_record(&CodeKeys::derive("8-tiger-lamp"), &packet).unwrap_err();
+        assert!(err.to_string().contains("can't be opened"));
+    }
+
+    #[test]
+    fn test_record_unknown_version() {
+        let keys = CodeKeys::derive("7-tiger-lamp");
+        // the old plaintext record: just the node id
+        let plain = hex::encode(node(1).as_bytes());
+        let mut txt = TXT::new();
+        txt.add_string(&plain).unwrap();
+        let packet = SignedPacket::builder()
+            .txt(Name::new("_x11q").unwrap(), txt, CODE_TTL)
+            .sign(&keys.keypair)
+            .unwrap();
+        let err = decode_record(&keys, &packet).unwrap_err();
+        assert!(err.to_string().contains("unknown format"));
     }
 
     #[test]
     fn test_pake_success() {
         let code = "7-tiger-lamp";
+        let b = binding(1, 2, 3);
 
-        let server = PakeServer::new(code);
-        let client = PakeClient::new(code);
+        let server = PakeServer::new(code, b);
+        let client = PakeClient::new(code, b);
+        let server_msg = server.message().to_vec();
 
-        let server_key = server.finish(client.message()).unwrap();
-        let client_key = client.finish(&PakeServer::new(code).message()).unwrap();
+        let sk = server.finish(client.message()).unwrap();
+        let ck = client.finish(&server_msg).unwrap();
+        assert_eq!(sk.key(), ck.key());
 
-        // both derive same key
-        // (need fresh server for the message since we consumed it)
-        let server2 = PakeServer::new(code);
-        let client2 = PakeClient::new(code);
-        let sk = server2.finish(client2.message()).unwrap();
-        let ck = client2.finish(server2.message()).unwrap();
-        assert_eq!(sk, ck);
+        // confirmation tags verify in both directions
+        ck.verify_server(&sk.server_confirmation()).unwrap();
+        sk.verify_client(&ck.client_confirmation()).unwrap();
     }
 
     #[test]
     fn test_pake_wrong_code() {
-        let server = PakeServer::new("7-tiger-lamp");
-        let client = PakeClient::new("8-wrong-code");
+        let b = binding(1, 2, 3);
+        let server = PakeServer::new("7-tiger-lamp", b);
+        let client = PakeClient::new("8-wrong-code", b);
+        let server_msg = server.message().to_vec();
+
+        // spake2 itself doesn't notice, key confirmation does
+        let sk = server.finish(client.message()).unwrap();
+        let ck = client.finish(&server_msg).unwrap();
+        assert!(sk.verify_client(&ck.client_confirmation()).is_err());
+        assert!(ck.verify_server(&sk.server_confirmation()).is_err());
+    }
+
+    #[test]
+    fn test_pake_relayed_mitm_rejected() {
+        // mitm node 9 knows nothing but forwards every message verbatim:
+        // server sees (server=1, client=9), client sees (server=9, client=2),
+        // and each quic connection has its own tls exporter
+        let code = "7-tiger-lamp";
+        let server = PakeServer::new(code, binding(1, 9, 3));
+        let client = PakeClient::new(code, binding(9, 2, 4));
+        let server_msg = server.message().to_vec();
+
+        let sk = server.finish(client.message()).unwrap();
+        let ck = client.finish(&server_msg).unwrap();
+
+        assert!(ck.verify_server(&sk.server_confirmation()).is_err());
+        assert!(sk.verify_client(&ck.client_confirmation()).is_err());
+    }
+
+    #[test]
+    fn test_pake_exporter_mismatch_rejected() {
+        // same node ids but a different tls session still fails
+        let code = "7-tiger-lamp";
+        let server = PakeServer::new(code, binding(1, 2, 3));
+        let client = PakeClient::new(code, binding(1, 2, 4));
+        let server_msg = server.message().to_vec();
+
+        let sk = server.finish(client.message()).unwrap();
+        let ck = client.finish(&server_msg).unwrap();
+        assert!(sk.verify_client(&ck.client_confirmation()).is_err());
+    }
+
+    #[test]
+    fn test_join_limit() {
+        assert_eq!(
+            "unlimited".parse::<JoinLimit>().unwrap(),
+            JoinLimit::Unlimited
+        );
+        assert_eq!("3".parse::<JoinLimit>().unwrap(), JoinLimit::Count(3));
+        assert!("0".parse::<JoinLimit>().is_err());
+        assert!(JoinLimit::Count(2).reached(2));
+        assert!(!JoinLimit::Count(2).reached(1));
+        assert!(!JoinLimit::Unlimited.reached(u32::MAX));
+    }
+
+    #[test]
+    fn test_attempt_limiter_backs_off_and_burns() {
+        let start = Instant::now();
+        let mut limiter = AttemptLimiter::new(3);
+        assert_eq!(limiter.backoff(start), None);
+
+        assert!(!limiter.failed(start));
+        assert_eq!(limiter.backoff(start), Some(Duration::from_secs(1)));
+        assert_eq!(limiter.backoff(start + Duration::from_secs(1)), None);
 
-        // finish should fail
-        let result = server.finish(client.message());
-        assert!(result.is_err());
+        let later = start + Duration::from_secs(5);
+        assert!(!limiter.failed(later));
+        assert_eq!(limiter.backoff(later), Some(Duration::from_secs(2)));
+        assert!(limiter.failed(later));
+        assert_eq!(limiter.failures(), 3);
     }
 }
@@ -0,0 +1,208 @@
+//! rendezvous-server - a self-hosted rendezvous for word codes
+//!
+//! for networks that block the bittorrent dht. it speaks the pkarr relay
+//! protocol (`PUT`/`GET /<z32 key>` with the signed packet as the body),
+//! so `--rendezvous pkarr:URL` works with it too, and keeps records in
+//! memory only until their ttl runs out. every packet is verified before
+//! it is stored and again by whoever resolves it: the server can withhold
+//! records, but not forge or alter them.
+
+use crate::rendezvous::Rendezvous;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use axum::body::Bytes;
+use axum::extract::{DefaultBodyLimit, Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::Router;
+use pkarr::{PublicKey, SignedPacket};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// records live at least this long, whatever ttl they ask for
+const MIN_TTL: u32 = 30;
+const MAX_TTL: u32 = 3600;
+const MAX_RECORDS: usize = 100_000;
+/// signature, timestamp and the largest dns packet pkarr allows
+const MAX_PAYLOAD: usize = 64 + 8 + 1000;
+
+/// Why a record was not stored
+#[derive(Debug, PartialEq)]
+pub enum Rejected {
+    /// a newer record is stored under the same key
+    Stale,
+    Full,
+}
+
+/// Signed records by key, until they expire
+///
+/// this is also the in-memory rendezvous backend used by tests.
+#[derive(Clone, Default)]
+pub struct Store {
+    records: Arc<Mutex<HashMap<PublicKey, SignedPacket>>>,
+}
+
+impl Store {
+    pub fn put(&self, packet: SignedPacket) -> Result<(), Rejected> {
+        let mut records = self.records.lock().unwrap();
+        let key = packet.public_key();
+        if let Some(stored) = records.get(&key) {
+            if !stored.is_expired(MIN_TTL, MAX_TTL) && stored.more_recent_than(&packet) {
+                return Err(Rejected::Stale);
+            }
+        } else if records.len() >= MAX_RECORDS {
+            records.retain(|_, p| !p.is_expired(MIN_TTL, MAX_TTL));
+            if records.len() >= MAX_RECORDS {
+                return Err(Rejected::Full);
+            }
+        }
+        records.insert(key, packet);
+        Ok(())
+    }
+
+    pub fn get(&self, key: &PublicKey) -> Option<SignedPacket> {
+        let mut records = self.records.lock().unwrap();
+        let packet = records.get(key)?;
+        if packet.is_expired(MIN_TTL, MAX_TTL) {
+            records.remove(key);
+            return None;
+        }
+        Some(packet.clone())
+    }
+}
+
+#[async_trait]
+impl Rendezvous for Store {
+    async fn publish(&self, packet: &SignedPacket) -> Result<()> {
+        self.put(packet.clone())
+            .map_err(|e| anyhow::anyhow!("record rejected: {:?}", e))
+    }
+
+    async fn resolve(&self, key: &PublicKey) -> Result<Option<SignedPacket>> {
+        Ok(self.get(key))
+    }
+}
+
+/// Serve the rendezvous on `listen` until interrupted
+pub async fn run_rendezvous_server(listen: SocketAddr) -> Result<()> {
+    let app = router(Store::default());
+    let listener = tokio::net::TcpListener::bind(listen)
+        .await
+        .with_context(|| format!("failed to bind {}", listen))?;
+
+    eprintln!("x11q rendezvous server on http://{}", listen);
+    eprintln!(
+        "use with: x11q serve --rendezvous server:http://HOST:{}",
+        listen.port()
+    );
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+fn router(store: Store) -> Router {
+    Router::new()
+        .route("/{key}", get(get_record).put(put_record))
+        .layer(DefaultBodyLimit::max(MAX_PAYLOAD))
+        .with_state(store)
+}
+
+async fn get_record(State(store): State<Store>, Path(key): Path<String>) -> Response {
+    let Ok(key) = PublicKey::try_from(key.as_str()) else {
+        return (StatusCode::BAD_REQUEST, "invalid key").into_response();
+    };
+    match store.get(&key) {
+        Some(packet) => packet.to_relay_payload().into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+async fn put_record(
+    State(store): State<Store>,
+    Path(key): Path<String>,
+    payload: Bytes,
+) -> Response {
+    let Ok(key) = PublicKey::try_from(key.as_str()) else {
+        return (StatusCode::BAD_REQUEST, "invalid key").into_response();
+    };
+    let Ok(packet) = SignedPacket::from_relay_payload(&key, &payload) else {
+        return (StatusCode::BAD_REQUEST, "invalid signed packet").into_response();
+    };
+    match store.put(packet) {
+        Ok(()) => StatusCode::NO_CONTENT.into_response(),
+        Err(Rejected::Stale) => (StatusCode::CONFLICT, "newer record stored").into_response(),
+        Err(Rejected::Full) => StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rendezvous::{self, Backend};
+    use iroh::{NodeAddr, SecretKey};
+    use pkarr::{Keypair, Timestamp};
+
+    fn packet(keypair: &Keypair, timestamp: u64) -> SignedPacket {
+        SignedPacket::builder()
+            .timestamp(Timestamp::from(timestamp))
+            .sign(keypair)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_store_keeps_newest() {
+        let store = Store::default();
+        let keypair = Keypair::random();
+        let key = keypair.public_key();
+        let now = Timestamp::now().as_u64();
+
+        store.put(packet(&keypair, now)).unwrap();
+        assert_eq!(
+            store.put(packet(&keypair, now - 1_000_000)),
+            Err(Rejected::Stale)
+        );
+        assert_eq!(store.get(&key).unwrap().timestamp().as_u64(), now);
+        store.put(packet(&keypair, now + 1_000_000)).unwrap();
+        assert_eq!(
+            store.get(&key).unwrap().timestamp().as_u64(),
+            now + 1_000_000
+        );
+        assert!(store.get(&Keypair::random().public_key()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_server_roundtrip() {
+        let store = Store::default();
+        let app = router(store.clone());
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let backend: Backend = format!("server:http://{}", addr).parse().unwrap();
+        let server = backend.open().unwrap();
+        let keys = rendezvous::CodeKeys::derive("7-tiger-lamp");
+        let addr = NodeAddr::new(SecretKey::generate(rand::rngs::OsRng).public())
+            .with_direct_addresses(["192.168.1.5:4433".parse().unwrap()]);
+
+        assert!(rendezvous::resolve_addr(server.as_ref(), &keys)
+            .await
+            .is_err());
+        rendezvous::publish_addr(server.as_ref(), &keys, &addr)
+            .await
+            .unwrap();
+        assert_eq!(
+            rendezvous::resolve_addr(server.as_ref(), &keys)
+                .await
+                .unwrap(),
+            addr
+        );
+        assert_eq!(store.records.lock().unwrap().len(), 1);
+
+        server.revoke(&keys).await.unwrap();
+        let err = rendezvous::resolve_addr(server.as_ref(), &keys)
+            .await
+            .unwrap_err();
+        assert_eq!(err.to_string(), "code already used");
+    }
+}
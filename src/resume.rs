@@ -0,0 +1,854 @@
+//! Resumable X11 streams that survive a reconnect
+//!
+//! The dialing side (`join`/`client`) picks a random session id and numbers
+//! its streams. After its StreamHeader, every X11 stream names both, so after
+//! a reconnect the new QUIC stream is attached to the X11 connection that is
+//! still open on each end instead of starting over:
+//!
+//! ```text
+//! resume  [session id: 16][stream id: u64]          dialer -> acceptor
+//! DATA    [1][offset: u64][len: u32][bytes]
+//! ACK     [2][offset: u64]    everything before offset was delivered
+//! END     [3][offset: u64]    the sender has no data at or after offset
+//! ```
+//!
+//! Both ends keep what the other hasn't acknowledged (up to MAX_REPLAY, then
+//! they stop reading their X11 side) and send it again on a new link; the
+//! offsets let the receiver drop what it already had. A stream whose link
+//! doesn't come back within RESUME_WINDOW is closed.
+//!
+//! Resumption is bound to the peer's node id, which iroh authenticates on
+//! every connection, so only the peer that opened a session can resume it.
+
+use crate::control::{self, Control, Negotiated, StreamHeader};
+use anyhow::{Context, Result};
+use iroh::endpoint::{Connection, ConnectionError, ConnectionType};
+use iroh::{Endpoint, NodeAddr, NodeId};
+use rand::RngCore;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, WriteHalf};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
+
+const DATA: u8 = 1;
+const ACK: u8 = 2;
+const END: u8 = 3;
+
+const HEADER_LEN: usize = 24;
+const CHUNK: usize = 64 * 1024;
+const PIPE_SIZE: usize = 256 * 1024;
+/// Unacknowledged bytes kept per direction of a stream
+const MAX_REPLAY: usize = 4 * 1024 * 1024;
+/// Acknowledge at least this often during bulk transfers
+const ACK_EVERY: u64 = 256 * 1024;
+/// How long a stream waits for its link to come back
+pub const RESUME_WINDOW: Duration = Duration::from_secs(120);
+const MAX_REDIAL_DELAY: Duration = Duration::from_secs(10);
+
+pub type SessionId = [u8; 16];
+
+/// False when the peer or we ended the connection on purpose
+pub fn is_transport_loss(reason: &ConnectionError) -> bool {
+    !matches!(
+        reason,
+        ConnectionError::ApplicationClosed(_)
+            | ConnectionError::LocallyClosed
+            | ConnectionError::VersionMismatch
+    )
+}
+
+/// One transport stream currently carrying a resumable stream
+pub struct Link {
+    send: Box<dyn AsyncWrite + Send + Unpin>,
+    recv: Box<dyn AsyncRead + Send + Unpin>,
+}
+
+impl Link {
+    fn new<W, R>(send: W, recv: R) -> Self
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        Self {
+            send: Box::new(send),
+            recv: Box::new(recv),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Frame {
+    Data(u64, Vec<u8>),
+    Ack(u64),
+    End(u64),
+}
+
+impl Frame {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Frame::Data(offset, data) => {
+                let mut buf = Vec::with_capacity(13 + data.len());
+                buf.push(DATA);
+                buf.extend_from_slice(&offset.to_le_bytes());
+                buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                buf.extend_from_slice(data);
+                buf
+            }
+            Frame::Ack(offset) => [&[ACK][..], &offset.to_le_bytes()].concat(),
+            Frame::End(offset) => [&[END][..], &offset.to_le_bytes()].concat(),
+        }
+    }
+}
+
+/// None on a clean end of stream
+async fn read_frame<R: AsyncRead + Unpin>(r: &mut R) -> Result<Option<Frame>> {
+    let mut tag = [0u8; 1];
+    match r.read_exact(&mut tag).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let offset = r.read_u64_le().await?;
+    let frame = match tag[0] {
+        DATA => {
+            let len = r.read_u32_le().await? as usize;
+            anyhow::ensure!(len <= CHUNK, "data frame too large");
+            let mut data = vec![0u8; len];
+            r.read_exact(&mut data).await?;
+            Frame::Data(offset, data)
+        }
+        ACK => Frame::Ack(offset),
+        END => Frame::End(offset),
+        tag => anyhow::bail!("unknown frame {}", tag),
+    };
+    Ok(Some(frame))
+}
+
+/// What we sent and the peer hasn't acknowledged yet
+#[derive(Default)]
+struct Outgoing {
+    acked: u64,
+    replay: VecDeque<u8>,
+    eof: bool,
+}
+
+impl Outgoing {
+    fn sent(&self) -> u64 {
+        self.acked + self.replay.len() as u64
+    }
+
+    fn full(&self) -> bool {
+        self.replay.len() >= MAX_REPLAY
+    }
+
+    fn push(&mut self, data: &[u8]) -> Frame {
+        let frame = Frame::Data(self.sent(), data.to_vec());
+        self.replay.extend(data);
+        frame
+    }
+
+    fn ack(&mut self, offset: u64) -> Result<()> {
+        anyhow::ensure!(offset <= self.sent(), "ack beyond the end of the stream");
+        if offset > self.acked {
+            self.replay.drain(..(offset - self.acked) as usize);
+            self.acked = offset;
+        }
+        Ok(())
+    }
+
+    /// Everything unacknowledged, to send again on a new link
+    fn replay(&self) -> Vec<Frame> {
+        let mut frames = Vec::new();
+        let mut offset = self.acked;
+        let (a, b) = self.replay.as_slices();
+        for chunk in a.chunks(CHUNK).chain(b.chunks(CHUNK)) {
+            frames.push(Frame::Data(offset, chunk.to_vec()));
+            offset += chunk.len() as u64;
+        }
+        if self.eof {
+            frames.push(Frame::End(offset));
+        }
+        frames
+    }
+
+    fn done(&self) -> bool {
+        self.eof && self.replay.is_empty()
+    }
+}
+
+/// What we received, so replayed data is only delivered once
+#[derive(Default)]
+struct Incoming {
+    received: u64,
+    end: Option<u64>,
+}
+
+impl Incoming {
+    /// The part of a DATA frame we haven't seen before
+    fn accept<'a>(&mut self, offset: u64, data: &'a [u8]) -> Result<&'a [u8]> {
+        anyhow::ensure!(
+            offset <= self.received,
+            "stream gap: got offset {}, expected {}",
+            offset,
+            self.received
+        );
+        let skip = ((self.received - offset) as usize).min(data.len());
+        self.received += (data.len() - skip) as u64;
+        Ok(&data[skip..])
+    }
+
+    fn ended(&self) -> bool {
+        self.end == Some(self.received)
+    }
+}
+
+/// Reader and writer tasks of the current link
+struct Active {
+    writer: mpsc::UnboundedSender<Vec<u8>>,
+    frames: mpsc::Receiver<Frame>,
+    reader: JoinHandle<()>,
+}
+
+impl Active {
+    fn start(link: Link) -> Self {
+        let (writer, mut outgoing) = mpsc::unbounded_channel::<Vec<u8>>();
+        let mut send = link.send;
+        tokio::spawn(async move {
+            while let Some(bytes) = outgoing.recv().await {
+                if send.write_all(&bytes).await.is_err() {
+                    return;
+                }
+            }
+            let _ = send.shutdown().await;
+        });
+
+        let (tx, frames) = mpsc::channel(64);
+        let mut recv = link.recv;
+        let reader = tokio::spawn(async move {
+            while let Ok(Some(frame)) = read_frame(&mut recv).await {
+                if tx.send(frame).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            writer,
+            frames,
+            reader,
+        }
+    }
+}
+
+impl Drop for Active {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+/// Queue `frame` on the link; drops the link once its writer has failed
+fn send(link: &mut Option<Active>, frame: &Frame) -> bool {
+    let Some(active) = link else { return false };
+    if active.writer.send(frame.encode()).is_err() {
+        *link = None;
+        return false;
+    }
+    true
+}
+
+async fn next_frame(link: &mut Option<Active>) -> Option<Frame> {
+    match link {
+        Some(active) => active.frames.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Write received data to our side of the pipe, counting what got there
+///
+/// Acks follow this count, so a slow local X11 peer pushes back on the
+/// remote sender instead of growing a queue here.
+async fn deliver(
+    mut pipe: WriteHalf<DuplexStream>,
+    mut chunks: mpsc::UnboundedReceiver<Option<Vec<u8>>>,
+    delivered: watch::Sender<u64>,
+) {
+    let mut open = true;
+    while let Some(chunk) = chunks.recv().await {
+        match chunk {
+            Some(data) => {
+                // once our side is gone the rest is dropped, but still
+                // counted so the peer can finish
+                if open && pipe.write_all(&data).await.is_err() {
+                    open = false;
+                }
+                delivered.send_modify(|n| *n += data.len() as u64);
+            }
+            None => {
+                let _ = pipe.shutdown().await;
+            }
+        }
+    }
+}
+
+/// Pump one stream between `pipe` and whichever link is current
+///
+/// Ends once both directions are finished and acknowledged, or when no
+/// link arrives within RESUME_WINDOW.
+async fn run_stream(pipe: DuplexStream, mut links: mpsc::UnboundedReceiver<Link>) {
+    let (mut pipe_read, pipe_write) = tokio::io::split(pipe);
+    let (chunks, queued) = mpsc::unbounded_channel();
+    let (delivered_tx, mut delivered) = watch::channel(0u64);
+    tokio::spawn(deliver(pipe_write, queued, delivered_tx));
+
+    let mut out = Outgoing::default();
+    let mut inc = Incoming::default();
+    let mut acked_peer = 0u64;
+    let mut shut = false;
+    let mut link: Option<Active> = None;
+    let mut lost_at: Option<Instant> = None;
+    let mut buf = vec![0u8; CHUNK];
+
+    loop {
+        if out.done() && inc.ended() && acked_peer == inc.received {
+            break;
+        }
+        if link.is_some() {
+            lost_at = None;
+        }
+        let deadline = *lost_at.get_or_insert_with(Instant::now) + RESUME_WINDOW;
+
+        tokio::select! {
+            next = links.recv() => {
+                let Some(next) = next else { break };
+                // replaces the old link, if it was still around
+                let mut active = Some(Active::start(next));
+                for frame in out.replay() {
+                    send(&mut active, &frame);
+                }
+                let now = *delivered.borrow();
+                if send(&mut active, &Frame::Ack(now)) {
+                    acked_peer = now;
+                }
+                link = active;
+            }
+            n = pipe_read.read(&mut buf), if !out.eof && !out.full() => {
+                let frame = match n {
+                    Ok(0) | Err(_) => {
+                        out.eof = true;
+                        Frame::End(out.sent())
+                    }
+                    Ok(n) => out.push(&buf[..n]),
+                };
+                send(&mut link, &frame);
+            }
+            frame = next_frame(&mut link) => match frame {
+                Some(Frame::Data(offset, data)) => match inc.accept(offset, &data) {
+                    Ok([]) => {}
+                    Ok(new) => {
+                        let _ = chunks.send(Some(new.to_vec()));
+                    }
+                    Err(e) => {
+                        eprintln!("resume: {e}");
+                        break;
+                    }
+                },
+                Some(Frame::Ack(offset)) => {
+                    if let Err(e) = out.ack(offset) {
+                        eprintln!("resume: {e}");
+                        break;
+                    }
+                }
+                Some(Frame::End(offset)) => inc.end = Some(offset),
+                None => link = None,
+            },
+            Ok(()) = delivered.changed() => {}
+            _ = tokio::time::sleep_until(deadline), if link.is_none() => break,
+        }
+
+        if inc.ended() && !shut {
+            shut = true;
+            let _ = chunks.send(None);
+        }
+        let now = *delivered.borrow();
+        if now > acked_peer
+            && (now - acked_peer >= ACK_EVERY || now == inc.received)
+            && send(&mut link, &Frame::Ack(now))
+        {
+            acked_peer = now;
+        }
+    }
+}
+
+type Streams = Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Link>>>>;
+/// The dialer's streams, with the header each new link starts with
+type Opened = Arc<Mutex<HashMap<u64, (StreamHeader, mpsc::UnboundedSender<Link>)>>>;
+
+/// Start a stream task, returning our end of its pipe
+fn spawn_stream(
+    links: mpsc::UnboundedReceiver<Link>,
+    finished: impl FnOnce() + Send + 'static,
+) -> DuplexStream {
+    let (user, pipe) = tokio::io::duplex(PIPE_SIZE);
+    tokio::spawn(async move {
+        run_stream(pipe, links).await;
+        finished();
+    });
+    user
+}
+
+fn encode_header(session: &SessionId, stream: u64) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[..16].copy_from_slice(session);
+    header[16..].copy_from_slice(&stream.to_le_bytes());
+    header
+}
+
+fn decode_header(header: &[u8; HEADER_LEN]) -> (SessionId, u64) {
+    let mut session = [0u8; 16];
+    session.copy_from_slice(&header[..16]);
+    let stream = u64::from_le_bytes(header[16..].try_into().unwrap());
+    (session, stream)
+}
+
+/// Dialing side of a session: opens streams and redials when the link drops
+pub struct Dialer {
+    session: SessionId,
+    endpoint: Endpoint,
+    addr: NodeAddr,
+    alpn: &'static [u8],
+    header: StreamHeader,
+    negotiated: Negotiated,
+    conn: watch::Sender<Option<Connection>>,
+    control: Mutex<Option<control::Handle>>,
+    streams: Opened,
+    next_stream: AtomicU64,
+    closing: AtomicBool,
+}
+
+impl Dialer {
+    /// Take over `conn` once it is ready, redialing `addr` with the same
+    /// endpoint when it drops; every stream starts with `header` and the
+    /// options it was opened with
+    pub fn new(
+        endpoint: Endpoint,
+        addr: NodeAddr,
+        alpn: &'static [u8],
+        header: StreamHeader,
+        conn: Connection,
+        control: Control,
+    ) -> Arc<Self> {
+        let mut session = [0u8; 16];
+        rand::rngs::OsRng.fill_bytes(&mut session);
+        Arc::new(Self {
+            session,
+            endpoint,
+            addr,
+            alpn,
+            header,
+            negotiated: control.negotiated,
+            control: Mutex::new(Some(control.spawn(conn.clone()))),
+            conn: watch::channel(Some(conn)).0,
+            streams: Opened::default(),
+            next_stream: AtomicU64::new(0),
+            closing: AtomicBool::new(false),
+        })
+    }
+
+    /// The current connection, None while reconnecting
+    pub fn subscribe(&self) -> watch::Receiver<Option<Connection>> {
+        self.conn.subscribe()
+    }
+
+    /// The node id of the acceptor
+    pub fn peer(&self) -> NodeId {
+        self.addr.node_id
+    }
+
+    /// What the session agreed on when it started
+    pub fn negotiated(&self) -> Negotiated {
+        self.negotiated
+    }
+
+    /// How the connection currently reaches the peer
+    pub fn path(&self) -> ConnectionType {
+        self.endpoint
+            .conn_type(self.addr.node_id)
+            .ok()
+            .and_then(|watcher| watcher.get().ok())
+            .unwrap_or(ConnectionType::None)
+    }
+
+    /// Open a stream to the acceptor with the StreamHeader `options`;
+    /// it waits out reconnects on its own
+    pub async fn open(&self, options: u8) -> DuplexStream {
+        let id = self.next_stream.fetch_add(1, Ordering::Relaxed);
+        let header = StreamHeader {
+            options,
+            ..self.header
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.streams
+            .lock()
+            .unwrap()
+            .insert(id, (header, tx.clone()));
+        let streams = Arc::clone(&self.streams);
+        let user = spawn_stream(rx, move || {
+            streams.lock().unwrap().remove(&id);
+        });
+
+        let conn = self.conn.borrow().clone();
+        if let Some(conn) = conn {
+            // on failure keep_alive attaches it after the reconnect
+            if let Ok(link) = self.link(&conn, &header, id).await {
+                let _ = tx.send(link);
+            }
+        }
+        user
+    }
+
+    async fn link(&self, conn: &Connection, header: &StreamHeader, id: u64) -> Result<Link> {
+        let (mut send, recv) = conn.open_bi().await?;
+        send.write_all(&header.encode()).await?;
+        send.write_all(&encode_header(&self.session, id)).await?;
+        Ok(Link::new(send, recv))
+    }
+
+    /// Redial whenever the connection is lost and resume every stream
+    ///
+    /// Returns when the session was closed on purpose, by us or the peer,
+    /// and fails when no reconnect succeeds within RESUME_WINDOW.
+    pub async fn keep_alive(&self) -> Result<()> {
+        loop {
+            let conn = self.conn.borrow().clone().context("not connected")?;
+            let reason = conn.closed().await;
+            if self.closing.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            match reason {
+                ref reason if is_transport_loss(reason) => {}
+                // closed after the peer's goodbye
+                ConnectionError::LocallyClosed => return Ok(()),
+                ConnectionError::ApplicationClosed(close) if close.error_code.into_inner() == 0 => {
+                    eprintln!("session closed by peer");
+                    return Ok(());
+                }
+                reason => anyhow::bail!("connection closed: {reason}"),
+            }
+
+            eprintln!("connection lost ({reason}), reconnecting...");
+            self.conn.send_replace(None);
+            let (conn, control) = self.redial().await?;
+            *self.control.lock().unwrap() = Some(control.spawn(conn.clone()));
+            self.conn.send_replace(Some(conn.clone()));
+
+            let streams: Vec<_> = self
+                .streams
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, (header, tx))| (*id, *header, tx.clone()))
+                .collect();
+            eprintln!("reconnected, resuming {} streams", streams.len());
+            for (id, header, tx) in streams {
+                match self.link(&conn, &header, id).await {
+                    Ok(link) => {
+                        let _ = tx.send(link);
+                    }
+                    Err(e) => eprintln!("failed to resume stream {id}: {e}"),
+                }
+            }
+        }
+    }
+
+    async fn redial(&self) -> Result<(Connection, Control)> {
+        let deadline = Instant::now() + RESUME_WINDOW;
+        let mut delay = Duration::from_secs(1);
+        loop {
+            match tokio::time::timeout_at(deadline, self.dial()).await {
+                Ok(Ok(ready)) => return Ok(ready),
+                Ok(Err(e)) => eprintln!("reconnect failed: {e}"),
+                Err(_) => break,
+            }
+            if Instant::now() + delay >= deadline {
+                break;
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_REDIAL_DELAY);
+        }
+        anyhow::bail!(
+            "could not reconnect within {}s, session lost",
+            RESUME_WINDOW.as_secs()
+        )
+    }
+
+    /// Connect again and wait to be let in: `serve` knows our node id from
+    /// the pake and skips it, `server` checks its allowlist as always
+    async fn dial(&self) -> Result<(Connection, Control)> {
+        let conn = self.endpoint.connect(self.addr.clone(), self.alpn).await?;
+        let mut control = control::open(&conn, self.negotiated.features()).await?;
+        control.ready().await?;
+        Ok((conn, control))
+    }
+
+    /// End the session on purpose, without reconnecting
+    pub async fn close(&self, reason: &str) {
+        self.closing.store(true, Ordering::Relaxed);
+        let control = self.control.lock().unwrap().take();
+        match control {
+            Some(control) => control.goodbye(reason).await,
+            None => {
+                if let Some(conn) = self.conn.borrow().as_ref() {
+                    conn.close(0u32.into(), reason.as_bytes());
+                }
+            }
+        }
+    }
+}
+
+/// A new stream for the acceptor to serve
+pub struct Accepted<T> {
+    pub stream: DuplexStream,
+    /// Shared by every stream of the session
+    pub shared: T,
+}
+
+struct Entry<T> {
+    streams: Streams,
+    shared: T,
+}
+
+type SessionMap<T> = HashMap<(NodeId, SessionId), Entry<T>>;
+
+/// Accepting side: live streams by peer and session, across connections
+pub struct Sessions<T> {
+    inner: Arc<Mutex<SessionMap<T>>>,
+}
+
+impl<T> Clone for Sessions<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Default for Sessions<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::default(),
+        }
+    }
+}
+
+impl<T: Clone + Default + Send + 'static> Sessions<T> {
+    /// Read the header of a stream `peer` opened
+    ///
+    /// Returns None when it resumed a stream that is already being served.
+    pub async fn attach<W, R>(
+        &self,
+        peer: NodeId,
+        send: W,
+        mut recv: R,
+    ) -> Result<Option<Accepted<T>>>
+    where
+        W: AsyncWrite + Send + Unpin + 'static,
+        R: AsyncRead + Send + Unpin + 'static,
+    {
+        let mut header = [0u8; HEADER_LEN];
+        recv.read_exact(&mut header)
+            .await
+            .context("failed to read stream header")?;
+        let (session, id) = decode_header(&header);
+        let link = Link::new(send, recv);
+
+        let mut map = self.inner.lock().unwrap();
+        let entry = map.entry((peer, session)).or_insert_with(|| Entry {
+            streams: Streams::default(),
+            shared: T::default(),
+        });
+        let mut streams = entry.streams.lock().unwrap();
+        let link = match streams.get(&id) {
+            Some(tx) => match tx.send(link) {
+                Ok(()) => return Ok(None),
+                // its task is just finishing, start over
+                Err(mpsc::error::SendError(link)) => link,
+            },
+            None => link,
+        };
+
+        let (tx, rx) = mpsc::unbounded_channel();
+        let _ = tx.send(link);
+        streams.insert(id, tx);
+        drop(streams);
+        let shared = entry.shared.clone();
+        drop(map);
+
+        let sessions = self.clone();
+        let stream = spawn_stream(rx, move || sessions.finished(peer, session, id));
+        Ok(Some(Accepted { stream, shared }))
+    }
+
+    /// End every stream of `peer` now, without waiting for it to resume
+    pub fn end(&self, peer: NodeId) {
+        // their tasks stop once the senders for new links are gone
+        self.inner
+            .lock()
+            .unwrap()
+            .retain(|(node, _), _| *node != peer);
+    }
+
+    fn finished(&self, peer: NodeId, session: SessionId, id: u64) {
+        let mut map = self.inner.lock().unwrap();
+        if let Some(entry) = map.get(&(peer, session)) {
+            let mut streams = entry.streams.lock().unwrap();
+            streams.remove(&id);
+            if streams.is_empty() {
+                drop(streams);
+                map.remove(&(peer, session));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Two stream tasks and the channels to hand them links
+    fn pair() -> (
+        DuplexStream,
+        mpsc::UnboundedSender<Link>,
+        DuplexStream,
+        mpsc::UnboundedSender<Link>,
+    ) {
+        let (a_tx, a_rx) = mpsc::unbounded_channel();
+        let (b_tx, b_rx) = mpsc::unbounded_channel();
+        let a = spawn_stream(a_rx, || {});
+        let b = spawn_stream(b_rx, || {});
+        (a, a_tx, b, b_tx)
+    }
+
+    /// Connect both tasks over a fresh in-memory link
+    fn connect(a: &mpsc::UnboundedSender<Link>, b: &mpsc::UnboundedSender<Link>) {
+        let (x, y) = tokio::io::duplex(4096);
+        let (x_read, x_write) = tokio::io::split(x);
+        let (y_read, y_write) = tokio::io::split(y);
+        a.send(Link::new(x_write, x_read)).ok().unwrap();
+        b.send(Link::new(y_write, y_read)).ok().unwrap();
+    }
+
+    #[test]
+    fn test_frames_encode() {
+        assert_eq!(
+            Frame::Ack(7).encode(),
+            [&[ACK][..], &7u64.to_le_bytes()].concat()
+        );
+        let data = Frame::Data(3, b"xyz".to_vec()).encode();
+        assert_eq!(data.len(), 16);
+        assert_eq!(&data[9..13], &3u32.to_le_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_frames_roundtrip() {
+        let frames = [
+            Frame::Data(5, b"hello".to_vec()),
+            Frame::Ack(9),
+            Frame::End(10),
+        ];
+        let bytes: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+        let mut r = &bytes[..];
+        for frame in frames {
+            assert_eq!(read_frame(&mut r).await.unwrap(), Some(frame));
+        }
+        assert_eq!(read_frame(&mut r).await.unwrap(), None);
+    }
+
+    #[test]
+    fn test_replay_buffer() {
+        let mut out = Outgoing::default();
+        out.push(b"abcd");
+        out.push(b"ef");
+        assert_eq!(out.sent(), 6);
+        out.ack(3).unwrap();
+        assert_eq!(out.replay(), vec![Frame::Data(3, b"def".to_vec())]);
+        // stale acks are harmless, acks past the end are not
+        out.ack(1).unwrap();
+        assert!(out.ack(7).is_err());
+        out.eof = true;
+        out.ack(6).unwrap();
+        assert_eq!(out.replay(), vec![Frame::End(6)]);
+        assert!(out.done());
+    }
+
+    #[test]
+    fn test_incoming_drops_duplicates() {
+        let mut inc = Incoming::default();
+        assert_eq!(inc.accept(0, b"abc").unwrap(), b"abc");
+        // replayed from an older ack: only the new tail is delivered
+        assert_eq!(inc.accept(1, b"bcde").unwrap(), b"de");
+        assert_eq!(inc.accept(2, b"cd").unwrap(), b"");
+        assert!(inc.accept(9, b"x").is_err());
+        inc.end = Some(5);
+        assert!(inc.ended());
+    }
+
+    #[tokio::test]
+    async fn test_stream_resumes_after_link_loss() {
+        let (mut a, a_links, mut b, b_links) = pair();
+        connect(&a_links, &b_links);
+
+        a.write_all(b"before ").await.unwrap();
+        let mut got = [0u8; 7];
+        b.read_exact(&mut got).await.unwrap();
+        assert_eq!(&got, b"before ");
+
+        // links replaced mid-stream: unacknowledged data is sent again
+        connect(&a_links, &b_links);
+        a.write_all(b"after").await.unwrap();
+        b.write_all(b"reply").await.unwrap();
+        connect(&a_links, &b_links);
+
+        let mut got = [0u8; 5];
+        b.read_exact(&mut got).await.unwrap();
+        assert_eq!(&got, b"after");
+        a.read_exact(&mut got).await.unwrap();
+        assert_eq!(&got, b"reply");
+
+        // both ends closing finishes the stream
+        drop(a);
+        let mut rest = Vec::new();
+        b.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_large_transfer_with_reconnects() {
+        let (mut a, a_links, mut b, b_links) = pair();
+        connect(&a_links, &b_links);
+
+        let data: Vec<u8> = (0..3_000_000u32).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+        let writer = tokio::spawn(async move {
+            a.write_all(&data).await.unwrap();
+            a.shutdown().await.unwrap();
+            a
+        });
+
+        let mut got = Vec::new();
+        let mut buf = vec![0u8; 100_000];
+        while got.len() < expected.len() {
+            let n = b.read(&mut buf).await.unwrap();
+            got.extend_from_slice(&buf[..n]);
+            if got.len() % 7 == 0 {
+                connect(&a_links, &b_links);
+            }
+        }
+        assert_eq!(got, expected);
+        assert_eq!(b.read(&mut buf).await.unwrap(), 0);
+        writer.await.unwrap();
+    }
+}
@@ -0,0 +1,159 @@
+//! scrypt (rfc 7914), the memory-hard kdf word codes are stretched with
+//!
+//! a word code has only ~22 bits, so anyone can try them all; scrypt makes
+//! every try cost tens of megabytes and milliseconds instead of one hash,
+//! which keeps a table of all codes from being cheap to build or refresh.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Cost parameters: 2^log_n blocks of 128 * r bytes each, p lanes
+#[derive(Clone, Copy, Debug)]
+pub struct Params {
+    pub log_n: u8,
+    pub r: usize,
+    pub p: usize,
+}
+
+/// Derive `out.len()` bytes from `password` and `salt`
+pub fn scrypt(password: &[u8], salt: &[u8], params: Params, out: &mut [u8]) {
+    let Params { log_n, r, p } = params;
+    let block = 128 * r;
+    let mut b = vec![0u8; p * block];
+    pbkdf2_sha256(password, salt, &mut b);
+    for lane in b.chunks_mut(block) {
+        romix(lane, 1 << log_n, r);
+    }
+    pbkdf2_sha256(password, &b, out);
+}
+
+/// pbkdf2-hmac-sha256 with a single iteration, all scrypt needs
+fn pbkdf2_sha256(password: &[u8], salt: &[u8], out: &mut [u8]) {
+    let mac = Hmac::<Sha256>::new_from_slice(password).expect("hmac accepts any key length");
+    for (i, chunk) in out.chunks_mut(32).enumerate() {
+        let mut mac = mac.clone();
+        mac.update(salt);
+        mac.update(&(i as u32 + 1).to_be_bytes());
+        let t = mac.finalize().into_bytes();
+        chunk.copy_from_slice(&t[..chunk.len()]);
+    }
+}
+
+fn romix(lane: &mut [u8], n: usize, r: usize) {
+    let words = 32 * r;
+    let mut x: Vec<u32> = lane
+        .chunks_exact(4)
+        .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+        .collect();
+    let mut v = vec![0u32; n * words];
+    let mut scratch = vec![0u32; words];
+
+    for i in 0..n {
+        v[i * words..(i + 1) * words].copy_from_slice(&x);
+        block_mix(&mut x, &mut scratch, r);
+    }
+    for _ in 0..n {
+        // integerify: the first word of the last 64-byte block
+        let j = x[words - 16] as usize & (n - 1);
+        for (x, v) in x.iter_mut().zip(&v[j * words..(j + 1) * words]) {
+            *x ^= v;
+        }
+        block_mix(&mut x, &mut scratch, r);
+    }
+
+    for (out, word) in lane.chunks_exact_mut(4).zip(&x) {
+        out.copy_from_slice(&word.to_le_bytes());
+    }
+}
+
+/// scryptBlockMix: salsa20/8 over the 2r blocks of `b`, even outputs
+/// first, then odd ones
+fn block_mix(b: &mut [u32], y: &mut [u32], r: usize) {
+    let mut x = [0u32; 16];
+    x.copy_from_slice(&b[(2 * r - 1) * 16..]);
+    for i in 0..2 * r {
+        for (x, b) in x.iter_mut().zip(&b[i * 16..(i + 1) * 16]) {
+            *x ^= b;
+        }
+        salsa20_8(&mut x);
+        let at = if i % 2 == 0 { i / 2 } else { r + i / 2 };
+        y[at * 16..(at + 1) * 16].copy_from_slice(&x);
+    }
+    b.copy_from_slice(y);
+}
+
+fn salsa20_8(b: &mut [u32; 16]) {
+    let mut x = *b;
+    for _ in 0..4 {
+        // columns, then rows
+        for [a, b, c, d] in [
+            [0, 4, 8, 12],
+            [5, 9, 13, 1],
+            [10, 14, 2, 6],
+            [15, 3, 7, 11],
+            [0, 1, 2, 3],
+            [5, 6, 7, 4],
+            [10, 11, 8, 9],
+            [15, 12, 13, 14],
+        ] {
+            x[b] ^= x[a].wrapping_add(x[d]).rotate_left(7);
+            x[c] ^= x[b].wrapping_add(x[a]).rotate_left(9);
+            x[d] ^= x[c].wrapping_add(x[b]).rotate_left(13);
+            x[a] ^= x[d].wrapping_add(x[c]).rotate_left(18);
+        }
+    }
+    for (b, x) in b.iter_mut().zip(x) {
+        *b = b.wrapping_add(x);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn derive(password: &str, salt: &str, log_n: u8, r: usize, p: usize) -> String {
+        let mut out = [0u8; 64];
+        scrypt(
+            password.as_bytes(),
+            salt.as_bytes(),
+            Params { log_n, r, p },
+            &mut out,
+        );
+        hex::encode(out)
+    }
+
+    #[test]
+    fn test_rfc7914_vectors() {
+        assert_eq!(
+            derive("", "", 4, 1, 1),
+            "77d6576238657b203b19ca42c18a0497f16b4844e3074ae8dfdffa3fede21442\
+             fcd0069ded0948f8326a753a0fc81f17e8d3e0fb2e0d3628cf35e20c38d18906"
+        );
+        assert_eq!(
+            derive("password", "NaCl", 10, 8, 16),
+            "fdbabe1c9d3472007856e7190d01e9fe7c6ad7cbc8237830e77376634b373162\
+             2eaf30d92e22a3886ff109279d9830dac727afb94a83ee6d8360cbdfa2cc0640"
+        );
+    }
+
+    #[test]
+    fn test_salsa20_8() {
+        // rfc 7914 section 8
+        let input = hex::decode(
+            "7e879a214f3ec9867ca940e641718f26baee555b8c61c1b50df846116dcd3b1d\
+             ee24f319df9b3d8514121e4b5ac5aa3276021d2909c74829edebc68db8b8c25e",
+        )
+        .unwrap();
+        let mut b = [0u32; 16];
+        for (b, w) in b.iter_mut().zip(input.chunks_exact(4)) {
+            *b = u32::from_le_bytes(w.try_into().unwrap());
+        }
+        salsa20_8(&mut b);
+        let out: Vec<u8> = b.iter().flat_map(|w| w.to_le_bytes()).collect();
+        assert_eq!(
+            hex::encode(out),
+            "a41f859c6608cc993b81cacb020cef05044b2181a2fd337dfd7b1c6396682f29\
+             b4393168e3c9e6bcfe6bc5b7a06d96bae424cc102c91745c24ad673dc7618f81"
+        );
+    }
+}
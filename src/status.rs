@@ -0,0 +1,817 @@
+//! Live status of a running instance over a local control socket
+//!
+//! `server`, `serve`, `join`, `client` and `mirror-server` each listen on
+//! their own Unix socket in the runtime directory while they run:
+//!
+//! ```text
+//! $XDG_RUNTIME_DIR/x11q/<role>-<pid>.sock    (or /tmp/x11q-<uid>/ without it)
+//! ```
+//!
+//! A client sends one request line and reads one JSON line back:
+//!
+//! ```text
+//! status          -> {"status":{"role":"serve","pid":..,"peers":[..]}}
+//! kick <node>     -> {"kicked":{"role":"serve","pid":..,"peers":[..]}}
+//! ```
+//!
+//! `<node>` is a node id or a prefix of one, like the short ids in the logs.
+//! The directory is private to the user, which is the only access check.
+
+use anyhow::{Context, Result};
+use iroh::endpoint::{Connection, ConnectionType};
+use iroh::{Endpoint, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Close code for a kicked peer, so it doesn't try to resume
+pub const KICKED: u32 = 4;
+#[cfg(unix)]
+const MAX_REQUEST: u64 = 1024;
+
+/// What an instance reports about itself
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    pub role: String,
+    pub pid: u32,
+    pub node_id: String,
+    pub display: String,
+    pub uptime_secs: u64,
+    pub peers: Vec<PeerReport>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PeerReport {
+    pub node_id: String,
+    /// False while a lost connection may still be resumed
+    pub connected: bool,
+    /// direct, relay, mixed or none
+    pub path: String,
+    pub remote_addr: Option<String>,
+    pub relay: Option<String>,
+    pub rtt_ms: Option<f64>,
+    /// QUIC packets on the current path, lost ones included
+    pub sent_packets: u64,
+    pub lost_packets: u64,
+    pub connected_secs: u64,
+    pub streams: Vec<StreamReport>,
+}
+
+/// One forwarded X11 stream; `in` is what came from the peer
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StreamReport {
+    pub id: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub open_secs: u64,
+}
+
+/// Peers kicked from one instance
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Kicked {
+    pub role: String,
+    pub pid: u32,
+    pub peers: Vec<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Status(Report),
+    Kicked(Kicked),
+    Error(String),
+}
+
+#[derive(Default)]
+struct Counters {
+    received: AtomicU64,
+    sent: AtomicU64,
+}
+
+/// Running totals since the instance started, for `--metrics`
+#[derive(Default)]
+pub struct Totals {
+    pub streams: AtomicU64,
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub pake_failures: AtomicU64,
+    pub mirror_frames: AtomicU64,
+    /// Compressed size of the mirror frames sent
+    pub mirror_bytes: AtomicU64,
+}
+
+struct StreamEntry {
+    counters: Arc<Counters>,
+    opened: Instant,
+}
+
+struct Peer {
+    conn: Option<Connection>,
+    since: Instant,
+    streams: HashMap<u64, StreamEntry>,
+}
+
+#[derive(Default)]
+struct State {
+    peers: HashMap<NodeId, Peer>,
+    kicked: HashSet<NodeId>,
+    next_stream: u64,
+}
+
+impl State {
+    /// Forget a peer once it has neither a connection nor streams
+    fn prune(&mut self, peer: NodeId) {
+        if self
+            .peers
+            .get(&peer)
+            .is_some_and(|p| p.conn.is_none() && p.streams.is_empty())
+        {
+            self.peers.remove(&peer);
+        }
+    }
+}
+
+/// Peers and traffic of this instance, shared with its control socket
+#[derive(Clone)]
+pub struct Status {
+    role: &'static str,
+    display: String,
+    endpoint: Endpoint,
+    started: Instant,
+    state: Arc<Mutex<State>>,
+    totals: Arc<Totals>,
+}
+
+impl Status {
+    pub fn new(role: &'static str, display: String, endpoint: &Endpoint) -> Self {
+        Self {
+            role,
+            display,
+            endpoint: endpoint.clone(),
+            started: Instant::now(),
+            state: Arc::default(),
+            totals: Arc::default(),
+        }
+    }
+
+    pub fn totals(&self) -> &Totals {
+        &self.totals
+    }
+
+    pub fn pake_failed(&self) {
+        self.totals.pake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A mirror frame went out, `bytes` after compression
+    pub fn mirror_frame(&self, bytes: usize) {
+        self.totals.mirror_frames.fetch_add(1, Ordering::Relaxed);
+        self.totals
+            .mirror_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Track `conn` as its peer's current connection until the guard drops
+    pub fn connected(&self, conn: &Connection) -> Result<Tracked> {
+        let peer = conn.remote_node_id()?;
+        let mut state = self.state.lock().unwrap();
+        let entry = state.peers.entry(peer).or_insert_with(|| Peer {
+            conn: None,
+            since: Instant::now(),
+            streams: HashMap::new(),
+        });
+        if entry.conn.is_none() {
+            entry.since = Instant::now();
+        }
+        entry.conn = Some(conn.clone());
+        Ok(Tracked {
+            status: self.clone(),
+            peer,
+            conn: conn.stable_id(),
+        })
+    }
+
+    /// Count the traffic of a new X11 stream with `peer`
+    pub fn stream(&self, peer: NodeId) -> Traffic {
+        let counters = Arc::new(Counters::default());
+        self.totals.streams.fetch_add(1, Ordering::Relaxed);
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_stream;
+        state.next_stream += 1;
+        let entry = state.peers.entry(peer).or_insert_with(|| Peer {
+            conn: None,
+            since: Instant::now(),
+            streams: HashMap::new(),
+        });
+        entry.streams.insert(
+            id,
+            StreamEntry {
+                counters: Arc::clone(&counters),
+                opened: Instant::now(),
+            },
+        );
+        Traffic {
+            status: self.clone(),
+            peer,
+            id,
+            counters,
+            totals: Arc::clone(&self.totals),
+        }
+    }
+
+    /// Whether `peer` was kicked; it is refused until the instance restarts
+    pub fn kicked(&self, peer: NodeId) -> bool {
+        self.state.lock().unwrap().kicked.contains(&peer)
+    }
+
+    /// Disconnect the peers whose node id starts with `prefix`
+    pub fn kick(&self, prefix: &str) -> Result<Kicked> {
+        anyhow::ensure!(!prefix.is_empty(), "no node id given");
+        let mut state = self.state.lock().unwrap();
+        let matches: Vec<NodeId> = state
+            .peers
+            .keys()
+            .filter(|id| id.to_string().starts_with(prefix))
+            .copied()
+            .collect();
+        anyhow::ensure!(
+            matches.len() <= 1,
+            "'{}' matches {} peers, use more of the node id",
+            prefix,
+            matches.len()
+        );
+        for peer in &matches {
+            state.kicked.insert(*peer);
+            if let Some(conn) = state.peers.get(peer).and_then(|p| p.conn.as_ref()) {
+                conn.close(KICKED.into(), b"kicked");
+            }
+        }
+        Ok(Kicked {
+            role: self.role.to_string(),
+            pid: std::process::id(),
+            peers: matches.iter().map(|id| id.to_string()).collect(),
+        })
+    }
+
+    pub fn report(&self) -> Report {
+        let state = self.state.lock().unwrap();
+        let mut peers: Vec<PeerReport> = state
+            .peers
+            .iter()
+            .map(|(id, peer)| self.peer_report(*id, peer))
+            .collect();
+        peers.sort_by(|a, b| a.node_id.cmp(&b.node_id));
+        Report {
+            role: self.role.to_string(),
+            pid: std::process::id(),
+            node_id: self.endpoint.node_id().to_string(),
+            display: self.display.clone(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            peers,
+        }
+    }
+
+    fn peer_report(&self, id: NodeId, peer: &Peer) -> PeerReport {
+        let path = self
+            .endpoint
+            .conn_type(id)
+            .ok()
+            .and_then(|watcher| watcher.get().ok())
+            .unwrap_or(ConnectionType::None);
+        let (name, remote_addr, relay) = match path {
+            ConnectionType::Direct(addr) => ("direct", Some(addr.to_string()), None),
+            ConnectionType::Relay(url) => ("relay", None, Some(url.to_string())),
+            ConnectionType::Mixed(addr, url) => {
+                ("mixed", Some(addr.to_string()), Some(url.to_string()))
+            }
+            ConnectionType::None => ("none", None, None),
+        };
+        let mut streams: Vec<StreamReport> = peer
+            .streams
+            .iter()
+            .map(|(id, stream)| StreamReport {
+                id: *id,
+                bytes_in: stream.counters.received.load(Ordering::Relaxed),
+                bytes_out: stream.counters.sent.load(Ordering::Relaxed),
+                open_secs: stream.opened.elapsed().as_secs(),
+            })
+            .collect();
+        streams.sort_by_key(|s| s.id);
+        let stats = peer.conn.as_ref().map(|c| c.stats().path);
+        PeerReport {
+            node_id: id.to_string(),
+            connected: peer.conn.is_some(),
+            path: name.to_string(),
+            remote_addr,
+            relay,
+            rtt_ms: stats.map(|s| s.rtt.as_secs_f64() * 1000.0),
+            sent_packets: stats.map_or(0, |s| s.sent_packets),
+            lost_packets: stats.map_or(0, |s| s.lost_packets),
+            connected_secs: peer.since.elapsed().as_secs(),
+            streams,
+        }
+    }
+
+    fn respond(&self, request: &str) -> Response {
+        let request = request.trim();
+        match request.split_once(' ') {
+            None if request == "status" => Response::Status(self.report()),
+            Some(("kick", node)) => match self.kick(node.trim()) {
+                Ok(kicked) => Response::Kicked(kicked),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            _ => Response::Error(format!("unknown request '{}'", request)),
+        }
+    }
+
+    /// Answer requests on this instance's control socket until the guard drops
+    #[cfg(unix)]
+    pub fn listen(&self) -> Option<Socket> {
+        match Socket::bind(self) {
+            Ok(socket) => Some(socket),
+            Err(e) => {
+                eprintln!("status socket unavailable: {e:#}");
+                None
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn listen(&self) -> Option<Socket> {
+        None
+    }
+}
+
+/// A peer's connection, tracked while this lives
+pub struct Tracked {
+    status: Status,
+    peer: NodeId,
+    conn: usize,
+}
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        let mut state = self.status.state.lock().unwrap();
+        let Some(peer) = state.peers.get_mut(&self.peer) else {
+            return;
+        };
+        // a reconnect may already have replaced it
+        if peer
+            .conn
+            .as_ref()
+            .is_some_and(|c| c.stable_id() == self.conn)
+        {
+            peer.conn = None;
+        }
+        state.prune(self.peer);
+    }
+}
+
+/// Byte counters of one X11 stream, listed while this lives
+pub struct Traffic {
+    status: Status,
+    peer: NodeId,
+    id: u64,
+    counters: Arc<Counters>,
+    totals: Arc<Totals>,
+}
+
+impl Traffic {
+    fn received(&self, n: u64) {
+        self.counters.received.fetch_add(n, Ordering::Relaxed);
+        self.totals.bytes_in.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn sent(&self, n: u64) {
+        self.counters.sent.fetch_add(n, Ordering::Relaxed);
+        self.totals.bytes_out.fetch_add(n, Ordering::Relaxed);
+    }
+}
+
+impl Drop for Traffic {
+    fn drop(&mut self) {
+        let mut state = self.status.state.lock().unwrap();
+        if let Some(peer) = state.peers.get_mut(&self.peer) {
+            peer.streams.remove(&self.id);
+        }
+        state.prune(self.peer);
+    }
+}
+
+/// The peer's end of an X11 stream, counting what goes through it
+pub struct Counted<S> {
+    inner: S,
+    traffic: Traffic,
+}
+
+impl<S> Counted<S> {
+    pub fn new(inner: S, traffic: Traffic) -> Self {
+        Self { inner, traffic }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let before = buf.filled().len();
+        let polled = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.traffic.received((buf.filled().len() - before) as u64);
+        polled
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let polled = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = polled {
+            self.traffic.sent(n as u64);
+        }
+        polled
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Directory holding the control sockets, private to the user
+pub fn socket_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("x11q"),
+        #[cfg(unix)]
+        None => std::env::temp_dir().join(format!("x11q-{}", unsafe { libc::getuid() })),
+        #[cfg(not(unix))]
+        None => std::env::temp_dir().join("x11q"),
+    }
+}
+
+/// The listening control socket, removed when dropped
+#[cfg(unix)]
+pub struct Socket {
+    path: PathBuf,
+    task: tokio::task::JoinHandle<()>,
+}
+
+#[cfg(not(unix))]
+pub struct Socket;
+
+#[cfg(unix)]
+impl Socket {
+    fn bind(status: &Status) -> Result<Self> {
+        let dir = socket_dir();
+        private_dir(&dir)?;
+        let path = dir.join(format!("{}-{}.sock", status.role, std::process::id()));
+        // a previous process with our pid can't still be running
+        let _ = std::fs::remove_file(&path);
+        let listener = tokio::net::UnixListener::bind(&path)
+            .with_context(|| format!("failed to bind {}", path.display()))?;
+        crate::xlock::remove_on_signal(&path);
+
+        let status = status.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let status = status.clone();
+                tokio::spawn(async move {
+                    let _ = answer(stream, &status).await;
+                });
+            }
+        });
+        Ok(Self { path, task })
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Socket {
+    fn drop(&mut self) {
+        self.task.abort();
+        crate::xlock::forget(&self.path);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+async fn answer(stream: tokio::net::UnixStream, status: &Status) -> Result<()> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+
+    let (read, mut write) = stream.into_split();
+    let mut request = String::new();
+    BufReader::new(read.take(MAX_REQUEST))
+        .read_line(&mut request)
+        .await?;
+    let mut response = serde_json::to_vec(&status.respond(&request))?;
+    response.push(b'\n');
+    write.write_all(&response).await?;
+    Ok(())
+}
+
+/// Create `dir` for our sockets, refusing one another user could get into
+#[cfg(unix)]
+fn private_dir(dir: &std::path::Path) -> Result<()> {
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("failed to create {}", dir.display())),
+    }
+    let meta = std::fs::symlink_metadata(dir)?;
+    anyhow::ensure!(
+        meta.is_dir() && meta.uid() == unsafe { libc::getuid() } && meta.mode() & 0o077 == 0,
+        "{} is not a private directory of ours",
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Send `request` to every running instance, in pid order
+///
+/// Sockets left behind by an instance that was killed are removed.
+#[cfg(unix)]
+pub async fn query_all(request: &str) -> Result<Vec<Response>> {
+    let mut paths: Vec<(u32, PathBuf)> = match std::fs::read_dir(socket_dir()) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter_map(|path| Some((socket_pid(&path)?, path)))
+            .collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e).context("failed to list running instances"),
+    };
+    paths.sort();
+
+    let mut responses = Vec::new();
+    for (_, path) in paths {
+        match query(&path, request).await {
+            Ok(response) => responses.push(response),
+            Err(e) if is_stale(&e) => {
+                let _ = std::fs::remove_file(&path);
+            }
+            Err(e) => eprintln!("{}: {e:#}", path.display()),
+        }
+    }
+    Ok(responses)
+}
+
+/// The pid in a `<role>-<pid>.sock` name
+#[cfg(unix)]
+fn socket_pid(path: &std::path::Path) -> Option<u32> {
+    let name = path.file_name()?.to_str()?.strip_suffix(".sock")?;
+    name.rsplit_once('-')?.1.parse().ok()
+}
+
+#[cfg(unix)]
+fn is_stale(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .is_some_and(|e| e.kind() == std::io::ErrorKind::ConnectionRefused)
+}
+
+#[cfg(unix)]
+async fn query(path: &std::path::Path, request: &str) -> Result<Response> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let mut stream = tokio::net::UnixStream::connect(path).await?;
+    stream
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await?;
+    serde_json::from_slice(&response).context("invalid response")
+}
+
+/// `x11q status`
+#[cfg(unix)]
+pub async fn run_status(json: bool) -> Result<()> {
+    let reports: Vec<Report> = query_all("status")
+        .await?
+        .into_iter()
+        .filter_map(|response| match response {
+            Response::Status(report) => Some(report),
+            _ => None,
+        })
+        .collect();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&reports)?);
+        return Ok(());
+    }
+    if reports.is_empty() {
+        println!("no x11q running");
+    }
+    for report in &reports {
+        print!("{}", describe(report));
+    }
+    Ok(())
+}
+
+/// `x11q kick NODE`: disconnect a peer from every instance it is on
+#[cfg(unix)]
+pub async fn run_kick(node: &str, json: bool) -> Result<()> {
+    // a paired peer's name works too
+    let node = match crate::authorized::resolve_peer(node) {
+        Ok(id) => id.to_string(),
+        Err(_) => node.to_string(),
+    };
+    let mut kicked = Vec::new();
+    for response in query_all(&format!("kick {}", node)).await? {
+        match response {
+            Response::Kicked(k) if !k.peers.is_empty() => kicked.push(k),
+            Response::Error(e) => anyhow::bail!(e),
+            _ => {}
+        }
+    }
+    if json {
+        println!("{}", serde_json::to_string_pretty(&kicked)?);
+    }
+    anyhow::ensure!(!kicked.is_empty(), "no running x11q has peer '{}'", node);
+    if !json {
+        for k in &kicked {
+            for peer in &k.peers {
+                println!("kicked {} from {} (pid {})", &peer[..8], k.role, k.pid);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Human readable form of a report
+fn describe(report: &Report) -> String {
+    let mut out = format!(
+        "{} {} (pid {}), node {}, up {}\n",
+        report.role,
+        report.display,
+        report.pid,
+        &report.node_id[..8.min(report.node_id.len())],
+        duration(report.uptime_secs)
+    );
+    if report.peers.is_empty() {
+        out.push_str("  no peers\n");
+    }
+    for peer in &report.peers {
+        let path = match (&peer.remote_addr, &peer.relay) {
+            (Some(addr), Some(relay)) => format!("{} {} via {}", peer.path, addr, relay),
+            (Some(addr), None) => format!("{} {}", peer.path, addr),
+            (None, Some(relay)) => format!("{} {}", peer.path, relay),
+            (None, None) => peer.path.clone(),
+        };
+        let state = match peer.rtt_ms {
+            Some(rtt) if peer.connected => format!("rtt {:.1}ms", rtt),
+            _ => "reconnecting".to_string(),
+        };
+        out.push_str(&format!(
+            "  {}  {}  {}  for {}\n",
+            &peer.node_id[..8.min(peer.node_id.len())],
+            path,
+            state,
+            duration(peer.connected_secs)
+        ));
+        for stream in &peer.streams {
+            out.push_str(&format!(
+                "    x11 stream {}  in {}  out {}  open {}\n",
+                stream.id,
+                bytes(stream.bytes_in),
+                bytes(stream.bytes_out),
+                duration(stream.open_secs)
+            ));
+        }
+    }
+    out
+}
+
+fn duration(secs: u64) -> String {
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m{}s", secs / 60, secs % 60),
+        _ => format!("{}h{}m", secs / 3600, secs / 60 % 60),
+    }
+}
+
+fn bytes(n: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if n < 1024 {
+        return format!("{} B", n);
+    }
+    let mut value = n as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn report() -> Report {
+        Report {
+            role: "serve".to_string(),
+            pid: 4242,
+            node_id: "3e9c6243427ba9f6".to_string(),
+            display: ":0".to_string(),
+            uptime_secs: 3725,
+            peers: vec![PeerReport {
+                node_id: "f884703f0ec5aa01".to_string(),
+                connected: true,
+                path: "direct".to_string(),
+                remote_addr: Some("192.168.1.5:41234".to_string()),
+                relay: None,
+                rtt_ms: Some(3.25),
+                sent_packets: 1000,
+                lost_packets: 3,
+                connected_secs: 75,
+                streams: vec![StreamReport {
+                    id: 0,
+                    bytes_in: 2048,
+                    bytes_out: 5 * 1024 * 1024,
+                    open_secs: 12,
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn test_response_json_roundtrip() {
+        let response = Response::Status(report());
+        let json = serde_json::to_string(&response).unwrap();
+        assert!(json.starts_with(r#"{"status":{"role":"serve","pid":4242"#));
+        assert_eq!(serde_json::from_str::<Response>(&json).unwrap(), response);
+
+        let error = Response::Error("nope".to_string());
+        assert_eq!(
+            serde_json::to_string(&error).unwrap(),
+            r#"{"error":"nope"}"#
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        assert_eq!(
+            describe(&report()),
+            "serve :0 (pid 4242), node 3e9c6243, up 1h2m\n\
+             \x20 f884703f  direct 192.168.1.5:41234  rtt 3.2ms  for 1m15s\n\
+             \x20   x11 stream 0  in 2.0 KiB  out 5.0 MiB  open 12s\n"
+        );
+    }
+
+    #[test]
+    fn test_socket_pid() {
+        let pid = |p: &str| socket_pid(std::path::Path::new(p));
+        assert_eq!(pid("/run/user/1000/x11q/serve-4242.sock"), Some(4242));
+        assert_eq!(pid("/run/user/1000/x11q/mirror-server-7.sock"), Some(7));
+        assert_eq!(pid("/run/user/1000/x11q/serve-4242.lock"), None);
+        assert_eq!(pid("/run/user/1000/x11q/notes"), None);
+    }
+
+    #[tokio::test]
+    async fn test_streams_counted_and_pruned() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let endpoint = Endpoint::builder()
+            .relay_mode(iroh::RelayMode::Disabled)
+            .bind()
+            .await
+            .unwrap();
+        let status = Status::new("server", ":0".to_string(), &endpoint);
+        let peer = iroh::SecretKey::generate(rand::rngs::OsRng).public();
+
+        let (a, mut b) = tokio::io::duplex(64);
+        let mut counted = Counted::new(a, status.stream(peer));
+        counted.write_all(b"hello").await.unwrap();
+        b.write_all(b"hi").await.unwrap();
+        let mut buf = [0u8; 2];
+        counted.read_exact(&mut buf).await.unwrap();
+
+        let report = status.report();
+        assert_eq!(report.peers.len(), 1);
+        let stream = &report.peers[0].streams[0];
+        assert_eq!((stream.bytes_in, stream.bytes_out), (2, 5));
+        assert!(!report.peers[0].connected);
+
+        assert!(status.kick("").is_err());
+        let kicked = status.kick(&peer.to_string()[..8]).unwrap();
+        assert_eq!(kicked.peers, vec![peer.to_string()]);
+        assert!(status.kicked(peer));
+
+        drop(counted);
+        assert!(status.report().peers.is_empty());
+        endpoint.close().await;
+    }
+}
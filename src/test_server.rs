@@ -6,9 +6,8 @@
 use anyhow::Result;
 use std::collections::HashMap;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::UnixListener;
 
-const X11_UNIX_DIR: &str = "/tmp/.X11-unix";
+use crate::xlock::{DisplayLock, DisplayNumber};
 
 // Extension major opcodes
 const RANDR_MAJOR_OPCODE: u8 = 140;
@@ -1165,16 +1164,13 @@ async fn handle_client(mut stream: tokio::net::UnixStream, client_id: u32) {
     eprintln!("[{}] client disconnected", client_id);
 }
 
-pub async fn run_test_server(display_num: u32) -> Result<()> {
+pub async fn run_test_server(display: DisplayNumber) -> Result<()> {
     use std::sync::atomic::{AtomicU32, Ordering};
     static CLIENT_COUNTER: AtomicU32 = AtomicU32::new(0);
 
-    let socket_path = format!("{}/X{}", X11_UNIX_DIR, display_num);
-    let _ = std::fs::remove_file(&socket_path);
-    std::fs::create_dir_all(X11_UNIX_DIR)?;
-
-    let listener = UnixListener::bind(&socket_path)?;
-    eprintln!("test x11 server on DISPLAY=:{}", display_num);
+    let lock = DisplayLock::acquire(display)?;
+    let listener = lock.listen()?;
+    eprintln!("test x11 server on DISPLAY=:{}", lock.display_num);
 
     loop {
         let (stream, _) = listener.accept().await?;
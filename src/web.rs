@@ -14,13 +14,12 @@ use futures_util::{SinkExt, StreamExt};
 use std::net::SocketAddr;
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::net::{TcpListener, TcpStream, UnixStream};
 use tokio::sync::{mpsc, Mutex};
 use tower_http::cors::CorsLayer;
 use tower_http::services::ServeDir;
 
-const X11_UNIX_DIR: &str = "/tmp/.X11-unix";
-const X11_TCP_BASE: u16 = 6000;
+use crate::xlock::{self, DisplayLock, DisplayNumber};
 
 /// Shared state for single-client X11 connection
 struct X11Bridge {
@@ -48,15 +47,15 @@ impl X11Bridge {
 }
 
 /// Run web server mode - serves x11q-web and bridges X11 connections
-pub async fn run_web(display_num: u32, port: u16, www_path: Option<&str>) -> Result<()> {
+pub async fn run_web(display: DisplayNumber, port: u16, www_path: Option<&str>) -> Result<()> {
     // Create X11 listeners for this display
-    let x11_socket = format!("{}/X{}", X11_UNIX_DIR, display_num);
-    let _ = std::fs::remove_file(&x11_socket);
-    std::fs::create_dir_all(X11_UNIX_DIR).ok();
+    let lock = DisplayLock::acquire(display)?;
+    let display_num = lock.display_num;
+    let x11_socket = lock.socket_path();
 
-    let unix_listener = UnixListener::bind(&x11_socket).context("failed to create X11 socket")?;
+    let unix_listener = lock.listen()?;
 
-    let tcp_port = X11_TCP_BASE + display_num as u16;
+    let tcp_port = xlock::tcp_port(display_num);
     let tcp_listener = TcpListener::bind(format!("127.0.0.1:{}", tcp_port))
         .await
         .context("failed to bind X11 TCP port")?;
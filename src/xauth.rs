@@ -0,0 +1,520 @@
+//! X11 connection setup parsing and MIT-MAGIC-COOKIE-1 authentication
+//!
+//! Every X11 connection starts with a setup request carrying an auth
+//! protocol name and data. The join side checks it against a per-session
+//! cookie before anything reaches QUIC, and strips it so the remote end
+//! never sees a local secret. The serve side then fills in the cookie
+//! for its own X server from the local Xauthority file.
+
+use anyhow::{Context, Result};
+use rand::RngCore;
+use std::path::{Path, PathBuf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+pub const MIT_MAGIC_COOKIE: &[u8] = b"MIT-MAGIC-COOKIE-1";
+const COOKIE_LEN: usize = 16;
+
+// Xauthority address families (see Xauth.h)
+const FAMILY_INTERNET: u16 = 0;
+const FAMILY_INTERNET6: u16 = 6;
+const FAMILY_LOCAL: u16 = 256;
+const FAMILY_WILD: u16 = 65535;
+
+/// Client connection setup request
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetupRequest {
+    /// b'l' (little endian) or b'B' (big endian)
+    pub byte_order: u8,
+    pub major: u16,
+    pub minor: u16,
+    pub auth_name: Vec<u8>,
+    pub auth_data: Vec<u8>,
+}
+
+fn pad4(n: usize) -> usize {
+    (4 - n % 4) % 4
+}
+
+impl SetupRequest {
+    fn u16(&self, b: [u8; 2]) -> u16 {
+        if self.byte_order == b'B' {
+            u16::from_be_bytes(b)
+        } else {
+            u16::from_le_bytes(b)
+        }
+    }
+
+    fn put_u16(&self, out: &mut Vec<u8>, v: u16) {
+        if self.byte_order == b'B' {
+            out.extend_from_slice(&v.to_be_bytes());
+        } else {
+            out.extend_from_slice(&v.to_le_bytes());
+        }
+    }
+
+    /// Parse a complete setup request
+    ///
+    /// Returns `Ok(None)` if `buf` doesn't hold the whole request yet,
+    /// otherwise the request and the number of bytes it occupies.
+    pub fn parse(buf: &[u8]) -> Result<Option<(Self, usize)>> {
+        if buf.len() < 12 {
+            return Ok(None);
+        }
+        anyhow::ensure!(
+            buf[0] == b'l' || buf[0] == b'B',
+            "bad x11 byte order {:#x}",
+            buf[0]
+        );
+
+        let mut req = SetupRequest {
+            byte_order: buf[0],
+            major: 0,
+            minor: 0,
+            auth_name: Vec::new(),
+            auth_data: Vec::new(),
+        };
+        req.major = req.u16([buf[2], buf[3]]);
+        req.minor = req.u16([buf[4], buf[5]]);
+        let name_len = req.u16([buf[6], buf[7]]) as usize;
+        let data_len = req.u16([buf[8], buf[9]]) as usize;
+
+        let name_start = 12;
+        let data_start = name_start + name_len + pad4(name_len);
+        let total = data_start + data_len + pad4(data_len);
+        if buf.len() < total {
+            return Ok(None);
+        }
+
+        req.auth_name = buf[name_start..name_start + name_len].to_vec();
+        req.auth_data = buf[data_start..data_start + data_len].to_vec();
+        Ok(Some((req, total)))
+    }
+
+    /// Read a setup request from `stream`
+    ///
+    /// Also returns any bytes the client already sent after it.
+    pub async fn read<S: AsyncRead + Unpin>(stream: &mut S) -> Result<(Self, Vec<u8>)> {
+        let mut buf = Vec::with_capacity(64);
+        let mut chunk = [0u8; 512];
+        loop {
+            if let Some((req, len)) = Self::parse(&buf)? {
+                return Ok((req, buf.split_off(len)));
+            }
+            let n = stream.read(&mut chunk).await?;
+            anyhow::ensure!(n > 0, "connection closed during x11 setup");
+            buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = vec![self.byte_order, 0];
+        self.put_u16(&mut out, self.major);
+        self.put_u16(&mut out, self.minor);
+        self.put_u16(&mut out, self.auth_name.len() as u16);
+        self.put_u16(&mut out, self.auth_data.len() as u16);
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&self.auth_name);
+        out.resize(out.len() + pad4(self.auth_name.len()), 0);
+        out.extend_from_slice(&self.auth_data);
+        out.resize(out.len() + pad4(self.auth_data.len()), 0);
+        out
+    }
+
+    /// Same request with the auth fields replaced
+    pub fn with_auth(&self, name: &[u8], data: &[u8]) -> Self {
+        Self {
+            auth_name: name.to_vec(),
+            auth_data: data.to_vec(),
+            ..self.clone()
+        }
+    }
+
+    /// Setup reply refusing the connection, in the client's byte order
+    pub fn refusal(&self, reason: &str) -> Vec<u8> {
+        let reason = &reason.as_bytes()[..reason.len().min(255)];
+        let mut out = vec![0, reason.len() as u8];
+        self.put_u16(&mut out, 11);
+        self.put_u16(&mut out, 0);
+        self.put_u16(&mut out, reason.len().div_ceil(4) as u16);
+        out.extend_from_slice(reason);
+        out.resize(out.len() + pad4(reason.len()), 0);
+        out
+    }
+}
+
+/// Random MIT-MAGIC-COOKIE-1 for one session
+#[derive(Clone, Copy)]
+pub struct Cookie([u8; COOKIE_LEN]);
+
+impl Cookie {
+    pub fn generate() -> Self {
+        let mut bytes = [0u8; COOKIE_LEN];
+        rand::rngs::OsRng.fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+
+    /// Constant-time check of a client's setup request
+    pub fn matches(&self, req: &SetupRequest) -> bool {
+        req.auth_name == MIT_MAGIC_COOKIE
+            && req.auth_data.len() == COOKIE_LEN
+            && req
+                .auth_data
+                .iter()
+                .zip(self.0.iter())
+                .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+                == 0
+    }
+}
+
+/// Check a local client's setup against the session cookie
+///
+/// Refuses the client with a proper X11 error if the cookie is missing or
+/// wrong. On success returns the setup with the auth stripped, ready to be
+/// sent to the remote side, and any bytes read past it.
+pub async fn authenticate_client<S>(stream: &mut S, cookie: &Cookie) -> Result<(Vec<u8>, Vec<u8>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (req, rest) = SetupRequest::read(stream).await?;
+    if !cookie.matches(&req) {
+        stream
+            .write_all(&req.refusal("Invalid MIT-MAGIC-COOKIE-1 key"))
+            .await?;
+        anyhow::bail!("rejected local client without valid cookie");
+    }
+    Ok((req.with_auth(&[], &[]).encode(), rest))
+}
+
+/// Xauthority file holding the session cookie, removed on drop
+pub struct SessionAuth {
+    pub cookie: Cookie,
+    pub path: PathBuf,
+}
+
+impl SessionAuth {
+    /// Generate a cookie for `display_num` and write it to a private file
+    pub fn create(display_num: u32) -> Result<Self> {
+        let dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .filter(|d| !d.is_empty())
+            .map(PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        let path = dir.join(format!("x11q-{}-{}.xauth", display_num, std::process::id()));
+
+        let cookie = Cookie::generate();
+        write_private(&path, &xauthority_entries(display_num, &cookie))?;
+        crate::xlock::remove_on_signal(&path);
+        Ok(Self { cookie, path })
+    }
+}
+
+impl Drop for SessionAuth {
+    fn drop(&mut self) {
+        crate::xlock::forget(&self.path);
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// One record of an Xauthority file (all fields big-endian, length-prefixed)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct XauthEntry {
+    family: u16,
+    address: Vec<u8>,
+    number: Vec<u8>,
+    pub name: Vec<u8>,
+    pub data: Vec<u8>,
+}
+
+impl XauthEntry {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.family.to_be_bytes());
+        for field in [&self.address, &self.number, &self.name, &self.data] {
+            out.extend_from_slice(&(field.len() as u16).to_be_bytes());
+            out.extend_from_slice(field);
+        }
+    }
+
+    /// Does this entry apply to `display_num` on this host?
+    ///
+    /// Mirrors libxcb: unix socket and loopback tcp connections both look
+    /// for the local host name, and wildcard entries match anything.
+    fn matches(&self, hostname: &[u8], display_num: u32) -> bool {
+        if self.number != display_num.to_string().as_bytes() {
+            return false;
+        }
+        match self.family {
+            FAMILY_WILD => true,
+            FAMILY_LOCAL => self.address == hostname,
+            FAMILY_INTERNET => self.address == [127, 0, 0, 1],
+            FAMILY_INTERNET6 => self.address == std::net::Ipv6Addr::LOCALHOST.octets(),
+            _ => false,
+        }
+    }
+}
+
+fn parse_xauthority(mut buf: &[u8]) -> Vec<XauthEntry> {
+    fn field(buf: &mut &[u8]) -> Option<Vec<u8>> {
+        let len = u16::from_be_bytes([*buf.first()?, *buf.get(1)?]) as usize;
+        let value = buf.get(2..2 + len)?.to_vec();
+        *buf = &buf[2 + len..];
+        Some(value)
+    }
+
+    let mut entries = Vec::new();
+    while buf.len() >= 2 {
+        let family = u16::from_be_bytes([buf[0], buf[1]]);
+        buf = &buf[2..];
+        let (Some(address), Some(number), Some(name), Some(data)) = (
+            field(&mut buf),
+            field(&mut buf),
+            field(&mut buf),
+            field(&mut buf),
+        ) else {
+            break; // truncated file, keep what we have
+        };
+        entries.push(XauthEntry {
+            family,
+            address,
+            number,
+            name,
+            data,
+        });
+    }
+    entries
+}
+
+/// This machine's host name, the address Xauthority records for local
+/// displays are filed under
+#[cfg(unix)]
+pub(crate) fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let name = String::from_utf8_lossy(&buf[..len]).trim().to_string();
+    (!name.is_empty()).then_some(name)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn hostname() -> Option<String> {
+    std::env::var("COMPUTERNAME")
+        .ok()
+        .map(|h| h.trim().to_string())
+        .filter(|h| !h.is_empty())
+}
+
+/// Xauthority records for `display_num`, one for the local host name and a
+/// wildcard one so TCP (`localhost:N`) clients find it too
+fn xauthority_entries(display_num: u32, cookie: &Cookie) -> Vec<u8> {
+    let hostname = hostname().unwrap_or_default().into_bytes();
+    let mut out = Vec::new();
+    for (family, address) in [(FAMILY_LOCAL, hostname), (FAMILY_WILD, Vec::new())] {
+        if family == FAMILY_LOCAL && address.is_empty() {
+            continue;
+        }
+        XauthEntry {
+            family,
+            address,
+            number: display_num.to_string().into_bytes(),
+            name: MIT_MAGIC_COOKIE.to_vec(),
+            data: cookie.0.to_vec(),
+        }
+        .encode(&mut out);
+    }
+    out
+}
+
+/// The user's Xauthority file ($XAUTHORITY or ~/.Xauthority)
+fn xauthority_path() -> Option<PathBuf> {
+    if let Some(path) = std::env::var_os("XAUTHORITY").filter(|p| !p.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".Xauthority"))
+}
+
+/// Best local credentials for `display_num`, preferring MIT-MAGIC-COOKIE-1
+pub fn local_credentials(display_num: u32) -> Option<XauthEntry> {
+    let bytes = std::fs::read(xauthority_path()?).ok()?;
+    let hostname = hostname().unwrap_or_default();
+    find_credentials(&parse_xauthority(&bytes), hostname.as_bytes(), display_num)
+}
+
+fn find_credentials(
+    entries: &[XauthEntry],
+    hostname: &[u8],
+    display_num: u32,
+) -> Option<XauthEntry> {
+    let mut matching = entries.iter().filter(|e| e.matches(hostname, display_num));
+    let first = matching.clone().next()?;
+    Some(
+        matching
+            .find(|e| e.name == MIT_MAGIC_COOKIE)
+            .unwrap_or(first)
+            .clone(),
+    )
+}
+
+/// Replace whatever auth the remote sent with the local display's cookie
+///
+/// Reads the setup request from the remote side and returns it rewritten
+/// for the local X server, plus any bytes the remote sent after it.
+/// Without a matching Xauthority entry the auth is simply dropped, which
+/// still works for servers relying on host or SI:localuser access.
+pub async fn inject_local_credentials<S>(
+    remote: &mut S,
+    display_num: u32,
+) -> Result<(SetupRequest, Vec<u8>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let (req, rest) = SetupRequest::read(remote).await?;
+    let req = match local_credentials(display_num) {
+        Some(entry) => req.with_auth(&entry.name, &entry.data),
+        None => req.with_auth(&[], &[]),
+    };
+    Ok((req, rest))
+}
+
+/// create_new so a planted file or symlink in a shared tmp dir is an error
+fn write_private(path: &Path, contents: &[u8]) -> Result<()> {
+    let mut opts = std::fs::OpenOptions::new();
+    opts.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        opts.mode(0o600);
+    }
+
+    use std::io::Write;
+    opts.open(path)
+        .and_then(|mut f| f.write_all(contents))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(byte_order: u8, name: &[u8], data: &[u8]) -> SetupRequest {
+        SetupRequest {
+            byte_order,
+            major: 11,
+            minor: 0,
+            auth_name: name.to_vec(),
+            auth_data: data.to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_setup_roundtrip() {
+        for order in [b'l', b'B'] {
+            let req = setup(order, MIT_MAGIC_COOKIE, &[7; 16]);
+            let bytes = req.encode();
+            assert_eq!(bytes.len() % 4, 0);
+            assert_eq!(
+                SetupRequest::parse(&bytes).unwrap(),
+                Some((req, bytes.len()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_setup_partial() {
+        let bytes = setup(b'l', MIT_MAGIC_COOKIE, &[7; 16]).encode();
+        assert_eq!(
+            SetupRequest::parse(&bytes[..bytes.len() - 1]).unwrap(),
+            None
+        );
+        assert_eq!(SetupRequest::parse(&bytes[..4]).unwrap(), None);
+        assert!(SetupRequest::parse(&[0u8; 12]).is_err());
+    }
+
+    #[test]
+    fn test_cookie_matches() {
+        let cookie = Cookie::generate();
+        assert!(cookie.matches(&setup(b'l', MIT_MAGIC_COOKIE, &cookie.0)));
+        assert!(!cookie.matches(&setup(b'l', MIT_MAGIC_COOKIE, &[0; 16])));
+        assert!(!cookie.matches(&setup(b'l', b"", b"")));
+        assert!(!cookie.matches(&setup(b'l', b"XDM-AUTHORIZATION-1", &cookie.0)));
+    }
+
+    #[test]
+    fn test_xauthority_roundtrip() {
+        let cookie = Cookie::generate();
+        let entries = parse_xauthority(&xauthority_entries(99, &cookie));
+        assert!(!entries.is_empty());
+        assert!(entries.iter().all(|e| e.data == cookie.0));
+        assert_eq!(entries.last().unwrap().family, FAMILY_WILD);
+    }
+
+    #[test]
+    fn test_find_credentials() {
+        let entry = |family, address: &[u8], number: &[u8], name: &[u8]| XauthEntry {
+            family,
+            address: address.to_vec(),
+            number: number.to_vec(),
+            name: name.to_vec(),
+            data: vec![1, 2, 3],
+        };
+        let entries = vec![
+            entry(FAMILY_LOCAL, b"otherhost", b"0", MIT_MAGIC_COOKIE),
+            entry(FAMILY_LOCAL, b"myhost", b"1", MIT_MAGIC_COOKIE),
+            entry(FAMILY_LOCAL, b"myhost", b"0", b"XDM-AUTHORIZATION-1"),
+            entry(FAMILY_INTERNET, &[127, 0, 0, 1], b"0", MIT_MAGIC_COOKIE),
+        ];
+
+        let found = find_credentials(&entries, b"myhost", 0).unwrap();
+        assert_eq!(found, entries[3]);
+        let found = find_credentials(&entries, b"myhost", 1).unwrap();
+        assert_eq!(found, entries[1]);
+        assert!(find_credentials(&entries, b"myhost", 2).is_none());
+
+        // truncated trailing record is ignored
+        let mut bytes = Vec::new();
+        entries[1].encode(&mut bytes);
+        bytes.extend_from_slice(&[1, 0, 0]);
+        assert_eq!(parse_xauthority(&bytes), vec![entries[1].clone()]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_hostname_matches_kernel() {
+        let kernel = std::fs::read_to_string("/proc/sys/kernel/hostname").unwrap();
+        assert_eq!(hostname().as_deref(), Some(kernel.trim()));
+    }
+
+    #[test]
+    fn test_refusal_reply() {
+        let reply = setup(b'B', b"", b"").refusal("no");
+        assert_eq!(reply, vec![0, 2, 0, 11, 0, 0, 0, 1, b'n', b'o', 0, 0]);
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_client_strips_cookie() {
+        let cookie = Cookie::generate();
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(&setup(b'l', MIT_MAGIC_COOKIE, &cookie.0).encode())
+            .await
+            .unwrap();
+
+        let (forwarded, rest) = authenticate_client(&mut server, &cookie).await.unwrap();
+        assert_eq!(forwarded, setup(b'l', b"", b"").encode());
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_authenticate_client_refuses_wrong_cookie() {
+        let cookie = Cookie::generate();
+        let (mut client, mut server) = tokio::io::duplex(1024);
+        client
+            .write_all(&setup(b'l', MIT_MAGIC_COOKIE, &[0; 16]).encode())
+            .await
+            .unwrap();
+
+        assert!(authenticate_client(&mut server, &cookie).await.is_err());
+        let mut status = [0u8; 1];
+        client.read_exact(&mut status).await.unwrap();
+        assert_eq!(status[0], 0);
+    }
+}
@@ -0,0 +1,979 @@
+//! Round-trip elimination for X11 clients on the joining side
+//!
+//! Toolkits start up with hundreds of synchronous round trips (InternAtom,
+//! QueryExtension, GetKeyboardMapping), each costing a full RTT over a long
+//! link. The joining side's proxy parses its clients' requests and answers
+//! the ones whose result can't change from a cache shared by the session:
+//!
+//! - InternAtom / GetAtomName: atoms live as long as the X server
+//! - QueryExtension: the server's extensions don't come and go
+//! - GetKeyboardMapping: until the first keyboard MappingNotify
+//!
+//! The serving side primes the cache with its X server's atoms, extensions
+//! and keymap (PRIME on the control stream) and it learns from every reply
+//! after that.
+//!
+//! A request answered here never reaches the X server, so the server numbers
+//! requests lower than the client does; every reply, error and event from
+//! the server gets its sequence number moved up by the requests answered
+//! before it. Replies and errors must reach the client in order, so requests
+//! are only answered once every forwarded request is done. An event that
+//! arrives after a local answer carries the local answer's number, as the
+//! client can't go back.
+
+use crate::firewall::{self, ByteOrder};
+use anyhow::{Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+// core request opcodes
+const X_INTERN_ATOM: u8 = 16;
+const X_GET_ATOM_NAME: u8 = 17;
+const X_LIST_FONTS_WITH_INFO: u8 = 50;
+const X_QUERY_EXTENSION: u8 = 98;
+const X_GET_KEYBOARD_MAPPING: u8 = 101;
+
+// reply types
+const X_ERROR: u8 = 0;
+const X_REPLY: u8 = 1;
+const X_MAPPING_NOTIFY: u8 = 34;
+const X_GENERIC_EVENT: u8 = 35;
+
+const MAPPING_KEYBOARD: u8 = 1;
+
+/// Requests and replies up to this size are buffered whole; bigger ones
+/// are passed on as they come
+const MAX_INSPECT: usize = 64 * 1024;
+const CHUNK: usize = 64 * 1024;
+/// A PRIME fits one control message
+const MAX_PRIME: usize = crate::control::MAX_MESSAGE;
+/// GetAtomName requests in flight while priming
+const ATOM_BATCH: u32 = 256;
+
+/// What the session knows about the serving side's X server
+#[derive(Debug, Default, PartialEq)]
+struct Tables {
+    atoms: HashMap<Vec<u8>, u32>,
+    names: HashMap<u32, Vec<u8>>,
+    /// present, major opcode, first event, first error
+    extensions: HashMap<Vec<u8>, [u8; 4]>,
+    keymap: Option<Keymap>,
+}
+
+#[derive(Debug, PartialEq)]
+struct Keymap {
+    first: u8,
+    per: u8,
+    keysyms: Vec<u32>,
+}
+
+impl Keymap {
+    /// Keysyms of `count` keycodes from `first`, if all are known
+    fn get(&self, first: u8, count: u8) -> Option<&[u32]> {
+        if count == 0 {
+            return None;
+        }
+        let start = (first.checked_sub(self.first)? as usize) * self.per as usize;
+        let end = start + count as usize * self.per as usize;
+        self.keysyms.get(start..end)
+    }
+}
+
+impl Tables {
+    fn intern(&mut self, name: &[u8], atom: u32) {
+        self.atoms.insert(name.to_vec(), atom);
+        self.names.insert(atom, name.to_vec());
+    }
+
+    /// `[atoms u32]([atom u32][len u16][name])* [extensions u16]([len u8][name][info; 4])*
+    /// [first u8][count u8][per u8]([keysym u32])*`, little endian, at most
+    /// MAX_PRIME: atoms that don't fit are left out
+    fn encode(&self) -> Vec<u8> {
+        let mut tail = Vec::new();
+        tail.extend_from_slice(&(self.extensions.len() as u16).to_le_bytes());
+        for (name, info) in &self.extensions {
+            tail.push(name.len() as u8);
+            tail.extend_from_slice(name);
+            tail.extend_from_slice(info);
+        }
+        match &self.keymap {
+            Some(keymap) => {
+                let count = keymap.keysyms.len() / keymap.per.max(1) as usize;
+                tail.extend_from_slice(&[keymap.first, count as u8, keymap.per]);
+                for keysym in &keymap.keysyms {
+                    tail.extend_from_slice(&keysym.to_le_bytes());
+                }
+            }
+            None => tail.extend_from_slice(&[0, 0, 0]),
+        }
+
+        let mut atoms = Vec::new();
+        let mut count = 0u32;
+        let mut sorted: Vec<_> = self.names.iter().collect();
+        sorted.sort();
+        for (atom, name) in sorted {
+            if 4 + atoms.len() + 6 + name.len() + tail.len() > MAX_PRIME {
+                break;
+            }
+            atoms.extend_from_slice(&atom.to_le_bytes());
+            atoms.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            atoms.extend_from_slice(name);
+            count += 1;
+        }
+
+        let mut out = count.to_le_bytes().to_vec();
+        out.extend_from_slice(&atoms);
+        out.extend_from_slice(&tail);
+        out
+    }
+
+    fn decode(mut data: &[u8]) -> Result<Self> {
+        fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8]> {
+            let taken = data.get(..n).context("truncated x11 cache prime")?;
+            *data = &data[n..];
+            Ok(taken)
+        }
+
+        let mut tables = Tables::default();
+        let atoms = u32::from_le_bytes(take(&mut data, 4)?.try_into()?);
+        for _ in 0..atoms {
+            let atom = u32::from_le_bytes(take(&mut data, 4)?.try_into()?);
+            let len = u16::from_le_bytes(take(&mut data, 2)?.try_into()?);
+            let name = take(&mut data, len as usize)?;
+            tables.intern(name, atom);
+        }
+        let extensions = u16::from_le_bytes(take(&mut data, 2)?.try_into()?);
+        for _ in 0..extensions {
+            let len = take(&mut data, 1)?[0];
+            let name = take(&mut data, len as usize)?.to_vec();
+            let info = take(&mut data, 4)?.try_into()?;
+            tables.extensions.insert(name, info);
+        }
+        let keymap = take(&mut data, 3)?;
+        let (first, count, per) = (keymap[0], keymap[1], keymap[2]);
+        if count > 0 && per > 0 {
+            let keysyms = take(&mut data, count as usize * per as usize * 4)?
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+                .collect();
+            tables.keymap = Some(Keymap {
+                first,
+                per,
+                keysyms,
+            });
+        }
+        Ok(tables)
+    }
+}
+
+/// Answers shared by every X11 stream of a session
+#[derive(Clone, Default)]
+pub struct Cache {
+    tables: Arc<Mutex<Tables>>,
+}
+
+impl Cache {
+    /// A cache starting out with the serving side's PRIME, if it sent one
+    pub fn new(prime: Option<&[u8]>) -> Self {
+        let tables = match prime.map(Tables::decode) {
+            Some(Ok(tables)) => tables,
+            Some(Err(e)) => {
+                eprintln!("ignoring x11 cache prime: {e}");
+                Tables::default()
+            }
+            None => Tables::default(),
+        };
+        Self {
+            tables: Arc::new(Mutex::new(tables)),
+        }
+    }
+}
+
+/// Read the local X server's atoms, extensions and keymap into a PRIME
+///
+/// With `untrusted`, the extensions the firewall hides are primed as absent.
+pub fn collect(display_num: u32, untrusted: bool) -> Result<Vec<u8>> {
+    use x11rb::connection::Connection;
+    use x11rb::errors::ReplyError;
+    use x11rb::protocol::xproto::ConnectionExt;
+
+    let (conn, _) = x11rb::connect(Some(&format!(":{}", display_num)))
+        .context("failed to connect to the local X display")?;
+    let mut tables = Tables::default();
+
+    let (min, max) = (conn.setup().min_keycode, conn.setup().max_keycode);
+    let mapping = conn.get_keyboard_mapping(min, max - min + 1)?.reply()?;
+    tables.keymap = Some(Keymap {
+        first: min,
+        per: mapping.keysyms_per_keycode,
+        keysyms: mapping.keysyms,
+    });
+
+    let names = conn.list_extensions()?.reply()?.names;
+    let cookies = names
+        .iter()
+        .map(|name| conn.query_extension(&name.name))
+        .collect::<Result<Vec<_>, _>>()?;
+    for (name, cookie) in names.iter().zip(cookies) {
+        let reply = cookie.reply()?;
+        let hidden = untrusted
+            && firewall::HIDDEN_EXTENSIONS
+                .iter()
+                .any(|h| h.as_bytes() == name.name);
+        let info = if hidden {
+            [0; 4]
+        } else {
+            [
+                reply.present as u8,
+                reply.major_opcode,
+                reply.first_event,
+                reply.first_error,
+            ]
+        };
+        tables.extensions.insert(name.name.clone(), info);
+    }
+
+    // atoms are numbered from 1 without gaps, the first BadAtom is the end
+    let mut size = 0;
+    'atoms: for batch in (1..).step_by(ATOM_BATCH as usize) {
+        let cookies = (batch..batch + ATOM_BATCH)
+            .map(|atom| conn.get_atom_name(atom))
+            .collect::<Result<Vec<_>, _>>()?;
+        for (atom, cookie) in (batch..).zip(cookies) {
+            let name = match cookie.reply() {
+                Ok(reply) => reply.name,
+                Err(ReplyError::X11Error(_)) => break 'atoms,
+                Err(e) => return Err(e.into()),
+            };
+            size += 6 + name.len();
+            if size > MAX_PRIME {
+                break 'atoms;
+            }
+            tables.intern(&name, atom);
+        }
+    }
+
+    Ok(tables.encode())
+}
+
+/// A forwarded request whose reply needs a closer look
+#[derive(Clone, Debug, PartialEq)]
+enum Track {
+    Atom(Vec<u8>),
+    AtomName(u32),
+    Extension(Vec<u8>),
+    /// ListFontsWithInfo: one reply per font, then an empty one
+    Fonts,
+}
+
+/// Where the two directions of one connection meet
+#[derive(Default)]
+struct Shared {
+    /// (server seq, client seq - server seq) from that request on
+    offsets: VecDeque<(u64, u64)>,
+    /// every forwarded request up to here is done
+    done: u64,
+    tracked: VecDeque<(u64, Track)>,
+}
+
+/// Local X client -> remote: answers what it can, numbers the rest
+struct Requests {
+    order: ByteOrder,
+    cache: Cache,
+    sync: Arc<Mutex<Shared>>,
+    /// requests from the client, and how many of them were forwarded
+    seq: u64,
+    forwarded: u64,
+    buf: Vec<u8>,
+    /// bytes of a large request still to pass through
+    streaming: usize,
+    /// local replies, with the client seq they answer
+    answers: Vec<(u64, Vec<u8>)>,
+}
+
+/// Remote -> local X client: fixes sequence numbers, learns from replies
+struct Replies {
+    order: ByteOrder,
+    cache: Cache,
+    sync: Arc<Mutex<Shared>>,
+    setup_done: bool,
+    /// last server seq seen and last client seq delivered
+    read: u64,
+    delivered: u64,
+    buf: Vec<u8>,
+    streaming: usize,
+}
+
+fn connection(cache: Cache, byte_order: u8) -> (Requests, Replies) {
+    let order = ByteOrder::new(byte_order);
+    let sync = Arc::new(Mutex::new(Shared {
+        offsets: VecDeque::from([(0, 0)]),
+        ..Shared::default()
+    }));
+    (
+        Requests {
+            order,
+            cache: cache.clone(),
+            sync: Arc::clone(&sync),
+            seq: 0,
+            forwarded: 0,
+            buf: Vec::new(),
+            streaming: 0,
+            answers: Vec::new(),
+        },
+        Replies {
+            order,
+            cache,
+            sync,
+            setup_done: false,
+            read: 0,
+            delivered: 0,
+            buf: Vec::new(),
+            streaming: 0,
+        },
+    )
+}
+
+impl Requests {
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.buf.extend_from_slice(data);
+        let mut pos = 0;
+
+        loop {
+            let avail = &self.buf[pos..];
+
+            if self.streaming > 0 {
+                let n = self.streaming.min(avail.len());
+                out.extend_from_slice(&avail[..n]);
+                pos += n;
+                self.streaming -= n;
+                if self.streaming > 0 {
+                    break;
+                }
+                continue;
+            }
+
+            if avail.len() < 4 {
+                break;
+            }
+            let short = self.order.u16(&avail[2..4]) as usize;
+            let len = if short != 0 {
+                short * 4
+            } else if avail.len() < 8 {
+                break;
+            } else {
+                // BIG-REQUESTS: 32-bit length follows the header
+                (self.order.u32(&avail[4..8]) as usize).saturating_mul(4)
+            };
+            let len = len.max(4);
+
+            if len <= MAX_INSPECT {
+                if avail.len() < len {
+                    break;
+                }
+                let req = avail[..len].to_vec();
+                if self.request(&req) {
+                    out.extend_from_slice(&req);
+                }
+                pos += len;
+            } else {
+                self.seq += 1;
+                self.forward();
+                self.streaming = len;
+            }
+        }
+
+        self.buf.drain(..pos);
+    }
+
+    /// Number a whole request; true = forward it
+    fn request(&mut self, req: &[u8]) -> bool {
+        self.seq += 1;
+        if let Some(reply) = self.answer(req) {
+            self.answers.push((self.seq, reply));
+            return false;
+        }
+        let track = self.track(req);
+        self.forward();
+        if let Some(track) = track {
+            let mut sync = self.sync.lock().unwrap();
+            sync.tracked.push_back((self.forwarded, track));
+        }
+        true
+    }
+
+    /// Number the request just counted as going to the server
+    fn forward(&mut self) {
+        self.forwarded += 1;
+        let offset = self.seq - self.forwarded;
+        let mut sync = self.sync.lock().unwrap();
+        if sync.offsets.back().map(|&(_, o)| o) != Some(offset) {
+            sync.offsets.push_back((self.forwarded, offset));
+        }
+    }
+
+    fn name<'a>(&self, req: &'a [u8]) -> Option<&'a [u8]> {
+        let len = self.order.u16(req.get(4..6)?) as usize;
+        req.get(8..8 + len)
+    }
+
+    fn track(&self, req: &[u8]) -> Option<Track> {
+        match req[0] {
+            X_INTERN_ATOM => Some(Track::Atom(self.name(req)?.to_vec())),
+            X_GET_ATOM_NAME => Some(Track::AtomName(self.order.u32(req.get(4..8)?))),
+            X_QUERY_EXTENSION => Some(Track::Extension(self.name(req)?.to_vec())),
+            X_LIST_FONTS_WITH_INFO => Some(Track::Fonts),
+            _ => None,
+        }
+    }
+
+    /// A reply from the cache, if the request may be answered here
+    fn answer(&self, req: &[u8]) -> Option<Vec<u8>> {
+        {
+            let sync = self.sync.lock().unwrap();
+            if sync.done < self.forwarded {
+                return None;
+            }
+        }
+        let tables = self.cache.tables.lock().unwrap();
+        let mut reply = vec![0u8; 32];
+        reply[0] = X_REPLY;
+        self.order.put_u16(&mut reply[2..], self.seq as u16);
+        match req[0] {
+            X_INTERN_ATOM => {
+                let atom = *tables.atoms.get(self.name(req)?)?;
+                self.order.put_u32(&mut reply[8..], atom);
+            }
+            X_GET_ATOM_NAME => {
+                let name = tables.names.get(&self.order.u32(req.get(4..8)?))?;
+                let padded = name.len().div_ceil(4) * 4;
+                self.order.put_u32(&mut reply[4..], (padded / 4) as u32);
+                self.order.put_u16(&mut reply[8..], name.len() as u16);
+                reply.extend_from_slice(name);
+                reply.resize(32 + padded, 0);
+            }
+            X_QUERY_EXTENSION => {
+                let info = tables.extensions.get(self.name(req)?)?;
+                reply[8..12].copy_from_slice(info);
+            }
+            X_GET_KEYBOARD_MAPPING => {
+                let keymap = tables.keymap.as_ref()?;
+                let keysyms = keymap.get(*req.get(4)?, *req.get(5)?)?;
+                reply[1] = keymap.per;
+                self.order.put_u32(&mut reply[4..], keysyms.len() as u32);
+                for &keysym in keysyms {
+                    let mut b = [0u8; 4];
+                    self.order.put_u32(&mut b, keysym);
+                    reply.extend_from_slice(&b);
+                }
+            }
+            _ => return None,
+        }
+        Some(reply)
+    }
+}
+
+impl Replies {
+    /// Pass on a local answer for client request `seq`
+    fn answer(&mut self, seq: u64, reply: &[u8], out: &mut Vec<u8>) {
+        self.delivered = self.delivered.max(seq);
+        out.extend_from_slice(reply);
+    }
+
+    fn push(&mut self, data: &[u8], out: &mut Vec<u8>) {
+        self.buf.extend_from_slice(data);
+        let mut pos = 0;
+
+        loop {
+            let avail = &self.buf[pos..];
+
+            if self.streaming > 0 {
+                let n = self.streaming.min(avail.len());
+                out.extend_from_slice(&avail[..n]);
+                pos += n;
+                self.streaming -= n;
+                if self.streaming > 0 {
+                    break;
+                }
+                continue;
+            }
+
+            if !self.setup_done {
+                if avail.len() < 8 {
+                    break;
+                }
+                let len = 8 + self.order.u16(&avail[6..8]) as usize * 4;
+                if avail.len() < len {
+                    break;
+                }
+                out.extend_from_slice(&avail[..len]);
+                pos += len;
+                self.setup_done = true;
+                continue;
+            }
+
+            if avail.len() < 32 {
+                break;
+            }
+            let kind = avail[0] & 0x7f;
+            let extra = if kind == X_REPLY || kind == X_GENERIC_EVENT {
+                (self.order.u32(&avail[4..8]) as usize).saturating_mul(4)
+            } else {
+                0
+            };
+            // a reply the cache learns from is read whole
+            let whole = kind == X_REPLY && extra <= MAX_INSPECT && self.tracked(&avail[..32]);
+            if whole && avail.len() < 32 + extra {
+                break;
+            }
+
+            let mut packet = avail[..if whole { 32 + extra } else { 32 }].to_vec();
+            self.packet(&mut packet);
+            out.extend_from_slice(&packet);
+            pos += packet.len();
+            if !whole {
+                self.streaming = extra;
+            }
+        }
+
+        self.buf.drain(..pos);
+    }
+
+    /// Whether a reply answers a tracked request
+    fn tracked(&self, header: &[u8]) -> bool {
+        let seq = self.widen(self.order.u16(&header[2..4]));
+        let sync = self.sync.lock().unwrap();
+        sync.tracked.iter().any(|&(s, _)| s == seq)
+    }
+
+    /// Full server seq of a packet's 16-bit one
+    fn widen(&self, seq: u16) -> u64 {
+        let seq = (self.read & !0xffff) | seq as u64;
+        if seq < self.read {
+            seq + 0x10000
+        } else {
+            seq
+        }
+    }
+
+    /// Learn from a packet, then renumber it for the client
+    fn packet(&mut self, packet: &mut [u8]) {
+        let kind = packet[0] & 0x7f;
+        let seq = self.widen(self.order.u16(&packet[2..4]));
+        self.read = seq;
+
+        let mut sync = self.sync.lock().unwrap();
+        while sync.offsets.len() > 1 && sync.offsets[1].0 <= seq {
+            sync.offsets.pop_front();
+        }
+        let offset = sync.offsets[0].1;
+        while sync.tracked.front().is_some_and(|&(s, _)| s < seq) {
+            sync.tracked.pop_front();
+        }
+        let track = sync
+            .tracked
+            .front()
+            .filter(|&&(s, _)| s == seq)
+            .map(|(_, track)| track.clone());
+
+        let done = match kind {
+            // a font list goes on until its empty reply
+            X_REPLY if track == Some(Track::Fonts) && packet[1] != 0 => seq - 1,
+            X_REPLY | X_ERROR => {
+                if track.is_some() {
+                    sync.tracked.pop_front();
+                }
+                seq
+            }
+            // events come before the reply of the request that caused them
+            _ => seq.saturating_sub(1),
+        };
+        sync.done = sync.done.max(done);
+        drop(sync);
+
+        match (kind, track) {
+            (X_REPLY, Some(track)) => self.learn(&track, packet),
+            (X_MAPPING_NOTIFY, _) if packet[4] == MAPPING_KEYBOARD => {
+                self.cache.tables.lock().unwrap().keymap = None;
+            }
+            _ => {}
+        }
+
+        // the client can't go back to a number it already saw answered
+        let client = (seq + offset).max(self.delivered);
+        self.delivered = client;
+        self.order.put_u16(&mut packet[2..], client as u16);
+    }
+
+    fn learn(&self, track: &Track, reply: &[u8]) {
+        let mut tables = self.cache.tables.lock().unwrap();
+        match track {
+            Track::Atom(name) => {
+                let atom = self.order.u32(&reply[8..12]);
+                if atom != 0 {
+                    tables.intern(name, atom);
+                }
+            }
+            Track::AtomName(atom) => {
+                let len = self.order.u16(&reply[8..10]) as usize;
+                if let Some(name) = reply.get(32..32 + len) {
+                    tables.intern(name, *atom);
+                }
+            }
+            Track::Extension(name) => {
+                let info = reply[8..12].try_into().unwrap();
+                tables.extensions.insert(name.clone(), info);
+            }
+            Track::Fonts => {}
+        }
+    }
+}
+
+/// Relay one X11 client to `remote` after its setup, in `byte_order`
+/// (b'l'/b'B'), answering from `cache` what it can; `rest` is what the
+/// client sent after the setup
+pub async fn proxy<L, R>(
+    local: L,
+    remote: R,
+    byte_order: u8,
+    rest: &[u8],
+    cache: Cache,
+) -> Result<()>
+where
+    L: AsyncRead + AsyncWrite,
+    R: AsyncRead + AsyncWrite,
+{
+    let (mut local_read, mut local_write) = io::split(local);
+    let (mut remote_read, mut remote_write) = io::split(remote);
+    let (mut requests, mut replies) = connection(cache, byte_order);
+    let (answers, mut answered) = mpsc::unbounded_channel();
+
+    let forward = async {
+        let mut buf = vec![0u8; CHUNK];
+        let mut out = Vec::with_capacity(CHUNK);
+        requests.push(rest, &mut out);
+        loop {
+            for answer in requests.answers.drain(..) {
+                let _ = answers.send(answer);
+            }
+            remote_write.write_all(&out).await?;
+            out.clear();
+            let n = local_read.read(&mut buf).await?;
+            if n == 0 {
+                return anyhow::Ok(());
+            }
+            requests.push(&buf[..n], &mut out);
+        }
+    };
+
+    let back = async {
+        let mut buf = vec![0u8; CHUNK];
+        let mut out = Vec::with_capacity(CHUNK);
+        loop {
+            tokio::select! {
+                // an answer is queued before the requests after it are
+                // sent, so taking answers first keeps sequence numbers
+                // going up for the client
+                biased;
+                Some((seq, reply)) = answered.recv() => replies.answer(seq, &reply, &mut out),
+                n = remote_read.read(&mut buf) => {
+                    let n = n?;
+                    if n == 0 {
+                        return anyhow::Ok(());
+                    }
+                    replies.push(&buf[..n], &mut out);
+                }
+            }
+            local_write.write_all(&out).await?;
+            out.clear();
+        }
+    };
+
+    tokio::select! {
+        r = forward => r,
+        r = back => r,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(op: u8, data: u8, body: &[u8]) -> Vec<u8> {
+        let mut req = vec![op, data, 0, 0];
+        req.extend_from_slice(body);
+        req.resize(req.len().div_ceil(4) * 4, 0);
+        let len = (req.len() / 4) as u16;
+        req[2..4].copy_from_slice(&len.to_le_bytes());
+        req
+    }
+
+    fn named(op: u8, name: &[u8]) -> Vec<u8> {
+        let mut body = (name.len() as u16).to_le_bytes().to_vec();
+        body.extend_from_slice(&[0, 0]);
+        body.extend_from_slice(name);
+        request(op, 0, &body)
+    }
+
+    fn reply(seq: u16, data: &[u8]) -> Vec<u8> {
+        let mut reply = vec![X_REPLY, 0];
+        reply.extend_from_slice(&seq.to_le_bytes());
+        reply.extend_from_slice(&[0; 4]);
+        reply.extend_from_slice(data);
+        reply.resize(32, 0);
+        reply
+    }
+
+    fn seq(packet: &[u8]) -> u16 {
+        u16::from_le_bytes([packet[2], packet[3]])
+    }
+
+    /// A connection past its setup, with `cache`
+    fn connected(cache: &Cache) -> (Requests, Replies) {
+        let (requests, mut replies) = connection(cache.clone(), b'l');
+        let mut out = Vec::new();
+        replies.push(&[1, 0, 11, 0, 0, 0, 0, 0], &mut out);
+        assert_eq!(out.len(), 8);
+        (requests, replies)
+    }
+
+    fn primed() -> Cache {
+        let mut tables = Tables::default();
+        tables.intern(b"WM_PROTOCOLS", 300);
+        tables
+            .extensions
+            .insert(b"RENDER".to_vec(), [1, 139, 0, 142]);
+        tables.keymap = Some(Keymap {
+            first: 8,
+            per: 2,
+            keysyms: (0..20).collect(),
+        });
+        Cache::new(Some(&tables.encode()))
+    }
+
+    #[test]
+    fn test_prime_roundtrip() {
+        let mut tables = Tables::default();
+        tables.intern(b"PRIMARY", 1);
+        tables.intern(b"_NET_WM_NAME", 400);
+        tables.extensions.insert(b"XTEST".to_vec(), [0; 4]);
+        tables.keymap = Some(Keymap {
+            first: 8,
+            per: 3,
+            keysyms: (0..744).collect(),
+        });
+        assert_eq!(Tables::decode(&tables.encode()).unwrap(), tables);
+        assert!(Tables::decode(&tables.encode()[..20]).is_err());
+
+        // atoms that don't fit are left out
+        for atom in 1000..9000 {
+            tables.intern(format!("ATOM_{atom}").as_bytes(), atom);
+        }
+        let prime = tables.encode();
+        assert!(prime.len() <= MAX_PRIME);
+        let decoded = Tables::decode(&prime).unwrap();
+        assert!(decoded.atoms.len() < tables.atoms.len());
+        assert_eq!(decoded.extensions, tables.extensions);
+    }
+
+    #[test]
+    fn test_cached_requests_answered_locally() {
+        let cache = primed();
+        let (mut requests, _) = connected(&cache);
+        let mut out = Vec::new();
+        requests.push(&named(X_INTERN_ATOM, b"WM_PROTOCOLS"), &mut out);
+        requests.push(
+            &request(X_GET_ATOM_NAME, 0, &300u32.to_le_bytes()),
+            &mut out,
+        );
+        requests.push(&named(X_QUERY_EXTENSION, b"RENDER"), &mut out);
+        requests.push(&request(X_GET_KEYBOARD_MAPPING, 0, &[9, 2]), &mut out);
+        assert!(out.is_empty());
+
+        let answers = &requests.answers;
+        assert_eq!(answers.len(), 4);
+        assert_eq!(seq(&answers[0].1), 1);
+        assert_eq!(&answers[0].1[8..12], &300u32.to_le_bytes());
+        assert_eq!(&answers[1].1[32..44], b"WM_PROTOCOLS");
+        assert_eq!(&answers[2].1[8..12], &[1, 139, 0, 142]);
+        let keymap = &answers[3].1;
+        assert_eq!((keymap[1], seq(keymap)), (2, 4));
+        let keysyms: Vec<u32> = keymap[32..]
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+            .collect();
+        assert_eq!(keysyms, vec![2, 3, 4, 5]);
+
+        // unknown names and keycodes go to the server
+        requests.push(&named(X_INTERN_ATOM, b"UNKNOWN"), &mut out);
+        requests.push(&request(X_GET_KEYBOARD_MAPPING, 0, &[15, 9]), &mut out);
+        assert_eq!(requests.answers.len(), 4);
+        assert_eq!(requests.forwarded, 2);
+    }
+
+    #[test]
+    fn test_sequence_numbers_follow_the_client() {
+        let cache = primed();
+        let (mut requests, mut replies) = connected(&cache);
+        let mut out = Vec::new();
+        // answered locally as 1, then GetInputFocus is 2 to the client, 1 to the server
+        requests.push(&named(X_INTERN_ATOM, b"WM_PROTOCOLS"), &mut out);
+        requests.push(&request(43, 0, &[]), &mut out);
+        assert_eq!(out, request(43, 0, &[]));
+
+        let mut back = Vec::new();
+        let (seq1, answer) = requests.answers.pop().unwrap();
+        replies.answer(seq1, &answer, &mut back);
+        replies.push(&reply(1, &[]), &mut back);
+        assert_eq!(seq(&back[..32]), 1);
+        assert_eq!(seq(&back[32..]), 2);
+    }
+
+    #[test]
+    fn test_no_local_answers_while_a_reply_is_due() {
+        let cache = primed();
+        let (mut requests, mut replies) = connected(&cache);
+        let mut out = Vec::new();
+        requests.push(&request(43, 0, &[]), &mut out);
+        requests.push(&named(X_INTERN_ATOM, b"WM_PROTOCOLS"), &mut out);
+        assert!(requests.answers.is_empty());
+        assert_eq!(requests.forwarded, 2);
+
+        // once both are answered the cache is back in use
+        replies.push(&reply(1, &[]), &mut Vec::new());
+        replies.push(&reply(2, &300u32.to_le_bytes()), &mut Vec::new());
+        requests.push(&named(X_INTERN_ATOM, b"WM_PROTOCOLS"), &mut out);
+        assert_eq!(requests.answers.len(), 1);
+        assert_eq!(requests.answers[0].0, 3);
+    }
+
+    #[test]
+    fn test_learns_from_replies() {
+        let cache = Cache::default();
+        let (mut requests, mut replies) = connected(&cache);
+        let mut out = Vec::new();
+        requests.push(&named(X_INTERN_ATOM, b"_NET_WM_PID"), &mut out);
+        requests.push(&named(X_QUERY_EXTENSION, b"XTEST"), &mut out);
+        assert_eq!(requests.forwarded, 2);
+
+        let mut back = Vec::new();
+        replies.push(&reply(1, &321u32.to_le_bytes()), &mut back);
+        replies.push(&reply(2, &[1, 132, 0, 0]), &mut back);
+        assert_eq!(back.len(), 64);
+
+        // another client of the session gets both without a round trip
+        let (mut requests, _) = connected(&cache);
+        requests.push(&named(X_INTERN_ATOM, b"_NET_WM_PID"), &mut out);
+        requests.push(
+            &request(X_GET_ATOM_NAME, 0, &321u32.to_le_bytes()),
+            &mut out,
+        );
+        requests.push(&named(X_QUERY_EXTENSION, b"XTEST"), &mut out);
+        assert_eq!(requests.answers.len(), 3);
+        assert_eq!(&requests.answers[2].1[8..12], &[1, 132, 0, 0]);
+    }
+
+    #[test]
+    fn test_requests_without_reply_hold_answers_back() {
+        let cache = primed();
+        let (mut requests, mut replies) = connected(&cache);
+        let mut out = Vec::new();
+        // MapWindow may still fail, so the InternAtom after it is forwarded
+        let map = request(8, 0, &7u32.to_le_bytes());
+        let intern = named(X_INTERN_ATOM, b"WM_PROTOCOLS");
+        requests.push(&map, &mut out);
+        requests.push(&intern, &mut out);
+        assert!(requests.answers.is_empty());
+        assert_eq!(out, [map, intern.clone()].concat());
+
+        // its reply settles both, and the next one is answered as 3
+        let mut back = Vec::new();
+        replies.push(&reply(2, &300u32.to_le_bytes()), &mut back);
+        requests.push(&intern, &mut out);
+        let (seq3, answer) = requests.answers.pop().unwrap();
+        assert_eq!(seq3, 3);
+        replies.answer(seq3, &answer, &mut back);
+
+        // an event the server sends after that can't go back to 2
+        let mut expose = vec![12, 0, 2, 0];
+        expose.resize(32, 0);
+        replies.push(&expose, &mut back);
+        assert_eq!(seq(&back[..32]), 2);
+        assert_eq!(seq(&back[64..]), 3);
+    }
+
+    #[test]
+    fn test_keymap_dropped_on_mapping_notify() {
+        let cache = primed();
+        let (mut requests, mut replies) = connected(&cache);
+        let mut notify = vec![X_MAPPING_NOTIFY, 0, 0, 0, MAPPING_KEYBOARD, 8, 10];
+        notify.resize(32, 0);
+        replies.push(&notify, &mut Vec::new());
+
+        let mut out = Vec::new();
+        requests.push(&request(X_GET_KEYBOARD_MAPPING, 0, &[9, 2]), &mut out);
+        assert!(requests.answers.is_empty());
+        assert!(!out.is_empty());
+    }
+
+    #[test]
+    fn test_large_requests_and_replies_stream_through() {
+        let cache = primed();
+        let (mut requests, mut replies) = connected(&cache);
+        // BIG-REQUESTS PutImage
+        let mut put = vec![72, 2, 0, 0];
+        put.extend_from_slice(&(100_000u32 / 4).to_le_bytes());
+        put.resize(100_000, 0xab);
+        let mut out = Vec::new();
+        for chunk in put.chunks(7000) {
+            requests.push(chunk, &mut out);
+        }
+        assert_eq!(out, put);
+
+        // a GetImage-sized reply passes in pieces
+        let mut big = reply(1, &[]);
+        big[4..8].copy_from_slice(&(200_000u32 / 4).to_le_bytes());
+        big.resize(32 + 200_000, 0xcd);
+        let mut back = Vec::new();
+        for chunk in big.chunks(9000) {
+            replies.push(chunk, &mut back);
+        }
+        assert_eq!(back, big);
+    }
+
+    #[tokio::test]
+    async fn test_proxy() {
+        let cache = primed();
+        let (client, local) = io::duplex(4096);
+        let (remote, mut server) = io::duplex(4096);
+        tokio::spawn(proxy(local, remote, b'l', &[], cache));
+        let (mut client_read, mut client_write) = io::split(client);
+
+        // setup reply, then a cached atom while a request is on the wire
+        server.write_all(&[1, 0, 11, 0, 0, 0, 0, 0]).await.unwrap();
+        let mut setup = [0u8; 8];
+        client_read.read_exact(&mut setup).await.unwrap();
+
+        client_write
+            .write_all(&named(X_INTERN_ATOM, b"WM_PROTOCOLS"))
+            .await
+            .unwrap();
+        let mut answer = [0u8; 32];
+        client_read.read_exact(&mut answer).await.unwrap();
+        assert_eq!(
+            (seq(&answer), &answer[8..12]),
+            (1, &300u32.to_le_bytes()[..])
+        );
+
+        client_write.write_all(&request(43, 0, &[])).await.unwrap();
+        let mut forwarded = [0u8; 4];
+        server.read_exact(&mut forwarded).await.unwrap();
+        assert_eq!(forwarded[0], 43);
+        server.write_all(&reply(1, &[])).await.unwrap();
+        let mut focus = [0u8; 32];
+        client_read.read_exact(&mut focus).await.unwrap();
+        assert_eq!(seq(&focus), 2);
+    }
+}
@@ -0,0 +1,482 @@
+//! Display number allocation with Xorg-compatible lock files, and the
+//! local X11 sockets that go with a display
+//!
+//! Every mode that creates a display (`join`, `client`, `web`, `display`,
+//! `test-server`) claims `/tmp/.X{N}-lock` before touching the socket, the
+//! same way Xorg does: our pid is written to a temp file and hard-linked into
+//! place, so there is never a half-written lock. A lock whose pid is gone is
+//! stale and gets replaced; a live one means the display is taken, and so
+//! does a socket that still accepts connections.
+//!
+//! The socket and lock are removed when the [`DisplayLock`] is dropped, and
+//! by [`cleanup_on_signal`] on SIGINT/SIGTERM, when destructors don't run.
+//! A command that must wind down itself first (`serve` tombstoning its
+//! code) takes the next signal with [`intercept_signals`] instead.
+//!
+//! On Linux each display also has an abstract-namespace socket,
+//! `@/tmp/.X11-unix/XN`, which libxcb tries before the filesystem path and
+//! which keeps working in containers that don't share `/tmp`. Displays we
+//! create listen on both; connecting to a local display falls back to it.
+
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+#[cfg(unix)]
+use tokio::net::{unix::SocketAddr, UnixListener, UnixStream};
+use tokio::sync::mpsc;
+
+const X11_UNIX_DIR: &str = "/tmp/.X11-unix";
+const X11_TCP_BASE: u16 = 6000;
+
+/// `auto` starts where ssh puts forwarded displays
+const AUTO_FIRST: u32 = 10;
+const AUTO_LAST: u32 = 999;
+
+/// Files to remove if we're killed by a signal
+static CLEANUP: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+/// Where the next signal goes instead of exiting, see [`intercept_signals`]
+static INTERCEPT: Mutex<Option<mpsc::UnboundedSender<i32>>> = Mutex::new(None);
+
+/// A `--display` argument: a number (`99`, `:99`) or `auto`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DisplayNumber {
+    Auto,
+    Fixed(u32),
+}
+
+impl FromStr for DisplayNumber {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "auto" {
+            return Ok(Self::Auto);
+        }
+        let n = s
+            .trim_start_matches(':')
+            .parse()
+            .context("expected a display number or 'auto'")?;
+        Ok(Self::Fixed(n))
+    }
+}
+
+/// Exclusive claim on a display number
+pub struct DisplayLock {
+    pub display_num: u32,
+    lock: Option<PathBuf>,
+    socket: Option<PathBuf>,
+}
+
+enum Attempt {
+    Locked(DisplayLock),
+    InUse(String),
+}
+
+impl DisplayLock {
+    /// Claim `display`, or the first free one for `auto`
+    pub fn acquire(display: DisplayNumber) -> Result<Self> {
+        match display {
+            DisplayNumber::Fixed(n) => match try_lock(n)? {
+                Attempt::Locked(lock) => Ok(lock),
+                Attempt::InUse(why) => anyhow::bail!("display :{} is in use ({})", n, why),
+            },
+            DisplayNumber::Auto => {
+                for n in AUTO_FIRST..=AUTO_LAST {
+                    // skip displays whose tcp port something else holds
+                    if std::net::TcpListener::bind(("127.0.0.1", tcp_port(n))).is_err() {
+                        continue;
+                    }
+                    if let Attempt::Locked(lock) = try_lock(n)? {
+                        eprintln!("using display :{}", n);
+                        return Ok(lock);
+                    }
+                }
+                anyhow::bail!("no free display between :{} and :{}", AUTO_FIRST, AUTO_LAST)
+            }
+        }
+    }
+
+    /// Path to bind the display's unix socket on
+    pub fn socket_path(&self) -> String {
+        socket_path(self.display_num)
+    }
+
+    /// Listen on the display's unix socket (and abstract socket on Linux)
+    #[cfg(unix)]
+    pub fn listen(&self) -> Result<X11Listener> {
+        let path = self.socket_path();
+        Ok(X11Listener {
+            #[cfg(target_os = "linux")]
+            abstract_name: bind_abstract(&path).context("failed to create abstract X11 socket")?,
+            path: UnixListener::bind(&path).context("failed to create X11 socket")?,
+        })
+    }
+}
+
+pub fn socket_path(display_num: u32) -> String {
+    format!("{}/X{}", X11_UNIX_DIR, display_num)
+}
+
+/// TCP port X servers listen on for `display_num`
+pub fn tcp_port(display_num: u32) -> u16 {
+    X11_TCP_BASE + display_num as u16
+}
+
+/// Unix sockets of a display we serve
+#[cfg(unix)]
+pub struct X11Listener {
+    path: UnixListener,
+    #[cfg(target_os = "linux")]
+    abstract_name: UnixListener,
+}
+
+#[cfg(unix)]
+impl X11Listener {
+    /// Next client on either socket
+    pub async fn accept(&self) -> std::io::Result<(UnixStream, SocketAddr)> {
+        #[cfg(target_os = "linux")]
+        {
+            tokio::select! {
+                r = self.path.accept() => r,
+                r = self.abstract_name.accept() => r,
+            }
+        }
+        #[cfg(not(target_os = "linux"))]
+        self.path.accept().await
+    }
+}
+
+/// Connect to local display `display_num`, via its abstract socket if the
+/// filesystem one is missing
+#[cfg(unix)]
+pub async fn connect(display_num: u32) -> std::io::Result<UnixStream> {
+    let path = socket_path(display_num);
+    match UnixStream::connect(&path).await {
+        Ok(stream) => Ok(stream),
+        #[cfg(target_os = "linux")]
+        Err(_) => {
+            let stream = std::os::unix::net::UnixStream::connect_addr(&abstract_addr(&path)?)?;
+            stream.set_nonblocking(true)?;
+            UnixStream::from_std(stream)
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(e) => Err(e),
+    }
+}
+
+/// Which unix socket local display `display_num` can be reached on, if any
+#[cfg(unix)]
+pub fn find_socket(display_num: u32) -> Option<String> {
+    let path = socket_path(display_num);
+    if Path::new(&path).exists() {
+        return Some(path);
+    }
+    #[cfg(target_os = "linux")]
+    if abstract_addr(&path)
+        .and_then(|addr| std::os::unix::net::UnixStream::connect_addr(&addr))
+        .is_ok()
+    {
+        return Some(format!("@{}", path));
+    }
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn abstract_addr(path: &str) -> std::io::Result<std::os::unix::net::SocketAddr> {
+    use std::os::linux::net::SocketAddrExt;
+    std::os::unix::net::SocketAddr::from_abstract_name(path.as_bytes())
+}
+
+#[cfg(target_os = "linux")]
+fn bind_abstract(path: &str) -> std::io::Result<UnixListener> {
+    let listener = std::os::unix::net::UnixListener::bind_addr(&abstract_addr(path)?)?;
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+/// Whether an X server already accepts connections for `display_num`
+#[cfg(unix)]
+fn socket_live(display_num: u32) -> bool {
+    let path = socket_path(display_num);
+    if std::os::unix::net::UnixStream::connect(&path).is_ok() {
+        return true;
+    }
+    #[cfg(target_os = "linux")]
+    if abstract_addr(&path)
+        .and_then(|addr| std::os::unix::net::UnixStream::connect_addr(&addr))
+        .is_ok()
+    {
+        return true;
+    }
+    false
+}
+
+impl Drop for DisplayLock {
+    fn drop(&mut self) {
+        for path in [self.socket.take(), self.lock.take()].into_iter().flatten() {
+            forget(&path);
+            let _ = std::fs::remove_file(&path);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn try_lock(display_num: u32) -> Result<Attempt> {
+    let lock_path = PathBuf::from(format!("/tmp/.X{}-lock", display_num));
+    let socket = PathBuf::from(socket_path(display_num));
+    let pid = std::process::id();
+
+    let tmp = PathBuf::from(format!("/tmp/.tX{}-lock.{}", display_num, pid));
+    let _ = std::fs::remove_file(&tmp);
+    write_lock_file(&tmp, pid)?;
+
+    let mut linked = false;
+    for _ in 0..2 {
+        match std::fs::hard_link(&tmp, &lock_path) {
+            Ok(()) => {
+                linked = true;
+                break;
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                if let Some(owner) = read_lock_pid(&lock_path) {
+                    if process_alive(owner) {
+                        let _ = std::fs::remove_file(&tmp);
+                        return Ok(Attempt::InUse(format!(
+                            "locked by pid {} in {}",
+                            owner,
+                            lock_path.display()
+                        )));
+                    }
+                }
+                eprintln!("removing stale lock {}", lock_path.display());
+                let _ = std::fs::remove_file(&lock_path);
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(&tmp);
+                return Err(e).with_context(|| format!("failed to create {}", lock_path.display()));
+            }
+        }
+    }
+    let _ = std::fs::remove_file(&tmp);
+    anyhow::ensure!(linked, "lost the race for {}", lock_path.display());
+    remove_on_signal(&lock_path);
+
+    // servers without lock files (or in another mount namespace) still count
+    if socket_live(display_num) {
+        forget(&lock_path);
+        let _ = std::fs::remove_file(&lock_path);
+        return Ok(Attempt::InUse(format!(
+            "{} accepts connections",
+            socket.display()
+        )));
+    }
+
+    // nothing is listening, so this is left over from a dead server
+    let _ = std::fs::remove_file(&socket);
+    std::fs::create_dir_all(X11_UNIX_DIR).ok();
+    remove_on_signal(&socket);
+
+    Ok(Attempt::Locked(DisplayLock {
+        display_num,
+        lock: Some(lock_path),
+        socket: Some(socket),
+    }))
+}
+
+/// No lock files or unix sockets here; the tcp port is the only claim
+#[cfg(not(unix))]
+fn try_lock(display_num: u32) -> Result<Attempt> {
+    Ok(Attempt::Locked(DisplayLock {
+        display_num,
+        lock: None,
+        socket: None,
+    }))
+}
+
+/// Xorg's format: the pid right-aligned in 10 columns, then a newline
+#[cfg(unix)]
+fn write_lock_file(path: &Path, pid: u32) -> Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o444)
+        .open(path)
+        .with_context(|| format!("failed to create {}", path.display()))?;
+    file.write_all(format!("{:>10}\n", pid).as_bytes())?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn read_lock_pid(path: &Path) -> Option<i32> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+#[cfg(unix)]
+fn process_alive(pid: i32) -> bool {
+    if pid <= 0 {
+        return false;
+    }
+    // signal 0 only checks; EPERM means it exists but belongs to someone else
+    let ret = unsafe { libc::kill(pid, 0) };
+    ret == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Remove `path` if the process is killed by SIGINT/SIGTERM
+pub fn remove_on_signal(path: &Path) {
+    CLEANUP.lock().unwrap().push(path.to_path_buf());
+}
+
+/// Stop tracking `path`, typically because its owner removed it
+pub fn forget(path: &Path) {
+    CLEANUP.lock().unwrap().retain(|p| p != path);
+}
+
+/// Exit on SIGINT/SIGTERM after removing sockets, locks and Xauthority files
+pub fn cleanup_on_signal() {
+    tokio::spawn(async {
+        loop {
+            let signal = wait_for_signal().await;
+            // one signal is handed over, a second one exits as usual
+            let intercepted = INTERCEPT.lock().unwrap().take();
+            if intercepted.is_some_and(|tx| tx.send(signal).is_ok()) {
+                continue;
+            }
+            for path in CLEANUP.lock().unwrap().drain(..) {
+                let _ = std::fs::remove_file(path);
+            }
+            std::process::exit(128 + signal);
+        }
+    });
+}
+
+/// The next SIGINT/SIGTERM, delivered here instead of exiting while this
+/// is held, so the caller can clean up and return
+pub struct Interrupts(mpsc::UnboundedReceiver<i32>);
+
+pub fn intercept_signals() -> Interrupts {
+    let (tx, rx) = mpsc::unbounded_channel();
+    *INTERCEPT.lock().unwrap() = Some(tx);
+    Interrupts(rx)
+}
+
+impl Interrupts {
+    /// The signal number, once one arrives
+    pub async fn recv(&mut self) -> i32 {
+        match self.0.recv().await {
+            Some(signal) => signal,
+            None => std::future::pending().await,
+        }
+    }
+}
+
+impl Drop for Interrupts {
+    fn drop(&mut self) {
+        INTERCEPT.lock().unwrap().take();
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> i32 {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let (Ok(mut int), Ok(mut term)) = (
+        signal(SignalKind::interrupt()),
+        signal(SignalKind::terminate()),
+    ) else {
+        return std::future::pending().await;
+    };
+    tokio::select! {
+        _ = int.recv() => libc::SIGINT,
+        _ = term.recv() => libc::SIGTERM,
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> i32 {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return std::future::pending().await;
+    }
+    2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_display_number() {
+        assert_eq!(
+            "auto".parse::<DisplayNumber>().unwrap(),
+            DisplayNumber::Auto
+        );
+        assert_eq!(
+            "99".parse::<DisplayNumber>().unwrap(),
+            DisplayNumber::Fixed(99)
+        );
+        assert_eq!(
+            ":12".parse::<DisplayNumber>().unwrap(),
+            DisplayNumber::Fixed(12)
+        );
+        assert!("nope".parse::<DisplayNumber>().is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lock_file_format() {
+        let path = std::env::temp_dir().join(format!("x11q-lock-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        write_lock_file(&path, 4242).unwrap();
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(read_lock_pid(&path), Some(4242));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "      4242\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_process_alive() {
+        assert!(process_alive(std::process::id() as i32));
+        assert!(!process_alive(0));
+        assert!(!process_alive(i32::MAX));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_live_lock_refused_stale_lock_replaced() {
+        // high enough to stay clear of real servers
+        let n = 40000 + std::process::id() % 10000;
+        let lock_path = PathBuf::from(format!("/tmp/.X{}-lock", n));
+
+        std::fs::write(&lock_path, format!("{:>10}\n", std::process::id())).unwrap();
+        assert!(DisplayLock::acquire(DisplayNumber::Fixed(n)).is_err());
+
+        std::fs::write(&lock_path, format!("{:>10}\n", i32::MAX)).unwrap();
+        let lock = DisplayLock::acquire(DisplayNumber::Fixed(n)).unwrap();
+        assert_eq!(read_lock_pid(&lock_path), Some(std::process::id() as i32));
+        drop(lock);
+        assert!(!lock_path.exists());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_abstract_socket_fallback() {
+        let n = 50000 + std::process::id() % 10000;
+        let lock = DisplayLock::acquire(DisplayNumber::Fixed(n)).unwrap();
+        let listener = lock.listen().unwrap();
+
+        // a container without the shared /tmp/.X11-unix
+        std::fs::remove_file(lock.socket_path()).unwrap();
+        assert_eq!(find_socket(n), Some(format!("@{}", lock.socket_path())));
+        let (client, accepted) = tokio::join!(connect(n), listener.accept());
+        client.unwrap();
+        accepted.unwrap();
+
+        // without the lock file too, the abstract name alone marks it taken
+        std::fs::remove_file(format!("/tmp/.X{}-lock", n)).unwrap();
+        assert!(DisplayLock::acquire(DisplayNumber::Fixed(n)).is_err());
+        assert!(!Path::new(&format!("/tmp/.X{}-lock", n)).exists());
+    }
+}